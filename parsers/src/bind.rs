@@ -32,7 +32,9 @@ impl BindQuery {
     }
 }
 
-impl IqSetPayload for BindQuery {}
+impl IqSetPayload for BindQuery {
+    type Response = BindResponse;
+}
 
 impl TryFrom<Element> for BindQuery {
     type Error = Error;
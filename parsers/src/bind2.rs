@@ -0,0 +1,203 @@
+// Copyright (c) 2026 agent <agent@local>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::ns;
+use crate::util::error::Error;
+use crate::Element;
+use std::convert::TryFrom;
+
+generate_element!(
+    /// A feature the server lets the client enable along with the bind.
+    Feature, "feature", BIND2,
+    attributes: [
+        /// The namespace of this feature.
+        var: Required<String> = "var",
+    ]
+);
+
+generate_element!(
+    /// The list of features which can be enabled along with the bind.
+    Inline, "inline", BIND2,
+    children: [
+        /// The features.
+        features: Vec<Feature> = ("feature", BIND2) => Feature
+    ]
+);
+
+impl Inline {
+    /// Whether the feature of this namespace can be enabled along with the
+    /// bind.
+    pub fn supports(&self, var: &str) -> bool {
+        self.features.iter().any(|feature| feature.var == var)
+    }
+}
+
+generate_element!(
+    /// Advertises Bind 2 in the inline features of SASL2.
+    BindFeature, "bind", BIND2,
+    children: [
+        /// The features which can be enabled along with the bind.
+        inline: Option<Inline> = ("inline", BIND2) => Inline
+    ]
+);
+
+/// Requests a resource to be bound, inline in the SASL2 authentication.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindQuery {
+    /// A tag identifying the client, which the server may use in the
+    /// resource it binds.
+    pub tag: Option<String>,
+
+    /// Requests of the features to enable along with the bind, for
+    /// instance carbons or stream management.
+    pub payloads: Vec<Element>,
+}
+
+impl BindQuery {
+    /// Creates a Bind 2 request with this tag.
+    pub fn new(tag: Option<String>) -> BindQuery {
+        BindQuery {
+            tag,
+            payloads: Vec::new(),
+        }
+    }
+
+    /// Enables a feature along with the bind.
+    pub fn with_payload<P: Into<Element>>(mut self, payload: P) -> BindQuery {
+        self.payloads.push(payload.into());
+        self
+    }
+}
+
+impl TryFrom<Element> for BindQuery {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<BindQuery, Error> {
+        check_self!(elem, "bind", BIND2);
+        check_no_attributes!(elem, "bind");
+
+        let mut tag = None;
+        let mut payloads = Vec::new();
+        for child in elem.children() {
+            if child.is("tag", ns::BIND2) {
+                if tag.is_some() {
+                    return Err(Error::ParseError("Bind must not have more than one tag."));
+                }
+                check_no_attributes!(child, "tag");
+                check_no_children!(child, "tag");
+                tag = Some(child.text());
+            } else {
+                payloads.push(child.clone());
+            }
+        }
+
+        Ok(BindQuery { tag, payloads })
+    }
+}
+
+impl From<BindQuery> for Element {
+    fn from(bind: BindQuery) -> Element {
+        Element::builder("bind", ns::BIND2)
+            .append_all(
+                bind.tag
+                    .map(|tag| Element::builder("tag", ns::BIND2).append(tag)),
+            )
+            .append_all(bind.payloads)
+            .build()
+    }
+}
+
+/// The result of a Bind 2 request, in the SASL2 success.
+///
+/// The bound JID itself is the authorization identifier of the success.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    /// The results of the features enabled along with the bind.
+    pub payloads: Vec<Element>,
+}
+
+impl TryFrom<Element> for Bound {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Bound, Error> {
+        check_self!(elem, "bound", BIND2);
+        check_no_attributes!(elem, "bound");
+
+        Ok(Bound {
+            payloads: elem.children().cloned().collect(),
+        })
+    }
+}
+
+impl From<Bound> for Element {
+    fn from(bound: Bound) -> Element {
+        Element::builder("bound", ns::BIND2)
+            .append_all(bound.payloads)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carbons;
+    use crate::sm;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Feature, 12);
+        assert_size!(Inline, 12);
+        assert_size!(BindFeature, 12);
+        assert_size!(BindQuery, 24);
+        assert_size!(Bound, 12);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Feature, 24);
+        assert_size!(Inline, 24);
+        assert_size!(BindFeature, 24);
+        assert_size!(BindQuery, 48);
+        assert_size!(Bound, 24);
+    }
+
+    #[test]
+    fn test_feature() {
+        let elem: Element = "<bind xmlns='urn:xmpp:bind:0'><inline><feature var='urn:xmpp:carbons:2'/><feature var='urn:xmpp:sm:3'/></inline></bind>"
+            .parse()
+            .unwrap();
+        let bind = BindFeature::try_from(elem).unwrap();
+        let inline = bind.inline.unwrap();
+        assert!(inline.supports(ns::CARBONS));
+        assert!(inline.supports(ns::SM));
+        assert!(!inline.supports(ns::CSI));
+    }
+
+    #[test]
+    fn test_query() {
+        let bind = BindQuery::new(Some(String::from("xmpp-rs")))
+            .with_payload(carbons::Enable)
+            .with_payload(sm::Enable::new().with_resume());
+        let elem: Element = bind.into();
+        let bind = BindQuery::try_from(elem).unwrap();
+        assert_eq!(bind.tag.unwrap(), "xmpp-rs");
+        assert_eq!(bind.payloads.len(), 2);
+        assert!(bind.payloads[0].is("enable", ns::CARBONS));
+        assert!(bind.payloads[1].is("enable", ns::SM));
+    }
+
+    #[test]
+    fn test_bound() {
+        let elem: Element = "<bound xmlns='urn:xmpp:bind:0'><enabled xmlns='urn:xmpp:sm:3' id='abc' resume='true'/></bound>"
+            .parse()
+            .unwrap();
+        let bound = Bound::try_from(elem).unwrap();
+        let enabled = sm::Enabled::try_from(bound.payloads[0].clone()).unwrap();
+        assert_eq!(enabled.id, Some(sm::StreamId(String::from("abc"))));
+    }
+}
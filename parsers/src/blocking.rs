@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 use crate::ns;
 use crate::util::error::Error;
 use crate::Element;
@@ -19,7 +19,9 @@ generate_empty_element!(
     BLOCKING
 );
 
-impl IqGetPayload for BlocklistRequest {}
+impl IqGetPayload for BlocklistRequest {
+    type Response = BlocklistResult;
+}
 
 macro_rules! generate_blocking_element {
     ($(#[$meta:meta])* $elem:ident, $name:tt) => (
@@ -76,7 +78,9 @@ generate_blocking_element!(
     "block"
 );
 
-impl IqSetPayload for Block {}
+impl IqSetPayload for Block {
+    type Response = NoPayload;
+}
 
 generate_blocking_element!(
     /// A query to unblock one or more JIDs, or all of them.
@@ -86,7 +90,9 @@ generate_blocking_element!(
     "unblock"
 );
 
-impl IqSetPayload for Unblock {}
+impl IqSetPayload for Unblock {
+    type Response = NoPayload;
+}
 
 generate_empty_element!(
     /// The application-specific error condition when a message is blocked.
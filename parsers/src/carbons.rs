@@ -5,7 +5,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::forwarding::Forwarded;
-use crate::iq::IqSetPayload;
+use crate::iq::{IqSetPayload, NoPayload};
 use crate::message::MessagePayload;
 
 generate_empty_element!(
@@ -15,7 +15,9 @@ generate_empty_element!(
     CARBONS
 );
 
-impl IqSetPayload for Enable {}
+impl IqSetPayload for Enable {
+    type Response = NoPayload;
+}
 
 generate_empty_element!(
     /// Disable a previously-enabled carbons.
@@ -24,7 +26,9 @@ generate_empty_element!(
     CARBONS
 );
 
-impl IqSetPayload for Disable {}
+impl IqSetPayload for Disable {
+    type Response = NoPayload;
+}
 
 generate_empty_element!(
     /// Request the enclosing message to not be copied to other carbons-enabled
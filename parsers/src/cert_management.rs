@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 use crate::util::helpers::Base64;
 
 generate_elem_id!(
@@ -38,7 +38,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for Append {}
+impl IqSetPayload for Append {
+    type Response = NoPayload;
+}
 
 generate_empty_element!(
     /// Client requests the current list of X.509 certificates.
@@ -47,7 +49,9 @@ generate_empty_element!(
     SASL_CERT
 );
 
-impl IqGetPayload for ListCertsQuery {}
+impl IqGetPayload for ListCertsQuery {
+    type Response = ListCertsResponse;
+}
 
 generate_elem_id!(
     /// One resource currently using a certificate.
@@ -103,7 +107,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for Disable {}
+impl IqSetPayload for Disable {
+    type Response = NoPayload;
+}
 
 generate_element!(
     /// Client revokes an X.509 certificate.
@@ -114,7 +120,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for Revoke {}
+impl IqSetPayload for Revoke {
+    type Response = NoPayload;
+}
 
 #[cfg(test)]
 mod tests {
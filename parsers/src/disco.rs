@@ -23,7 +23,9 @@ attributes: [
     node: Option<String> = "node",
 ]);
 
-impl IqGetPayload for DiscoInfoQuery {}
+impl IqGetPayload for DiscoInfoQuery {
+    type Response = DiscoInfoResult;
+}
 
 generate_element!(
 #[derive(Eq, Hash)]
@@ -195,7 +197,9 @@ attributes: [
     node: Option<String> = "node",
 ]);
 
-impl IqGetPayload for DiscoItemsQuery {}
+impl IqGetPayload for DiscoItemsQuery {
+    type Response = DiscoItemsResult;
+}
 
 generate_element!(
 /// Structure representing an `<item xmlns='http://jabber.org/protocol/disco#items'/>` element.
@@ -6,7 +6,7 @@
 
 use crate::data_forms::DataForm;
 use crate::date::DateTime;
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 
 generate_attribute!(
     /// When sending a push update, the action value indicates if the service is being added or
@@ -95,7 +95,9 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for Service {}
+impl IqGetPayload for Service {
+    type Response = ServicesResult;
+}
 
 generate_element!(
     /// Structure representing a `<services xmlns='urn:xmpp:extdisco:2'/>` element.
@@ -106,7 +108,9 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for ServicesQuery {}
+impl IqGetPayload for ServicesQuery {
+    type Response = ServicesResult;
+}
 
 generate_element!(
     /// Structure representing a `<services xmlns='urn:xmpp:extdisco:2'/>` element.
@@ -122,7 +126,9 @@ generate_element!(
 );
 
 impl IqResultPayload for ServicesResult {}
-impl IqSetPayload for ServicesResult {}
+impl IqSetPayload for ServicesResult {
+    type Response = NoPayload;
+}
 
 generate_element!(
     /// Structure representing a `<credentials xmlns='urn:xmpp:extdisco:2'/>` element.
@@ -133,7 +139,9 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for Credentials {}
+impl IqGetPayload for Credentials {
+    type Response = Credentials;
+}
 impl IqResultPayload for Credentials {}
 
 #[cfg(test)]
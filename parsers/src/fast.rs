@@ -0,0 +1,156 @@
+// Copyright (c) 2026 agent <agent@local>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::date::DateTime;
+
+generate_element!(
+    /// A mechanism usable with a FAST token.
+    Mechanism, "mechanism", FAST,
+    text: (
+        /// The name of the mechanism, for instance HT-SHA-256-NONE.
+        name: Text<String>
+    )
+);
+
+generate_element!(
+    /// Advertises FAST in the inline features of SASL2, along with the
+    /// mechanisms usable with a token.
+    Fast, "fast", FAST,
+    children: [
+        /// The mechanisms usable with a token.
+        mechanisms: Vec<Mechanism> = ("mechanism", FAST) => Mechanism
+    ]
+);
+
+impl Fast {
+    /// Whether this token mechanism is supported by the server.
+    pub fn supports(&self, name: &str) -> bool {
+        self.mechanisms
+            .iter()
+            .any(|mechanism| mechanism.name == name)
+    }
+}
+
+generate_attribute!(
+    /// Whether the token used to authenticate should be invalidated.
+    Invalidate,
+    "invalidate",
+    bool
+);
+
+generate_element!(
+    /// Tells the server the SASL2 authentication is using a FAST token.
+    FastQuery, "fast", FAST,
+    attributes: [
+        /// A counter, increased for each use of the token.
+        count: Option<u32> = "count",
+
+        /// Whether to invalidate the token after this authentication.
+        invalidate: Default<Invalidate> = "invalidate",
+    ]
+);
+
+impl FastQuery {
+    /// Creates a FAST authentication with this counter.
+    pub fn new(count: u32) -> FastQuery {
+        FastQuery {
+            count: Some(count),
+            invalidate: Invalidate::False,
+        }
+    }
+}
+
+generate_element!(
+    /// Requests a token from the server, inline in the SASL2 authentication.
+    RequestToken, "request-token", FAST,
+    attributes: [
+        /// The mechanism the token will be used with.
+        mechanism: Required<String> = "mechanism",
+    ]
+);
+
+impl RequestToken {
+    /// Requests a token for this mechanism.
+    pub fn new<M: Into<String>>(mechanism: M) -> RequestToken {
+        RequestToken {
+            mechanism: mechanism.into(),
+        }
+    }
+}
+
+generate_element!(
+    /// A token given by the server, in the SASL2 success.
+    Token, "token", FAST,
+    attributes: [
+        /// When the token stops being valid.
+        expiry: Required<DateTime> = "expiry",
+
+        /// The secret token.
+        token: Required<String> = "token",
+    ]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Mechanism, 12);
+        assert_size!(Fast, 12);
+        assert_size!(Invalidate, 1);
+        assert_size!(FastQuery, 12);
+        assert_size!(RequestToken, 12);
+        assert_size!(Token, 28);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Mechanism, 24);
+        assert_size!(Fast, 24);
+        assert_size!(Invalidate, 1);
+        assert_size!(FastQuery, 12);
+        assert_size!(RequestToken, 24);
+        assert_size!(Token, 40);
+    }
+
+    #[test]
+    fn test_feature() {
+        let elem: Element = "<fast xmlns='urn:xmpp:fast:0'><mechanism>HT-SHA-256-EXPR</mechanism><mechanism>HT-SHA-256-NONE</mechanism></fast>"
+            .parse()
+            .unwrap();
+        let fast = Fast::try_from(elem).unwrap();
+        assert!(fast.supports("HT-SHA-256-NONE"));
+        assert!(!fast.supports("HT-SHA-256-UNIQ"));
+    }
+
+    #[test]
+    fn test_query() {
+        let elem: Element = FastQuery::new(3).into();
+        assert_eq!(elem.attr("count"), Some("3"));
+        assert_eq!(elem.attr("invalidate"), None);
+        let fast = FastQuery::try_from(elem).unwrap();
+        assert_eq!(fast.count, Some(3));
+        assert_eq!(fast.invalidate, Invalidate::False);
+    }
+
+    #[test]
+    fn test_token() {
+        let elem: Element = "<token xmlns='urn:xmpp:fast:0' expiry='2022-04-01T12:00:00Z' token='WXZzciBwYmFmdmZnZiBqdmd1IGpmcmdoYnhm'/>"
+            .parse()
+            .unwrap();
+        let token = Token::try_from(elem).unwrap();
+        assert_eq!(token.token, "WXZzciBwYmFmdmZnZiBqdmd1IGpmcmdoYnhm");
+        assert_eq!(
+            token.expiry,
+            "2022-04-01T12:00:00Z".parse::<DateTime>().unwrap()
+        );
+    }
+}
@@ -26,7 +26,9 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for SlotRequest {}
+impl IqGetPayload for SlotRequest {
+    type Response = SlotResult;
+}
 
 /// Slot header
 #[derive(Debug, Clone, PartialEq)]
@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::IqSetPayload;
+use crate::iq::{IqSetPayload, NoPayload};
 use crate::util::helpers::Base64;
 
 generate_id!(
@@ -39,7 +39,9 @@ attributes: [
     stanza: Default<Stanza> = "stanza",
 ]);
 
-impl IqSetPayload for Open {}
+impl IqSetPayload for Open {
+    type Response = NoPayload;
+}
 
 generate_element!(
 /// Exchange a chunk of data in an open stream.
@@ -57,7 +59,9 @@ Data, "data", IBB,
     )
 );
 
-impl IqSetPayload for Data {}
+impl IqSetPayload for Data {
+    type Response = NoPayload;
+}
 
 generate_element!(
 /// Close an open stream.
@@ -67,7 +71,9 @@ attributes: [
     sid: Required<StreamId> = "sid",
 ]);
 
-impl IqSetPayload for Close {}
+impl IqSetPayload for Close {
+    type Response = NoPayload;
+}
 
 #[cfg(test)]
 mod tests {
@@ -5,7 +5,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::data_forms::DataForm;
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 use crate::ns;
 use crate::util::error::Error;
 use crate::Element;
@@ -31,8 +31,12 @@ pub struct Query {
     //pub oob: Option<Oob>,
 }
 
-impl IqGetPayload for Query {}
-impl IqSetPayload for Query {}
+impl IqGetPayload for Query {
+    type Response = Query;
+}
+impl IqSetPayload for Query {
+    type Response = NoPayload;
+}
 impl IqResultPayload for Query {}
 
 impl TryFrom<Element> for Query {
@@ -14,14 +14,44 @@ use minidom::IntoAttributeValue;
 use std::convert::TryFrom;
 
 /// Should be implemented on every known payload of an `<iq type='get'/>`.
-pub trait IqGetPayload: TryFrom<Element> + Into<Element> {}
+pub trait IqGetPayload: TryFrom<Element> + Into<Element> {
+    /// The payload of the `<iq type='result'/>` answering this request.
+    type Response: IqResultPayload;
+}
 
 /// Should be implemented on every known payload of an `<iq type='set'/>`.
-pub trait IqSetPayload: TryFrom<Element> + Into<Element> {}
+pub trait IqSetPayload: TryFrom<Element> + Into<Element> {
+    /// The payload of the `<iq type='result'/>` answering this request,
+    /// `NoPayload` when the request only gets acknowledged.
+    type Response: IqResultPayload;
+}
 
 /// Should be implemented on every known payload of an `<iq type='result'/>`.
 pub trait IqResultPayload: TryFrom<Element> + Into<Element> {}
 
+/// The payload of an `<iq type='result'/>` which has none, answering
+/// requests which only get acknowledged.
+///
+/// This type has no value, so an `Option<NoPayload>` is always `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoPayload {}
+
+impl TryFrom<Element> for NoPayload {
+    type Error = Error;
+
+    fn try_from(_elem: Element) -> Result<NoPayload, Error> {
+        Err(Error::ParseError("This result must not have a payload."))
+    }
+}
+
+impl From<NoPayload> for Element {
+    fn from(no_payload: NoPayload) -> Element {
+        match no_payload {}
+    }
+}
+
+impl IqResultPayload for NoPayload {}
+
 /// Represents one of the four possible iq types.
 #[derive(Debug, Clone)]
 pub enum IqType {
@@ -244,6 +274,17 @@ mod tests {
         assert_size!(Iq, 424);
     }
 
+    #[test]
+    fn test_no_payload() {
+        let elem: Element = "<ping xmlns='urn:xmpp:ping'/>".parse().unwrap();
+        let error = NoPayload::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "This result must not have a payload.");
+    }
+
     #[test]
     fn test_require_type() {
         #[cfg(not(feature = "component"))]
@@ -17,7 +17,9 @@ generate_element!(
     )
 );
 
-impl IqGetPayload for JidPrepQuery {}
+impl IqGetPayload for JidPrepQuery {
+    type Response = JidPrepResponse;
+}
 
 impl JidPrepQuery {
     /// Create a new JID Prep query.
@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::IqSetPayload;
+use crate::iq::{IqSetPayload, NoPayload};
 use crate::jingle_grouping::Group;
 use crate::jingle_ibb::Transport as IbbTransport;
 use crate::jingle_ice_udp::Transport as IceUdpTransport;
@@ -568,7 +568,9 @@ pub struct Jingle {
     pub other: Vec<Element>,
 }
 
-impl IqSetPayload for Jingle {}
+impl IqSetPayload for Jingle {
+    type Response = NoPayload;
+}
 
 impl Jingle {
     /// Create a new Jingle element.
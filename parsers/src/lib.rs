@@ -47,6 +47,10 @@ pub mod sasl;
 pub mod stanza_error;
 /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
 pub mod stream;
+/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+pub mod stream_error;
+/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+pub mod stream_features;
 
 /// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
 pub mod roster;
@@ -228,6 +232,12 @@ pub mod eme;
 /// XEP-0380: OMEMO Encryption (experimental version 0.3.0)
 pub mod legacy_omemo;
 
+/// XEP-0386: Bind 2
+pub mod bind2;
+
+/// XEP-0388: Extensible SASL Profile
+pub mod sasl2;
+
 /// XEP-0390: Entity Capabilities 2.0
 pub mod ecaps2;
 
@@ -239,3 +249,6 @@ pub mod occupant_id;
 
 /// XEP-0441: Message Archive Management Preferences
 pub mod mam_prefs;
+
+/// XEP-0484: Fast Authentication Streamlining Tokens
+pub mod fast;
@@ -36,8 +36,12 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for Query {}
-impl IqSetPayload for Query {}
+impl IqGetPayload for Query {
+    type Response = Query;
+}
+impl IqSetPayload for Query {
+    type Response = Fin;
+}
 impl IqResultPayload for Query {}
 
 generate_element!(
@@ -40,8 +40,12 @@ pub struct Prefs {
     pub never: Vec<Jid>,
 }
 
-impl IqGetPayload for Prefs {}
-impl IqSetPayload for Prefs {}
+impl IqGetPayload for Prefs {
+    type Response = Prefs;
+}
+impl IqSetPayload for Prefs {
+    type Response = Prefs;
+}
 impl IqResultPayload for Prefs {}
 
 impl TryFrom<Element> for Prefs {
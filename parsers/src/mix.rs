@@ -7,7 +7,7 @@
 // TODO: validate nicks by applying the “nickname” profile of the PRECIS OpaqueString class, as
 // defined in RFC 7700.
 
-use crate::iq::{IqResultPayload, IqSetPayload};
+use crate::iq::{IqResultPayload, IqSetPayload, NoPayload};
 use crate::message::MessagePayload;
 use crate::pubsub::{NodeName, PubSubPayload};
 use jid::BareJid;
@@ -89,7 +89,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for Join {}
+impl IqSetPayload for Join {
+    type Response = Join;
+}
 impl IqResultPayload for Join {}
 
 impl Join {
@@ -126,7 +128,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for UpdateSubscription {}
+impl IqSetPayload for UpdateSubscription {
+    type Response = UpdateSubscription;
+}
 impl IqResultPayload for UpdateSubscription {}
 
 impl UpdateSubscription {
@@ -154,7 +158,9 @@ generate_empty_element!(
     MIX_CORE
 );
 
-impl IqSetPayload for Leave {}
+impl IqSetPayload for Leave {
+    type Response = Leave;
+}
 impl IqResultPayload for Leave {}
 
 generate_element!(
@@ -166,7 +172,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for SetNick {}
+impl IqSetPayload for SetNick {
+    type Response = SetNick;
+}
 impl IqResultPayload for SetNick {}
 
 impl SetNick {
@@ -212,7 +220,9 @@ generate_element!(
     ]
 );
 
-impl IqSetPayload for Create {}
+impl IqSetPayload for Create {
+    type Response = Create;
+}
 impl IqResultPayload for Create {}
 
 impl Create {
@@ -240,7 +250,9 @@ generate_element!(
 
 // TODO: section 7.3.4, example 33, doesn’t mirror the <destroy/> in the iq result unlike every
 // other section so far.
-impl IqSetPayload for Destroy {}
+impl IqSetPayload for Destroy {
+    type Response = NoPayload;
+}
 
 impl Destroy {
     /// Create a new Destroy element.
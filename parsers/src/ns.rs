@@ -12,14 +12,23 @@ pub const XMPP_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
 /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
 pub const STREAM: &str = "http://etherx.jabber.org/streams";
 /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+pub const XMPP_STREAMS: &str = "urn:ietf:params:xml:ns:xmpp-streams";
+/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
 pub const TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
 /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
 pub const SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
 /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
 pub const BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
 
+/// RFC 3921: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
+pub const SESSION: &str = "urn:ietf:params:xml:ns:xmpp-session";
+
 /// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
 pub const ROSTER: &str = "jabber:iq:roster";
+/// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
+pub const ROSTERVER: &str = "urn:xmpp:features:rosterver";
+/// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
+pub const PRE_APPROVAL: &str = "urn:xmpp:features:pre-approval";
 
 /// RFC 7395: An Extensible Messaging and Presence Protocol (XMPP) Subprotocol for WebSocket
 pub const WEBSOCKET: &str = "urn:ietf:params:xml:ns:xmpp-framing";
@@ -64,6 +73,8 @@ pub const XHTML: &str = "http://www.w3.org/1999/xhtml";
 
 /// XEP-0077: In-Band Registration
 pub const REGISTER: &str = "jabber:iq:register";
+/// XEP-0077: In-Band Registration
+pub const REGISTER_FEATURE: &str = "http://jabber.org/features/iq-register";
 
 /// XEP-0084: User Avatar
 pub const AVATAR_DATA: &str = "urn:xmpp:avatar:data";
@@ -91,6 +102,9 @@ pub const CAPS: &str = "http://jabber.org/protocol/caps";
 /// XEP-0118: User Tune
 pub const TUNE: &str = "http://jabber.org/protocol/tune";
 
+/// XEP-0124: Bidirectional-streams Over Synchronous HTTP (BOSH)
+pub const BOSH: &str = "http://jabber.org/protocol/httpbind";
+
 /// XEP-0157: Contact Addresses for XMPP Services
 pub const SERVER_INFO: &str = "http://jabber.org/network/serverinfo";
 
@@ -133,6 +147,9 @@ pub const TIME: &str = "urn:xmpp:time";
 /// XEP-0203: Delayed Delivery
 pub const DELAY: &str = "urn:xmpp:delay";
 
+/// XEP-0206: XMPP Over BOSH
+pub const XBOSH: &str = "urn:xmpp:xbosh";
+
 /// XEP-0215: External Service Discovery
 pub const EXT_DISCO: &str = "urn:xmpp:extdisco:2";
 
@@ -257,6 +274,12 @@ pub const LEGACY_OMEMO_DEVICELIST: &str = "eu.siacs.conversations.axolotl.device
 /// XEP-0384: OMEMO Encryption (experimental version 0.3.0)
 pub const LEGACY_OMEMO_BUNDLES: &str = "eu.siacs.conversations.axolotl.bundles";
 
+/// XEP-0386: Bind 2
+pub const BIND2: &str = "urn:xmpp:bind:0";
+
+/// XEP-0388: Extensible SASL Profile
+pub const SASL2: &str = "urn:xmpp:sasl:2";
+
 /// XEP-0390: Entity Capabilities 2.0
 pub const ECAPS2: &str = "urn:xmpp:caps";
 /// XEP-0390: Entity Capabilities 2.0
@@ -272,6 +295,9 @@ pub const BOOKMARKS2_COMPAT_PEP: &str = "urn:xmpp:bookmarks:1#compat-pep";
 /// XEP-0421: Anonymous unique occupant identifiers for MUCs
 pub const OID: &str = "urn:xmpp:occupant-id:0";
 
+/// XEP-0484: Fast Authentication Streamlining Tokens
+pub const FAST: &str = "urn:xmpp:fast:0";
+
 /// Alias for the main namespace of the stream, that is "jabber:client" when
 /// the component feature isn’t enabled.
 #[cfg(not(feature = "component"))]
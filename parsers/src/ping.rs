@@ -5,7 +5,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::IqGetPayload;
+use crate::iq::{IqGetPayload, NoPayload};
 
 generate_empty_element!(
     /// Represents a ping to the recipient, which must be answered with an
@@ -15,7 +15,9 @@ generate_empty_element!(
     PING
 );
 
-impl IqGetPayload for Ping {}
+impl IqGetPayload for Ping {
+    type Response = NoPayload;
+}
 
 #[cfg(test)]
 mod tests {
@@ -6,7 +6,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::data_forms::DataForm;
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 use crate::ns;
 use crate::pubsub::{AffiliationAttribute, NodeName, Subscription};
 use crate::util::error::Error;
@@ -139,8 +139,12 @@ pub enum PubSubOwner {
     Subscriptions(Subscriptions),
 }
 
-impl IqGetPayload for PubSubOwner {}
-impl IqSetPayload for PubSubOwner {}
+impl IqGetPayload for PubSubOwner {
+    type Response = PubSubOwner;
+}
+impl IqSetPayload for PubSubOwner {
+    type Response = NoPayload;
+}
 impl IqResultPayload for PubSubOwner {}
 
 impl TryFrom<Element> for PubSubOwner {
@@ -334,8 +334,12 @@ pub enum PubSub {
     Unsubscribe(Unsubscribe),
 }
 
-impl IqGetPayload for PubSub {}
-impl IqSetPayload for PubSub {}
+impl IqGetPayload for PubSub {
+    type Response = PubSub;
+}
+impl IqSetPayload for PubSub {
+    type Response = PubSub;
+}
 impl IqResultPayload for PubSub {}
 
 impl TryFrom<Element> for PubSub {
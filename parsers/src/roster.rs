@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload, NoPayload};
 use jid::BareJid;
 
 generate_elem_id!(
@@ -84,8 +84,12 @@ generate_element!(
     ]
 );
 
-impl IqGetPayload for Roster {}
-impl IqSetPayload for Roster {}
+impl IqGetPayload for Roster {
+    type Response = Roster;
+}
+impl IqSetPayload for Roster {
+    type Response = NoPayload;
+}
 impl IqResultPayload for Roster {}
 
 #[cfg(test)]
@@ -40,6 +40,16 @@ generate_attribute!(
         /// Creates a temporary JID on login, which will be destroyed on
         /// disconnect.
         Anonymous => "ANONYMOUS",
+
+        /// Sends an OAuth 2.0 bearer token, in a single step.
+        ///
+        /// See https://tools.ietf.org/html/rfc7628
+        OAuthBearer => "OAUTHBEARER",
+
+        /// Legacy predecessor of [OAuthBearer](#structfield.OAuthBearer),
+        /// sending the token like [Plain](#structfield.Plain) sends the
+        /// password.
+        XOAuth2 => "X-OAUTH2",
     }
 );
 
@@ -253,6 +263,16 @@ mod tests {
         assert!(auth.data.is_empty());
     }
 
+    #[test]
+    fn test_oauthbearer() {
+        let elem: Element = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='OAUTHBEARER'>bixhPWp1bGlldEBjYXB1bGV0LmV4YW1wbGUsAWF1dGg9QmVhcmVyIHZGOWRmdDRxbVRjMk52YjNSbGNrQmhiSFJoZG1semRHRXVZMjl0Q2c9PQEB</auth>"
+            .parse()
+            .unwrap();
+        let auth = Auth::try_from(elem).unwrap();
+        assert_eq!(auth.mechanism, Mechanism::OAuthBearer);
+        assert!(auth.data.starts_with(b"n,a=juliet@capulet.example,\x01auth=Bearer "));
+    }
+
     #[test]
     fn section_6_5_1() {
         let elem: Element =
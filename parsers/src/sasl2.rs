@@ -0,0 +1,531 @@
+// Copyright (c) 2026 agent <agent@local>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::bind2::BindFeature;
+use crate::fast::Fast;
+use crate::ns;
+use crate::sasl::DefinedCondition;
+use crate::sm::StreamManagement;
+use crate::util::error::Error;
+use crate::util::helpers::Base64;
+use crate::Element;
+use jid::Jid;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+generate_element!(
+    /// A SASL mechanism offered by the server.
+    Mechanism, "mechanism", SASL2,
+    text: (
+        /// The name of the mechanism.
+        name: Text<String>
+    )
+);
+
+/// The features which can be negotiated along with the authentication.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inline {
+    /// Resource binding (XEP-0386).
+    pub bind: Option<BindFeature>,
+
+    /// Token-based authentication (XEP-0484).
+    pub fast: Option<Fast>,
+
+    /// Stream resumption (XEP-0198).
+    pub sm: Option<StreamManagement>,
+
+    /// Other inline features.
+    pub payloads: Vec<Element>,
+}
+
+impl TryFrom<Element> for Inline {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Inline, Error> {
+        check_self!(elem, "inline", SASL2);
+        check_no_attributes!(elem, "inline");
+
+        let mut inline = Inline {
+            bind: None,
+            fast: None,
+            sm: None,
+            payloads: Vec::new(),
+        };
+        for child in elem.children() {
+            if child.is("bind", ns::BIND2) {
+                inline.bind = Some(BindFeature::try_from(child.clone())?);
+            } else if child.is("fast", ns::FAST) {
+                inline.fast = Some(Fast::try_from(child.clone())?);
+            } else if child.is("sm", ns::SM) {
+                inline.sm = Some(StreamManagement);
+            } else {
+                inline.payloads.push(child.clone());
+            }
+        }
+        Ok(inline)
+    }
+}
+
+impl From<Inline> for Element {
+    fn from(inline: Inline) -> Element {
+        Element::builder("inline", ns::SASL2)
+            .append_all(inline.bind)
+            .append_all(inline.fast)
+            .append_all(inline.sm)
+            .append_all(inline.payloads)
+            .build()
+    }
+}
+
+/// Advertises SASL2 in the stream features.
+///
+/// Unknown children, from extensions of SASL2, are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authentication {
+    /// The mechanisms offered by the server.
+    pub mechanisms: Vec<Mechanism>,
+
+    /// The features which can be negotiated along with the
+    /// authentication.
+    pub inline: Option<Inline>,
+}
+
+impl TryFrom<Element> for Authentication {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Authentication, Error> {
+        check_self!(elem, "authentication", SASL2);
+
+        let mut authentication = Authentication {
+            mechanisms: Vec::new(),
+            inline: None,
+        };
+        for child in elem.children() {
+            if child.is("mechanism", ns::SASL2) {
+                authentication
+                    .mechanisms
+                    .push(Mechanism { name: child.text() });
+            } else if child.is("inline", ns::SASL2) {
+                authentication.inline = Some(Inline::try_from(child.clone())?);
+            }
+        }
+        Ok(authentication)
+    }
+}
+
+impl From<Authentication> for Element {
+    fn from(authentication: Authentication) -> Element {
+        Element::builder("authentication", ns::SASL2)
+            .append_all(authentication.mechanisms)
+            .append_all(authentication.inline)
+            .build()
+    }
+}
+
+impl Authentication {
+    /// Whether this mechanism is offered by the server.
+    pub fn supports(&self, name: &str) -> bool {
+        self.mechanisms
+            .iter()
+            .any(|mechanism| mechanism.name == name)
+    }
+}
+
+generate_element!(
+    /// Identifies the client, so that the server can tell its sessions apart.
+    UserAgent, "user-agent", SASL2,
+    attributes: [
+        /// A stable identifier of this client installation, usually a UUID.
+        id: Option<String> = "id",
+    ],
+    children: [
+        /// The name of the client software.
+        software: Option<String> = ("software", SASL2) => String,
+
+        /// The name of the device the client is running on.
+        device: Option<String> = ("device", SASL2) => String
+    ]
+);
+
+/// Starts the authentication, optionally negotiating other features inline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authenticate {
+    /// The mechanism used.
+    pub mechanism: String,
+
+    /// The first message of the mechanism, if it starts with the client.
+    pub initial_response: Option<Vec<u8>>,
+
+    /// The identification of the client.
+    pub user_agent: Option<UserAgent>,
+
+    /// Inline requests, for instance a Bind 2 request or a FAST token
+    /// request.
+    pub payloads: Vec<Element>,
+}
+
+impl Authenticate {
+    /// Starts an authentication with this mechanism.
+    pub fn new<M: Into<String>>(mechanism: M, initial_response: Option<Vec<u8>>) -> Authenticate {
+        Authenticate {
+            mechanism: mechanism.into(),
+            initial_response,
+            user_agent: None,
+            payloads: Vec::new(),
+        }
+    }
+
+    /// Sets the identification of the client.
+    pub fn with_user_agent(mut self, user_agent: UserAgent) -> Authenticate {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Adds an inline request.
+    pub fn with_payload<P: Into<Element>>(mut self, payload: P) -> Authenticate {
+        self.payloads.push(payload.into());
+        self
+    }
+}
+
+impl TryFrom<Element> for Authenticate {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Authenticate, Error> {
+        check_self!(elem, "authenticate", SASL2);
+        check_no_unknown_attributes!(elem, "authenticate", ["mechanism"]);
+
+        let mut initial_response = None;
+        let mut user_agent = None;
+        let mut payloads = Vec::new();
+        for child in elem.children() {
+            if child.is("initial-response", ns::SASL2) {
+                if initial_response.is_some() {
+                    return Err(Error::ParseError(
+                        "Authenticate must not have more than one initial-response.",
+                    ));
+                }
+                check_no_attributes!(child, "initial-response");
+                check_no_children!(child, "initial-response");
+                initial_response = Some(Base64::decode(&child.text())?);
+            } else if child.is("user-agent", ns::SASL2) {
+                if user_agent.is_some() {
+                    return Err(Error::ParseError(
+                        "Authenticate must not have more than one user-agent.",
+                    ));
+                }
+                user_agent = Some(UserAgent::try_from(child.clone())?);
+            } else {
+                payloads.push(child.clone());
+            }
+        }
+
+        Ok(Authenticate {
+            mechanism: get_attr!(elem, "mechanism", Required),
+            initial_response,
+            user_agent,
+            payloads,
+        })
+    }
+}
+
+impl From<Authenticate> for Element {
+    fn from(authenticate: Authenticate) -> Element {
+        Element::builder("authenticate", ns::SASL2)
+            .attr("mechanism", authenticate.mechanism)
+            .append_all(authenticate.initial_response.map(|data| {
+                Element::builder("initial-response", ns::SASL2).append_all(Base64::encode(&data))
+            }))
+            .append_all(authenticate.user_agent)
+            .append_all(authenticate.payloads)
+            .build()
+    }
+}
+
+generate_element!(
+    /// A step of the mechanism, sent by the server.
+    Challenge, "challenge", SASL2,
+    text: (
+        /// The challenge data.
+        data: Base64<Vec<u8>>
+    )
+);
+
+generate_element!(
+    /// A step of the mechanism, sent by the client.
+    Response, "response", SASL2,
+    text: (
+        /// The response data.
+        data: Base64<Vec<u8>>
+    )
+);
+
+/// Sent by the server once the authentication succeeded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Success {
+    /// The final message of the mechanism, if any.
+    pub additional_data: Option<Vec<u8>>,
+
+    /// The JID the client is now authenticated as, a full JID if a resource
+    /// got bound.
+    pub authorization_identifier: Jid,
+
+    /// The results of the inline requests, for instance the Bind 2 result
+    /// or a FAST token.
+    pub payloads: Vec<Element>,
+}
+
+impl TryFrom<Element> for Success {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Success, Error> {
+        check_self!(elem, "success", SASL2);
+        check_no_attributes!(elem, "success");
+
+        let mut additional_data = None;
+        let mut authorization_identifier = None;
+        let mut payloads = Vec::new();
+        for child in elem.children() {
+            if child.is("additional-data", ns::SASL2) {
+                if additional_data.is_some() {
+                    return Err(Error::ParseError(
+                        "Success must not have more than one additional-data.",
+                    ));
+                }
+                check_no_attributes!(child, "additional-data");
+                check_no_children!(child, "additional-data");
+                additional_data = Some(Base64::decode(&child.text())?);
+            } else if child.is("authorization-identifier", ns::SASL2) {
+                if authorization_identifier.is_some() {
+                    return Err(Error::ParseError(
+                        "Success must not have more than one authorization-identifier.",
+                    ));
+                }
+                check_no_attributes!(child, "authorization-identifier");
+                check_no_children!(child, "authorization-identifier");
+                authorization_identifier = Some(Jid::from_str(&child.text())?);
+            } else {
+                payloads.push(child.clone());
+            }
+        }
+
+        Ok(Success {
+            additional_data,
+            authorization_identifier: authorization_identifier.ok_or(Error::ParseError(
+                "Success must have an authorization-identifier.",
+            ))?,
+            payloads,
+        })
+    }
+}
+
+impl From<Success> for Element {
+    fn from(success: Success) -> Element {
+        Element::builder("success", ns::SASL2)
+            .append_all(success.additional_data.map(|data| {
+                Element::builder("additional-data", ns::SASL2).append_all(Base64::encode(&data))
+            }))
+            .append(
+                Element::builder("authorization-identifier", ns::SASL2)
+                    .append(String::from(success.authorization_identifier)),
+            )
+            .append_all(success.payloads)
+            .build()
+    }
+}
+
+/// Sent by the server when the authentication failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// The reason of the failure, shared with the original SASL.
+    pub defined_condition: DefinedCondition,
+
+    /// A human-readable explanation for the failure.
+    pub text: Option<String>,
+
+    /// Application-specific conditions.
+    pub payloads: Vec<Element>,
+}
+
+impl TryFrom<Element> for Failure {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Failure, Error> {
+        check_self!(elem, "failure", SASL2);
+        check_no_attributes!(elem, "failure");
+
+        let mut defined_condition = None;
+        let mut text = None;
+        let mut payloads = Vec::new();
+        for child in elem.children() {
+            if child.is("text", ns::SASL2) {
+                if text.is_some() {
+                    return Err(Error::ParseError(
+                        "Failure must not have more than one text.",
+                    ));
+                }
+                check_no_attributes!(child, "text");
+                check_no_children!(child, "text");
+                text = Some(child.text());
+            } else if child.has_ns(ns::SASL) {
+                if defined_condition.is_some() {
+                    return Err(Error::ParseError(
+                        "Failure must not have more than one defined-condition.",
+                    ));
+                }
+                defined_condition = Some(DefinedCondition::try_from(child.clone())?);
+            } else {
+                payloads.push(child.clone());
+            }
+        }
+
+        Ok(Failure {
+            defined_condition: defined_condition
+                .ok_or(Error::ParseError("Failure must have a defined-condition."))?,
+            text,
+            payloads,
+        })
+    }
+}
+
+impl From<Failure> for Element {
+    fn from(failure: Failure) -> Element {
+        Element::builder("failure", ns::SASL2)
+            .append(failure.defined_condition)
+            .append_all(
+                failure
+                    .text
+                    .map(|text| Element::builder("text", ns::SASL2).append(text)),
+            )
+            .append_all(failure.payloads)
+            .build()
+    }
+}
+
+generate_element!(
+    /// Sent by the client to cancel the authentication.
+    Abort, "abort", SASL2,
+    children: [
+        /// Why the client aborted.
+        text: Option<String> = ("text", SASL2) => String
+    ]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bind2::BindQuery;
+    use crate::fast::{RequestToken, Token};
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Mechanism, 12);
+        assert_size!(Inline, 40);
+        assert_size!(Authentication, 52);
+        assert_size!(UserAgent, 36);
+        assert_size!(Authenticate, 72);
+        assert_size!(Challenge, 12);
+        assert_size!(Response, 12);
+        assert_size!(Success, 60);
+        assert_size!(Failure, 28);
+        assert_size!(Abort, 12);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Mechanism, 24);
+        assert_size!(Inline, 80);
+        assert_size!(Authentication, 104);
+        assert_size!(UserAgent, 72);
+        assert_size!(Authenticate, 144);
+        assert_size!(Challenge, 24);
+        assert_size!(Response, 24);
+        assert_size!(Success, 120);
+        assert_size!(Failure, 56);
+        assert_size!(Abort, 24);
+    }
+
+    #[test]
+    fn test_feature() {
+        let elem: Element = "<authentication xmlns='urn:xmpp:sasl:2'>
+            <mechanism>SCRAM-SHA-1</mechanism>
+            <mechanism>SCRAM-SHA-1-PLUS</mechanism>
+            <inline>
+                <sm xmlns='urn:xmpp:sm:3'/>
+                <bind xmlns='urn:xmpp:bind:0'><inline><feature var='urn:xmpp:carbons:2'/></inline></bind>
+                <fast xmlns='urn:xmpp:fast:0'><mechanism>HT-SHA-256-NONE</mechanism></fast>
+            </inline>
+        </authentication>"
+            .parse()
+            .unwrap();
+        let authentication = Authentication::try_from(elem).unwrap();
+        assert!(authentication.supports("SCRAM-SHA-1-PLUS"));
+        assert!(!authentication.supports("PLAIN"));
+        let inline = authentication.inline.unwrap();
+        assert!(inline.sm.is_some());
+        assert!(inline.bind.unwrap().inline.unwrap().supports(ns::CARBONS));
+        assert!(inline.fast.unwrap().supports("HT-SHA-256-NONE"));
+        assert!(inline.payloads.is_empty());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let authenticate = Authenticate::new(
+            "SCRAM-SHA-1",
+            Some(b"n,,n=user,r=12C4CD5C-E38E-4A98-8F6D-15C38F51CCC6".to_vec()),
+        )
+        .with_user_agent(UserAgent {
+            id: Some(String::from("d4565fa7-4d72-4749-b3d3-740edbf87770")),
+            software: Some(String::from("xmpp-rs")),
+            device: None,
+        })
+        .with_payload(BindQuery::new(None))
+        .with_payload(RequestToken::new("HT-SHA-256-NONE"));
+        let elem: Element = authenticate.clone().into();
+        assert_eq!(Authenticate::try_from(elem).unwrap(), authenticate);
+    }
+
+    #[test]
+    fn test_success() {
+        let elem: Element = "<success xmlns='urn:xmpp:sasl:2'>
+            <additional-data>dj1tc1ZIcy9CeklPSERxWGVWSDdFbW1EdTlpZDg9</additional-data>
+            <authorization-identifier>user@example.org/xmpp-rs.abc</authorization-identifier>
+            <bound xmlns='urn:xmpp:bind:0'/>
+            <token xmlns='urn:xmpp:fast:0' expiry='2022-04-01T12:00:00Z' token='s3cr3t'/>
+        </success>"
+            .parse()
+            .unwrap();
+        let success = Success::try_from(elem).unwrap();
+        assert_eq!(
+            success.additional_data.unwrap(),
+            b"v=msVHs/BzIOHDqXeVH7EmmDu9id8="
+        );
+        assert_eq!(
+            success.authorization_identifier,
+            Jid::from_str("user@example.org/xmpp-rs.abc").unwrap()
+        );
+        assert!(success.payloads[0].is("bound", ns::BIND2));
+        let token = Token::try_from(success.payloads[1].clone()).unwrap();
+        assert_eq!(token.token, "s3cr3t");
+    }
+
+    #[test]
+    fn test_failure() {
+        let elem: Element = "<failure xmlns='urn:xmpp:sasl:2'>
+            <aborted xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>
+            <optional-application-specific xmlns='urn:something:else'/>
+            <text>This is a terrible example.</text>
+        </failure>"
+            .parse()
+            .unwrap();
+        let failure = Failure::try_from(elem).unwrap();
+        assert_eq!(failure.defined_condition, DefinedCondition::Aborted);
+        assert_eq!(failure.text.unwrap(), "This is a terrible example.");
+        assert_eq!(failure.payloads.len(), 1);
+    }
+}
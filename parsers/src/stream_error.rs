@@ -0,0 +1,374 @@
+// Copyright (c) 2026 agent <agent@local>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::ns;
+use crate::util::error::Error;
+use crate::Element;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// List of valid stream error conditions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefinedCondition {
+    /// The entity has sent XML that cannot be processed.
+    BadFormat,
+
+    /// The entity has sent a namespace prefix that is unsupported, or has
+    /// sent no namespace prefix on an element that needs such a prefix.
+    BadNamespacePrefix,
+
+    /// The server either (1) is closing the existing stream for this entity
+    /// because a new stream has been initiated that conflicts with the
+    /// existing stream, or (2) is refusing a new stream for this entity
+    /// because allowing the new stream would conflict with an existing
+    /// stream (e.g., because the server allows only a certain number of
+    /// connections from the same IP address or allows only one server-to-
+    /// server stream for a given domain pair as a way of helping to ensure
+    /// in-order processing).
+    Conflict,
+
+    /// One party is closing the stream because it has reason to believe that
+    /// the other party has permanently lost the ability to communicate over
+    /// the stream.
+    ConnectionTimeout,
+
+    /// The value of the 'to' attribute provided in the initial stream header
+    /// corresponds to an FQDN that is no longer serviced by the receiving
+    /// entity.
+    HostGone,
+
+    /// The value of the 'to' attribute provided in the initial stream header
+    /// does not correspond to an FQDN that is serviced by the receiving
+    /// entity.
+    HostUnknown,
+
+    /// A stanza sent between two servers lacks a 'to' or 'from' attribute,
+    /// the 'from' or 'to' attribute has no value, or the value violates the
+    /// rules for XMPP addresses.
+    ImproperAddressing,
+
+    /// The server has experienced a misconfiguration or other internal error
+    /// that prevents it from servicing the stream.
+    InternalServerError,
+
+    /// The data provided in a 'from' attribute does not match an authorized
+    /// JID or validated domain as negotiated.
+    InvalidFrom,
+
+    /// The stream namespace name is something other than
+    /// "http://etherx.jabber.org/streams" or the content namespace declared
+    /// as the default namespace is not supported.
+    InvalidNamespace,
+
+    /// The entity has sent invalid XML over the stream to a server that
+    /// performs validation.
+    InvalidXml,
+
+    /// The entity has attempted to send XML stanzas or other outbound data
+    /// before the stream has been authenticated, or otherwise is not
+    /// authorized to perform an action related to stream negotiation.
+    NotAuthorized,
+
+    /// The initiating entity has sent XML that violates the well-formedness
+    /// rules of XML.
+    NotWellFormed,
+
+    /// The entity has violated some local service policy (e.g., a stanza
+    /// exceeds a configured size limit).
+    PolicyViolation,
+
+    /// The server is unable to properly connect to a remote entity that is
+    /// needed for authentication or authorization.
+    RemoteConnectionFailed,
+
+    /// The server is closing the stream because it has new (typically
+    /// security-critical) features to offer, because the keys or
+    /// certificates used to establish a secure context for the stream have
+    /// expired or have been revoked during the life of the stream, because
+    /// the TLS sequence number has wrapped, etc.
+    Reset,
+
+    /// The server lacks the system resources necessary to service the
+    /// stream.
+    ResourceConstraint,
+
+    /// The entity has attempted to send restricted XML features such as a
+    /// comment, processing instruction, DTD subset, or XML entity reference.
+    RestrictedXml,
+
+    /// The server will not provide service to the initiating entity but is
+    /// redirecting traffic to another host, given here as a domain name or
+    /// IP address, optionally followed by a port.
+    SeeOtherHost(String),
+
+    /// The server is being shut down and all active streams are being
+    /// closed.
+    SystemShutdown,
+
+    /// The error condition is not one of those defined by the other
+    /// conditions in this list; this error condition SHOULD NOT be used
+    /// except in conjunction with an application-specific condition.
+    UndefinedCondition,
+
+    /// The initiating entity has encoded the stream in an encoding that is
+    /// not supported by the server.
+    UnsupportedEncoding,
+
+    /// The receiving entity has advertised a mandatory-to-negotiate stream
+    /// feature that the initiating entity does not support.
+    UnsupportedFeature,
+
+    /// The initiating entity has sent a first-level child of the stream that
+    /// is not supported by the server.
+    UnsupportedStanzaType,
+
+    /// The 'version' attribute provided by the initiating entity in the
+    /// stream header specifies a version of XMPP that is not supported by
+    /// the server.
+    UnsupportedVersion,
+}
+
+impl DefinedCondition {
+    fn name(&self) -> &'static str {
+        match self {
+            DefinedCondition::BadFormat => "bad-format",
+            DefinedCondition::BadNamespacePrefix => "bad-namespace-prefix",
+            DefinedCondition::Conflict => "conflict",
+            DefinedCondition::ConnectionTimeout => "connection-timeout",
+            DefinedCondition::HostGone => "host-gone",
+            DefinedCondition::HostUnknown => "host-unknown",
+            DefinedCondition::ImproperAddressing => "improper-addressing",
+            DefinedCondition::InternalServerError => "internal-server-error",
+            DefinedCondition::InvalidFrom => "invalid-from",
+            DefinedCondition::InvalidNamespace => "invalid-namespace",
+            DefinedCondition::InvalidXml => "invalid-xml",
+            DefinedCondition::NotAuthorized => "not-authorized",
+            DefinedCondition::NotWellFormed => "not-well-formed",
+            DefinedCondition::PolicyViolation => "policy-violation",
+            DefinedCondition::RemoteConnectionFailed => "remote-connection-failed",
+            DefinedCondition::Reset => "reset",
+            DefinedCondition::ResourceConstraint => "resource-constraint",
+            DefinedCondition::RestrictedXml => "restricted-xml",
+            DefinedCondition::SeeOtherHost(_) => "see-other-host",
+            DefinedCondition::SystemShutdown => "system-shutdown",
+            DefinedCondition::UndefinedCondition => "undefined-condition",
+            DefinedCondition::UnsupportedEncoding => "unsupported-encoding",
+            DefinedCondition::UnsupportedFeature => "unsupported-feature",
+            DefinedCondition::UnsupportedStanzaType => "unsupported-stanza-type",
+            DefinedCondition::UnsupportedVersion => "unsupported-version",
+        }
+    }
+}
+
+impl TryFrom<Element> for DefinedCondition {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<DefinedCondition, Error> {
+        check_ns_only!(elem, "defined-condition", XMPP_STREAMS);
+        check_no_children!(elem, "defined-condition");
+        check_no_attributes!(elem, "defined-condition");
+
+        Ok(match elem.name() {
+            "bad-format" => DefinedCondition::BadFormat,
+            "bad-namespace-prefix" => DefinedCondition::BadNamespacePrefix,
+            "conflict" => DefinedCondition::Conflict,
+            "connection-timeout" => DefinedCondition::ConnectionTimeout,
+            "host-gone" => DefinedCondition::HostGone,
+            "host-unknown" => DefinedCondition::HostUnknown,
+            "improper-addressing" => DefinedCondition::ImproperAddressing,
+            "internal-server-error" => DefinedCondition::InternalServerError,
+            "invalid-from" => DefinedCondition::InvalidFrom,
+            "invalid-namespace" => DefinedCondition::InvalidNamespace,
+            "invalid-xml" => DefinedCondition::InvalidXml,
+            "not-authorized" => DefinedCondition::NotAuthorized,
+            "not-well-formed" => DefinedCondition::NotWellFormed,
+            "policy-violation" => DefinedCondition::PolicyViolation,
+            "remote-connection-failed" => DefinedCondition::RemoteConnectionFailed,
+            "reset" => DefinedCondition::Reset,
+            "resource-constraint" => DefinedCondition::ResourceConstraint,
+            "restricted-xml" => DefinedCondition::RestrictedXml,
+            "see-other-host" => DefinedCondition::SeeOtherHost(elem.text()),
+            "system-shutdown" => DefinedCondition::SystemShutdown,
+            "undefined-condition" => DefinedCondition::UndefinedCondition,
+            "unsupported-encoding" => DefinedCondition::UnsupportedEncoding,
+            "unsupported-feature" => DefinedCondition::UnsupportedFeature,
+            "unsupported-stanza-type" => DefinedCondition::UnsupportedStanzaType,
+            "unsupported-version" => DefinedCondition::UnsupportedVersion,
+            _ => return Err(Error::ParseError("Unknown stream error condition.")),
+        })
+    }
+}
+
+impl From<DefinedCondition> for Element {
+    fn from(condition: DefinedCondition) -> Element {
+        let name = condition.name();
+        Element::builder(name, ns::XMPP_STREAMS)
+            .append_all(match condition {
+                DefinedCondition::SeeOtherHost(host) => Some(host),
+                _ => None,
+            })
+            .build()
+    }
+}
+
+type Lang = String;
+
+/// A `<stream:error/>`, after which the stream gets closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamError {
+    /// One of the defined conditions for this error to happen.
+    pub defined_condition: DefinedCondition,
+
+    /// Human-readable description of this error.
+    pub texts: BTreeMap<Lang, String>,
+
+    /// An application-specific condition.
+    pub other: Option<Element>,
+}
+
+impl StreamError {
+    /// Creates a new `<stream:error/>` with this condition.
+    pub fn new(defined_condition: DefinedCondition) -> StreamError {
+        StreamError {
+            defined_condition,
+            texts: BTreeMap::new(),
+            other: None,
+        }
+    }
+}
+
+impl TryFrom<Element> for StreamError {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<StreamError, Error> {
+        check_self!(elem, "error", STREAM);
+        check_no_attributes!(elem, "error");
+
+        let mut texts = BTreeMap::new();
+        let mut defined_condition = None;
+        let mut other = None;
+        for child in elem.children() {
+            if child.is("text", ns::XMPP_STREAMS) {
+                check_no_children!(child, "text");
+                check_no_unknown_attributes!(child, "text", ["xml:lang"]);
+                let lang = get_attr!(child, "xml:lang", Default);
+                if texts.insert(lang, child.text()).is_some() {
+                    return Err(Error::ParseError(
+                        "Text element present twice for the same xml:lang.",
+                    ));
+                }
+            } else if child.has_ns(ns::XMPP_STREAMS) {
+                if defined_condition.is_some() {
+                    return Err(Error::ParseError(
+                        "Error must not have more than one defined-condition.",
+                    ));
+                }
+                defined_condition = Some(DefinedCondition::try_from(child.clone())?);
+            } else {
+                if other.is_some() {
+                    return Err(Error::ParseError(
+                        "Error must not have more than one other element.",
+                    ));
+                }
+                other = Some(child.clone());
+            }
+        }
+
+        Ok(StreamError {
+            defined_condition: defined_condition
+                .ok_or(Error::ParseError("Error must have a defined-condition."))?,
+            texts,
+            other,
+        })
+    }
+}
+
+impl From<StreamError> for Element {
+    fn from(err: StreamError) -> Element {
+        Element::builder("error", ns::STREAM)
+            .append(err.defined_condition)
+            .append_all(err.texts.into_iter().map(|(lang, text)| {
+                Element::builder("text", ns::XMPP_STREAMS)
+                    .attr("xml:lang", lang)
+                    .append(text)
+            }))
+            .append_all(err.other)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(DefinedCondition, 12);
+        assert_size!(StreamError, 84);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(DefinedCondition, 24);
+        assert_size!(StreamError, 168);
+    }
+
+    #[test]
+    fn test_simple() {
+        let elem: Element = "<error xmlns='http://etherx.jabber.org/streams'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-streams'/><text xmlns='urn:ietf:params:xml:ns:xmpp-streams' xml:lang='en'>Replaced by new connection</text></error>"
+            .parse()
+            .unwrap();
+        let error = StreamError::try_from(elem).unwrap();
+        assert_eq!(error.defined_condition, DefinedCondition::Conflict);
+        assert_eq!(error.texts["en"], "Replaced by new connection");
+        assert_eq!(error.other, None);
+    }
+
+    #[test]
+    fn test_see_other_host() {
+        let elem: Element = "<error xmlns='http://etherx.jabber.org/streams'><see-other-host xmlns='urn:ietf:params:xml:ns:xmpp-streams'>[2001:db8::1]:5222</see-other-host><escape-your-data xmlns='http://example.org/ns'/></error>"
+            .parse()
+            .unwrap();
+        let error = StreamError::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            error.defined_condition,
+            DefinedCondition::SeeOtherHost(String::from("[2001:db8::1]:5222"))
+        );
+        assert!(error
+            .other
+            .as_ref()
+            .unwrap()
+            .is("escape-your-data", "http://example.org/ns"));
+
+        let elem2: Element = error.into();
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_invalid_condition() {
+        let elem: Element = "<error xmlns='http://etherx.jabber.org/streams'><text xmlns='urn:ietf:params:xml:ns:xmpp-streams'>Oops</text></error>"
+            .parse()
+            .unwrap();
+        let error = StreamError::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Error must have a defined-condition.");
+
+        let elem: Element = "<error xmlns='http://etherx.jabber.org/streams'><coucou xmlns='urn:ietf:params:xml:ns:xmpp-streams'/></error>"
+            .parse()
+            .unwrap();
+        let error = StreamError::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown stream error condition.");
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright (c) 2026 agent <agent@local>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::ns;
+use crate::sasl2::Authentication;
+use crate::util::error::Error;
+use crate::Element;
+use std::convert::TryFrom;
+
+/// Offers to negotiate TLS on this stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartTls {
+    /// Whether the server refuses to go any further without TLS.
+    pub required: bool,
+}
+
+impl TryFrom<Element> for StartTls {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<StartTls, Error> {
+        check_self!(elem, "starttls", TLS);
+        Ok(StartTls {
+            required: elem.has_child("required", ns::TLS),
+        })
+    }
+}
+
+impl From<StartTls> for Element {
+    fn from(starttls: StartTls) -> Element {
+        Element::builder("starttls", ns::TLS)
+            .append_all(flag(starttls.required, "required", ns::TLS))
+            .build()
+    }
+}
+
+generate_element!(
+    /// A SASL mechanism offered by the server.
+    SaslMechanism, "mechanism", SASL,
+    text: (
+        /// The name of the mechanism, for instance SCRAM-SHA-1.
+        name: Text<String>
+    )
+);
+
+/// The list of SASL mechanisms offered by the server.
+///
+/// Children other than `<mechanism/>`, such as the `<hostname/>` of
+/// XEP-0233, are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaslMechanisms {
+    /// The mechanisms.
+    pub mechanisms: Vec<SaslMechanism>,
+}
+
+impl SaslMechanisms {
+    /// Whether this mechanism is offered by the server.
+    pub fn supports(&self, name: &str) -> bool {
+        self.mechanisms
+            .iter()
+            .any(|mechanism| mechanism.name == name)
+    }
+}
+
+impl TryFrom<Element> for SaslMechanisms {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<SaslMechanisms, Error> {
+        check_self!(elem, "mechanisms", SASL);
+        let mechanisms = elem
+            .children()
+            .filter(|child| child.is("mechanism", ns::SASL))
+            .map(|child| SaslMechanism { name: child.text() })
+            .collect();
+        Ok(SaslMechanisms { mechanisms })
+    }
+}
+
+impl From<SaslMechanisms> for Element {
+    fn from(mechanisms: SaslMechanisms) -> Element {
+        Element::builder("mechanisms", ns::SASL)
+            .append_all(mechanisms.mechanisms)
+            .build()
+    }
+}
+
+/// Legacy session establishment, required by RFC 3921 but removed from
+/// RFC 6121, which servers keep advertising as optional as described in
+/// draft-cridland-xmpp-session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// Whether the client can skip establishing the session, as
+    /// advertised by the `<optional/>` child.
+    pub optional: bool,
+}
+
+impl TryFrom<Element> for Session {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Session, Error> {
+        check_self!(elem, "session", SESSION);
+        Ok(Session {
+            optional: elem.has_child("optional", ns::SESSION),
+        })
+    }
+}
+
+impl From<Session> for Element {
+    fn from(session: Session) -> Element {
+        Element::builder("session", ns::SESSION)
+            .append_all(flag(session.optional, "optional", ns::SESSION))
+            .build()
+    }
+}
+
+/// The features advertised by the server after each stream (re)start.
+///
+/// Features this crate doesn’t know about, or which it fails to parse,
+/// are kept in `others`, and unknown children of the known ones are
+/// ignored, so that extensions never prevent connecting.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamFeatures {
+    /// TLS negotiation.
+    pub starttls: Option<StartTls>,
+
+    /// SASL authentication.
+    pub sasl_mechanisms: Option<SaslMechanisms>,
+
+    /// Extensible SASL (XEP-0388), along with the features which can be
+    /// negotiated with it, such as Bind 2 (XEP-0386) and FAST (XEP-0484).
+    pub sasl2: Option<Authentication>,
+
+    /// Resource binding.
+    pub bind: bool,
+
+    /// Legacy session establishment.
+    pub session: Option<Session>,
+
+    /// Stream Management (XEP-0198).
+    pub sm: bool,
+
+    /// Client State Indication (XEP-0352).
+    pub csi: bool,
+
+    /// Roster versioning (RFC 6121).
+    pub rosterver: bool,
+
+    /// Subscription pre-approval (RFC 6121).
+    pub pre_approval: bool,
+
+    /// In-band registration (XEP-0077).
+    pub register: bool,
+
+    /// The features not handled by this struct.
+    pub others: Vec<Element>,
+}
+
+impl StreamFeatures {
+    /// Whether TLS can be negotiated on this stream.
+    pub fn can_starttls(&self) -> bool {
+        self.starttls.is_some()
+    }
+
+    /// Iterates over the names of the SASL mechanisms offered by the
+    /// server.
+    pub fn sasl_mechanisms(&self) -> impl Iterator<Item = &str> {
+        self.sasl_mechanisms
+            .iter()
+            .flat_map(|mechanisms| mechanisms.mechanisms.iter())
+            .map(|mechanism| mechanism.name.as_str())
+    }
+}
+
+/// Turns a feature into its element, when the server advertises it.
+fn flag(present: bool, name: &str, ns: &str) -> Option<Element> {
+    if present {
+        Some(Element::builder(name, ns).build())
+    } else {
+        None
+    }
+}
+
+impl TryFrom<Element> for StreamFeatures {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<StreamFeatures, Error> {
+        check_self!(elem, "features", STREAM);
+
+        let mut features = StreamFeatures::default();
+        for child in elem.children() {
+            if child.is("starttls", ns::TLS) {
+                features.starttls = Some(StartTls::try_from(child.clone())?);
+            } else if child.is("mechanisms", ns::SASL) {
+                features.sasl_mechanisms = Some(SaslMechanisms::try_from(child.clone())?);
+            } else if child.is("authentication", ns::SASL2) {
+                match Authentication::try_from(child.clone()) {
+                    Ok(authentication) => features.sasl2 = Some(authentication),
+                    Err(_) => features.others.push(child.clone()),
+                }
+            } else if child.is("bind", ns::BIND) {
+                features.bind = true;
+            } else if child.is("session", ns::SESSION) {
+                features.session = Some(Session::try_from(child.clone())?);
+            } else if child.is("sm", ns::SM) {
+                features.sm = true;
+            } else if child.is("csi", ns::CSI) {
+                features.csi = true;
+            } else if child.is("ver", ns::ROSTERVER) {
+                features.rosterver = true;
+            } else if child.is("sub", ns::PRE_APPROVAL) {
+                features.pre_approval = true;
+            } else if child.is("register", ns::REGISTER_FEATURE) {
+                features.register = true;
+            } else {
+                features.others.push(child.clone());
+            }
+        }
+        Ok(features)
+    }
+}
+
+impl From<StreamFeatures> for Element {
+    fn from(features: StreamFeatures) -> Element {
+        Element::builder("features", ns::STREAM)
+            .append_all(features.starttls)
+            .append_all(features.sasl_mechanisms)
+            .append_all(features.sasl2)
+            .append_all(flag(features.bind, "bind", ns::BIND))
+            .append_all(features.session)
+            .append_all(flag(features.sm, "sm", ns::SM))
+            .append_all(flag(features.csi, "csi", ns::CSI))
+            .append_all(flag(features.rosterver, "ver", ns::ROSTERVER))
+            .append_all(flag(features.pre_approval, "sub", ns::PRE_APPROVAL))
+            .append_all(flag(features.register, "register", ns::REGISTER_FEATURE))
+            .append_all(features.others)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(StartTls, 1);
+        assert_size!(SaslMechanism, 12);
+        assert_size!(SaslMechanisms, 12);
+        assert_size!(Session, 1);
+        assert_size!(StreamFeatures, 84);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(StartTls, 1);
+        assert_size!(SaslMechanism, 24);
+        assert_size!(SaslMechanisms, 24);
+        assert_size!(Session, 1);
+        assert_size!(StreamFeatures, 160);
+    }
+
+    #[test]
+    fn test_starttls() {
+        let elem: Element = "<features xmlns='http://etherx.jabber.org/streams'><starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'><required/></starttls></features>"
+            .parse()
+            .unwrap();
+        let features = StreamFeatures::try_from(elem).unwrap();
+        assert!(features.can_starttls());
+        assert!(features.starttls.unwrap().required);
+        assert_eq!(features.sasl_mechanisms, None);
+        assert!(!features.bind);
+    }
+
+    #[test]
+    fn test_authentication() {
+        let elem: Element = "<features xmlns='http://etherx.jabber.org/streams'><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>SCRAM-SHA-1</mechanism><mechanism>PLAIN</mechanism></mechanisms><authentication xmlns='urn:xmpp:sasl:2'><mechanism>SCRAM-SHA-1</mechanism></authentication><register xmlns='http://jabber.org/features/iq-register'/></features>"
+            .parse()
+            .unwrap();
+        let features = StreamFeatures::try_from(elem).unwrap();
+        assert!(!features.can_starttls());
+        let mechanisms: Vec<_> = features.sasl_mechanisms().collect();
+        assert_eq!(mechanisms, ["SCRAM-SHA-1", "PLAIN"]);
+        assert!(features.sasl2.unwrap().supports("SCRAM-SHA-1"));
+        assert!(features.register);
+    }
+
+    #[test]
+    fn test_bound() {
+        let elem: Element = "<features xmlns='http://etherx.jabber.org/streams'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/><session xmlns='urn:ietf:params:xml:ns:xmpp-session'><optional/></session><sm xmlns='urn:xmpp:sm:3'/><csi xmlns='urn:xmpp:csi:0'/><ver xmlns='urn:xmpp:features:rosterver'/><sub xmlns='urn:xmpp:features:pre-approval'/><limits xmlns='urn:xmpp:stream-limits:0'/></features>"
+            .parse()
+            .unwrap();
+        let features = StreamFeatures::try_from(elem.clone()).unwrap();
+        assert!(features.bind);
+        assert!(features.session.as_ref().unwrap().optional);
+        assert!(features.sm);
+        assert!(features.csi);
+        assert!(features.rosterver);
+        assert!(features.pre_approval);
+        assert!(!features.register);
+        assert_eq!(features.others.len(), 1);
+        assert!(features.others[0].is("limits", "urn:xmpp:stream-limits:0"));
+
+        let elem2: Element = features.into();
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_unknown_children() {
+        let elem: Element = "<features xmlns='http://etherx.jabber.org/streams'><starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'><foo xmlns='urn:example'/></starttls><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism><hostname xmlns='urn:xmpp:domain-based-name:1'>auth42.us.example.com</hostname></mechanisms><authentication xmlns='urn:xmpp:sasl:2'><mechanism>PLAIN</mechanism><upgrade xmlns='urn:xmpp:sasl:upgrade:0'>UPGR-SCRAM-SHA-256</upgrade></authentication><session xmlns='urn:ietf:params:xml:ns:xmpp-session'><optional/><foo/></session></features>"
+            .parse()
+            .unwrap();
+        let features = StreamFeatures::try_from(elem).unwrap();
+        assert!(!features.starttls.unwrap().required);
+        let mechanisms: Vec<_> = features.sasl_mechanisms().collect();
+        assert_eq!(mechanisms, ["PLAIN"]);
+        assert!(features.sasl2.unwrap().supports("PLAIN"));
+        assert!(features.session.unwrap().optional);
+        assert!(features.others.is_empty());
+    }
+}
@@ -20,7 +20,9 @@ generate_empty_element!(
     TIME
 );
 
-impl IqGetPayload for TimeQuery {}
+impl IqGetPayload for TimeQuery {
+    type Response = TimeResult;
+}
 
 /// An entity time result, containing an unique DateTime.
 #[derive(Debug, Clone)]
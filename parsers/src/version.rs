@@ -16,7 +16,9 @@ generate_empty_element!(
     VERSION
 );
 
-impl IqGetPayload for VersionQuery {}
+impl IqGetPayload for VersionQuery {
+    type Response = VersionResult;
+}
 
 generate_element!(
     /// Represents the answer about the software version we are using.
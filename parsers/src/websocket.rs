@@ -69,9 +69,29 @@ impl Open {
     }
 }
 
+generate_element!(
+    /// The stream closing for WebSocket.
+    Close, "close", WEBSOCKET,
+    attributes: [
+        /// The URI the client should reconnect to instead, when the server
+        /// redirects it.
+        see_other_uri: Option<String> = "see-other-uri",
+    ]
+);
+
+impl Close {
+    /// Creates a simple `<close/>` element.
+    pub fn new() -> Close {
+        Close {
+            see_other_uri: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ns;
     use crate::Element;
     use std::convert::TryFrom;
 
@@ -79,12 +99,14 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(Open, 84);
+        assert_size!(Close, 12);
     }
 
     #[cfg(target_pointer_width = "64")]
     #[test]
     fn test_size() {
         assert_size!(Open, 168);
+        assert_size!(Close, 24);
     }
 
     #[test]
@@ -99,4 +121,26 @@ mod tests {
         assert_eq!(open.version, None);
         assert_eq!(open.xml_lang, None);
     }
+
+    #[test]
+    fn test_close() {
+        let elem: Element = "<close xmlns='urn:ietf:params:xml:ns:xmpp-framing'/>"
+            .parse()
+            .unwrap();
+        let close = Close::try_from(elem).unwrap();
+        assert_eq!(close.see_other_uri, None);
+
+        let elem: Element = "<close xmlns='urn:ietf:params:xml:ns:xmpp-framing' see-other-uri='wss://otherendpoint.example/xmpp-bind'/>"
+            .parse()
+            .unwrap();
+        let close = Close::try_from(elem).unwrap();
+        assert_eq!(
+            close.see_other_uri.unwrap(),
+            "wss://otherendpoint.example/xmpp-bind"
+        );
+
+        let elem: Element = Close::new().into();
+        assert!(elem.is("close", ns::WEBSOCKET));
+        assert_eq!(elem.attrs().count(), 0);
+    }
 }
@@ -0,0 +1,785 @@
+//! BOSH transport (XEP-0124 and XEP-0206)
+//!
+//! A background task runs the HTTP session: what gets written to the
+//! `BoshStream` is sent wrapped in `<body/>` requests, while one
+//! request is kept pending for the server to answer with its own
+//! stanzas. The payloads of the responses are read back as an XML
+//! byte stream, so that the usual `XMPPStream` machinery, including
+//! authentication and resource binding, works on top of it.
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::ready;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::client::conn::{handshake, SendRequest};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Body, Request, StatusCode, Uri};
+use log::debug;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep_until, timeout, Instant};
+use xmpp_parsers::{ns, Element};
+
+use crate::error::to_io_error;
+use crate::happy_eyeballs::connect_to_host;
+use crate::proxy::ProxyConfig;
+use crate::resolver::Resolver;
+use crate::starttls::get_tls_stream;
+use crate::tls::TlsConfig;
+use crate::xmpp_codec::{escape, Packet, PacketSplitter};
+use crate::xmpp_stream::AsyncReadAndWrite;
+use crate::{BoshError, Error};
+
+/// Longest time we let the server hold a request, in seconds
+const WAIT: u64 = 60;
+
+/// Number of requests we let the server hold at once
+const HOLD: u64 = 1;
+
+/// Extra time given to a request on top of `wait`
+const REQUEST_MARGIN: Duration = Duration::from_secs(10);
+
+/// How many times the same request is sent before giving up
+const MAX_ATTEMPTS: u8 = 3;
+
+/// Opens a connection to the BOSH endpoint at `url`, over TLS for
+/// `https:` URLs, for a session with the server of `domain`.
+///
+/// Establishing each TCP connection, with the host looked up by
+/// `resolver` and through `proxy` if set, is given up after `timeout`.
+/// The session itself is created once the stream
+/// header is written.
+pub async fn connect(
+    url: &str,
+    domain: &str,
+    timeout: Duration,
+    resolver: &Arc<dyn Resolver>,
+    proxy: Option<&ProxyConfig>,
+    tls: &TlsConfig,
+) -> Result<BoshStream, Error> {
+    let uri = Uri::from_str(url).map_err(BoshError::InvalidUrl)?;
+    let secure = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(BoshError::UnsupportedScheme.into()),
+    };
+    let authority = uri.authority().ok_or(BoshError::UnsupportedScheme)?;
+    let host = authority
+        .host()
+        // IPv6 literals come within brackets
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = authority
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+    let endpoint = Arc::new(Endpoint {
+        path: uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/")
+            .parse()
+            .map_err(BoshError::InvalidUrl)?,
+        authority: authority.as_str().to_owned(),
+        host,
+        port,
+        secure,
+        connect_timeout: timeout,
+        resolver: resolver.clone(),
+        proxy: proxy.cloned(),
+        tls: tls.clone(),
+    });
+
+    // Connect right away, so that this is where connection errors
+    // show up.
+    let sender = endpoint.connect().await?;
+
+    let (packets_tx, packets_rx) = unbounded_channel();
+    let (data_tx, data_rx) = unbounded_channel();
+    let session = Session::new(endpoint, sender, domain.to_owned(), data_tx);
+    tokio::spawn(session.run(packets_rx));
+
+    Ok(BoshStream {
+        packets: packets_tx,
+        data: data_rx,
+        read_buf: Bytes::new(),
+        splitter: PacketSplitter::new(),
+    })
+}
+
+/// Where to send the HTTP requests
+struct Endpoint {
+    path: Uri,
+    authority: String,
+    host: String,
+    port: u16,
+    secure: bool,
+    connect_timeout: Duration,
+    resolver: Arc<dyn Resolver>,
+    proxy: Option<ProxyConfig>,
+    tls: TlsConfig,
+}
+
+impl Endpoint {
+    /// Opens a new HTTP connection
+    async fn connect(&self) -> Result<SendRequest<Body>, Error> {
+        let tcp_stream = connect_to_host(
+            &self.host,
+            self.port,
+            self.connect_timeout,
+            &self.resolver,
+            self.proxy.as_ref(),
+        )
+        .await?;
+        let stream: Box<dyn AsyncReadAndWrite> = if self.secure {
+            Box::new(get_tls_stream(tcp_stream, &self.host, Some("http/1.1"), &self.tls).await?)
+        } else {
+            Box::new(tcp_stream)
+        };
+        let (sender, connection) = handshake(stream).await.map_err(BoshError::Http)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("BOSH connection closed: {}", e);
+            }
+        });
+        Ok(sender)
+    }
+}
+
+/// Why a request failed
+enum Failure {
+    /// Network failure, the request can be sent again
+    Transport(io::Error),
+    /// The server rejected the request, ending the session
+    Status(StatusCode),
+}
+
+/// Sends `body` to the endpoint, on `sender` if it's still usable or
+/// on a new connection otherwise.
+async fn post(
+    endpoint: Arc<Endpoint>,
+    sender: Option<SendRequest<Body>>,
+    body: String,
+) -> Result<(SendRequest<Body>, Bytes), Failure> {
+    let reusable = match sender {
+        Some(mut sender) => sender.ready().await.map(|()| sender).ok(),
+        None => None,
+    };
+    let mut sender = match reusable {
+        Some(sender) => sender,
+        None => endpoint
+            .connect()
+            .await
+            .map_err(|e| Failure::Transport(to_io_error(e)))?,
+    };
+
+    let request = Request::post(endpoint.path.clone())
+        .header(HOST, endpoint.authority.as_str())
+        .header(CONTENT_TYPE, "text/xml; charset=utf-8")
+        .body(Body::from(body))
+        .map_err(|e| Failure::Transport(to_io_error(e)))?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| Failure::Transport(to_io_error(e)))?;
+    if response.status() != StatusCode::OK {
+        return Err(Failure::Status(response.status()));
+    }
+    let data = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| Failure::Transport(to_io_error(e)))?;
+    Ok((sender, data))
+}
+
+/// Purpose of a request
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Session creation, opening the first stream
+    Create,
+    /// Stream restart, after authentication
+    Restart,
+    /// Payloads, or an empty poll for the server to answer later
+    Normal,
+    /// End of the session
+    Terminate,
+}
+
+/// A request waiting for its response
+struct Sent {
+    rid: u64,
+    kind: Kind,
+    body: String,
+    attempt: u8,
+}
+
+type InFlight = BoxFuture<'static, (Sent, Result<(SendRequest<Body>, Bytes), Failure>)>;
+
+/// State of the HTTP session
+struct Session {
+    endpoint: Arc<Endpoint>,
+    /// Connections available for the next requests
+    idle: Vec<SendRequest<Body>>,
+    /// Requests waiting for their response
+    in_flight: FuturesUnordered<InFlight>,
+    /// Responses received ahead of the previous ones, by `rid`
+    answered: BTreeMap<u64, (Sent, Bytes)>,
+    /// `rid` of the next request
+    next_rid: u64,
+    /// `rid` of the next response to process, they have to be
+    /// processed in order
+    next_answer: u64,
+    /// Session id, once created
+    sid: Option<String>,
+    /// Domain of the server
+    to: String,
+    /// Stream attributes reported on creation
+    stream_id: String,
+    from: Option<String>,
+    /// Negotiated limits
+    requests: usize,
+    hold: u64,
+    wait: u64,
+    polling: Duration,
+    /// When empty polls may be sent again, if the server doesn't hold
+    /// them
+    next_poll: Option<Instant>,
+    /// Payloads to be sent
+    queue: Vec<Element>,
+    restart: bool,
+    terminate: bool,
+    terminating: bool,
+    done: bool,
+    /// Stream data for the reader
+    data: UnboundedSender<io::Result<Bytes>>,
+}
+
+impl Session {
+    fn new(
+        endpoint: Arc<Endpoint>,
+        sender: SendRequest<Body>,
+        to: String,
+        data: UnboundedSender<io::Result<Bytes>>,
+    ) -> Self {
+        // Random initial rid, leaving plenty of room to increment it
+        let rid = rand::thread_rng().gen_range(1..1 << 48);
+        Session {
+            endpoint,
+            idle: vec![sender],
+            in_flight: FuturesUnordered::new(),
+            answered: BTreeMap::new(),
+            next_rid: rid,
+            next_answer: rid,
+            sid: None,
+            to,
+            stream_id: String::new(),
+            from: None,
+            requests: 1,
+            hold: HOLD,
+            wait: WAIT,
+            polling: Duration::ZERO,
+            next_poll: None,
+            queue: Vec::new(),
+            restart: false,
+            terminate: false,
+            terminating: false,
+            done: false,
+            data,
+        }
+    }
+
+    async fn run(mut self, mut packets: UnboundedReceiver<Packet>) {
+        let mut packets_open = true;
+        while !self.done {
+            if let Err(e) = self.send_queued() {
+                let _ = self.data.send(Err(e));
+                return;
+            }
+            let next_poll = self.next_poll.unwrap_or_else(Instant::now);
+            tokio::select! {
+                packet = packets.recv(), if packets_open => match packet {
+                    Some(packet) => self.packet(packet),
+                    None => {
+                        // Nobody's reading anymore, end the session
+                        packets_open = false;
+                        if self.sid.is_none() {
+                            return;
+                        }
+                        self.terminate = true;
+                    }
+                },
+                Some((sent, result)) = self.in_flight.next(), if !self.in_flight.is_empty() => {
+                    if let Err(e) = self.answer(sent, result) {
+                        let _ = self.data.send(Err(e));
+                        return;
+                    }
+                }
+                () = sleep_until(next_poll), if self.next_poll.is_some() => {
+                    self.next_poll = None;
+                }
+                else => return,
+            }
+        }
+    }
+
+    /// Handles a packet written to the stream
+    fn packet(&mut self, packet: Packet) {
+        match packet {
+            Packet::StreamStart(_) if self.sid.is_none() => {
+                let mut body = self.body_start(Kind::Create);
+                write!(
+                    body,
+                    " to='{}' xml:lang='en' ver='1.11' wait='{}' hold='{}' content='text/xml; charset=utf-8' xmlns:xmpp='{}' xmpp:version='1.0'/>",
+                    escape(&self.to),
+                    WAIT,
+                    HOLD,
+                    ns::XBOSH
+                )
+                .unwrap();
+                self.send(Kind::Create, body);
+            }
+            Packet::StreamStart(_) => self.restart = true,
+            Packet::Stanza(stanza) => self.queue.push(stanza),
+            Packet::Text(_) => (),
+            Packet::StreamEnd => self.terminate = true,
+        }
+    }
+
+    /// Starts a `<body/>` with a new `rid`
+    fn body_start(&self, kind: Kind) -> String {
+        let mut body = format!("<body xmlns='{}' rid='{}'", ns::BOSH, self.next_rid);
+        if let Some(ref sid) = self.sid {
+            write!(body, " sid='{}'", escape(sid)).unwrap();
+        }
+        if kind == Kind::Terminate {
+            body.push_str(" type='terminate'");
+        }
+        body
+    }
+
+    /// Sends what's pending, as far as the server allows
+    fn send_queued(&mut self) -> io::Result<()> {
+        if self.sid.is_none() {
+            return Ok(());
+        }
+        while !self.terminating && self.in_flight.len() < self.requests {
+            let kind = if self.restart {
+                self.restart = false;
+                let mut body = self.body_start(Kind::Restart);
+                write!(
+                    body,
+                    " to='{}' xml:lang='en' xmlns:xmpp='{}' xmpp:restart='true'/>",
+                    escape(&self.to),
+                    ns::XBOSH
+                )
+                .unwrap();
+                self.send(Kind::Restart, body);
+                continue;
+            } else if self.terminate {
+                self.terminating = true;
+                Kind::Terminate
+            } else if !self.queue.is_empty() {
+                Kind::Normal
+            } else if self.in_flight.is_empty() && self.next_poll.is_none() {
+                // Empty poll, for the server to send its stanzas
+                Kind::Normal
+            } else {
+                break;
+            };
+
+            let mut body = self.body_start(kind);
+            if self.queue.is_empty() {
+                body.push_str("/>");
+            } else {
+                body.push('>');
+                let mut payloads = Vec::new();
+                for payload in self.queue.drain(..) {
+                    payload.write_to(&mut payloads).map_err(to_io_error)?;
+                }
+                body.push_str(&String::from_utf8(payloads).map_err(to_io_error)?);
+                body.push_str("</body>");
+            }
+            self.send(kind, body);
+        }
+        Ok(())
+    }
+
+    /// Sends a new request
+    fn send(&mut self, kind: Kind, body: String) {
+        let rid = self.next_rid;
+        self.next_rid += 1;
+        self.post(Sent {
+            rid,
+            kind,
+            body,
+            attempt: 1,
+        });
+    }
+
+    fn post(&mut self, sent: Sent) {
+        let endpoint = self.endpoint.clone();
+        let sender = self.idle.pop();
+        let body = sent.body.clone();
+        let duration = Duration::from_secs(self.wait) + REQUEST_MARGIN;
+        self.in_flight.push(Box::pin(async move {
+            let result = match timeout(duration, post(endpoint, sender, body)).await {
+                Ok(result) => result,
+                Err(_) => Err(Failure::Transport(io::ErrorKind::TimedOut.into())),
+            };
+            (sent, result)
+        }));
+    }
+
+    /// Sends a request again, with the same `rid` and content, after a
+    /// recoverable failure
+    fn retry(&mut self, mut sent: Sent, error: io::Error) -> io::Result<()> {
+        if sent.attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+        debug!("Sending BOSH request {} again: {}", sent.rid, error);
+        sent.attempt += 1;
+        self.post(sent);
+        Ok(())
+    }
+
+    /// Handles the completion of a request
+    fn answer(
+        &mut self,
+        sent: Sent,
+        result: Result<(SendRequest<Body>, Bytes), Failure>,
+    ) -> io::Result<()> {
+        match result {
+            Ok((sender, data)) => {
+                self.idle.push(sender);
+                self.answered.insert(sent.rid, (sent, data));
+            }
+            Err(Failure::Transport(e)) => return self.retry(sent, e),
+            Err(Failure::Status(status)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("BOSH request rejected with HTTP status {}", status),
+                ))
+            }
+        }
+
+        while let Some((sent, data)) = self.answered.remove(&self.next_answer) {
+            let text = std::str::from_utf8(&data).map_err(to_io_error)?;
+            let body = Element::from_str(text).map_err(to_io_error)?;
+            if !body.is("body", ns::BOSH) {
+                return Err(to_io_error("BOSH response isn't a <body/>"));
+            }
+
+            match body.attr("type") {
+                Some("terminate") if sent.kind != Kind::Terminate => {
+                    self.done = true;
+                    if let Some(condition) = body.attr("condition") {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            format!("BOSH session terminated by the server: {}", condition),
+                        ));
+                    }
+                    // Closed without error, like the end of a stream
+                    self.push(b"</stream:stream>".to_vec());
+                    return Ok(());
+                }
+                Some("error") => {
+                    // Recoverable binding condition, the previous
+                    // requests all got their response already.
+                    let error = to_io_error("recoverable BOSH error");
+                    return self.retry(sent, error);
+                }
+                _ => (),
+            }
+
+            match sent.kind {
+                Kind::Create => {
+                    let sid = body
+                        .attr("sid")
+                        .ok_or_else(|| to_io_error("BOSH session created without sid"))?;
+                    self.sid = Some(sid.to_owned());
+                    self.stream_id = body.attr("authid").unwrap_or(sid).to_owned();
+                    self.from = body.attr("from").map(String::from);
+                    let attr = |name| body.attr(name).and_then(|value| value.parse().ok());
+                    self.hold = attr("hold").unwrap_or(HOLD);
+                    self.wait = attr("wait").unwrap_or(WAIT);
+                    self.polling = Duration::from_secs(attr("polling").unwrap_or(0));
+                    self.requests = attr("requests").unwrap_or(self.hold + 1).max(1) as usize;
+                    self.stream_header();
+                }
+                Kind::Restart => self.stream_header(),
+                Kind::Normal | Kind::Terminate => (),
+            }
+
+            let mut payloads = Vec::new();
+            for payload in body.children() {
+                payload.write_to(&mut payloads).map_err(to_io_error)?;
+            }
+            if !payloads.is_empty() {
+                self.push(payloads);
+            } else if sent.kind == Kind::Normal && self.hold == 0 {
+                // The server doesn't hold requests, don't poll it
+                // more often than it wants.
+                self.next_poll = Some(Instant::now() + self.polling);
+            }
+
+            if sent.kind == Kind::Terminate {
+                self.push(b"</stream:stream>".to_vec());
+                self.done = true;
+                return Ok(());
+            }
+            self.next_answer += 1;
+        }
+        Ok(())
+    }
+
+    /// Passes the header of a new stream to the reader
+    fn stream_header(&mut self) {
+        let mut header = format!(
+            "<stream:stream xmlns='{}' xmlns:stream='{}' version='1.0' id='{}'",
+            ns::JABBER_CLIENT,
+            ns::STREAM,
+            escape(&self.stream_id)
+        );
+        if let Some(ref from) = self.from {
+            write!(header, " from='{}'", escape(from)).unwrap();
+        }
+        header.push('>');
+        self.push(header.into_bytes());
+    }
+
+    fn push(&mut self, data: Vec<u8>) {
+        // The reader may be gone already, the session ends soon then.
+        let _ = self.data.send(Ok(data.into()));
+    }
+}
+
+/// XML byte stream carried over a BOSH session
+pub struct BoshStream {
+    /// Packets to the session task
+    packets: UnboundedSender<Packet>,
+    /// Stream data from the session task
+    data: UnboundedReceiver<io::Result<Bytes>>,
+    /// Incoming data not read yet
+    read_buf: Bytes,
+    /// Splits outgoing data into packets
+    splitter: PacketSplitter,
+}
+
+impl AsyncRead for BoshStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_buf.is_empty() {
+            match ready!(this.data.poll_recv(cx)) {
+                Some(Ok(data)) => this.read_buf = data,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // End of file
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(this.read_buf.len());
+        buf.put_slice(&this.read_buf.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BoshStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        for packet in this.splitter.split(buf).map_err(to_io_error)? {
+            this.packets
+                .send(packet)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsyncClient, AsyncConfig, AsyncServerConfig, Event};
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+    use xmpp_parsers::iq::Iq;
+    use xmpp_parsers::Jid;
+
+    type ServerStream = BufReader<TcpStream>;
+
+    async fn recv(stream: &mut ServerStream) -> Option<Element> {
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+        Some(String::from_utf8(body).unwrap().parse().unwrap())
+    }
+
+    async fn send(stream: &mut ServerStream, body: &str) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream
+            .get_mut()
+            .write_all(response.as_bytes())
+            .await
+            .unwrap();
+    }
+
+    fn features(features: &str) -> String {
+        format!(
+            "<stream:features xmlns:stream='http://etherx.jabber.org/streams'>{}</stream:features>",
+            features
+        )
+    }
+
+    /// Answers the requests of one HTTP connection, holding empty
+    /// polls until a later request comes in
+    async fn serve_connection(
+        tcp_stream: TcpStream,
+        max_rid: Arc<watch::Sender<u64>>,
+        terminated: UnboundedSender<()>,
+    ) {
+        let mut stream = BufReader::new(tcp_stream);
+        while let Some(request) = recv(&mut stream).await {
+            assert!(request.is("body", ns::BOSH));
+            let rid: u64 = request.attr("rid").unwrap().parse().unwrap();
+            if rid > *max_rid.borrow() {
+                max_rid.send(rid).unwrap();
+            }
+
+            let payload = match request.children().next() {
+                _ if request.attr("sid").is_none() => {
+                    assert_eq!(request.attr("to"), Some("example.org"));
+                    assert_eq!(request.attr("hold"), Some("1"));
+                    format!(" sid='sid1' authid='s1' from='example.org' wait='60' hold='1' requests='2'>{}", features("<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms>"))
+                }
+                _ if request.attr("type") == Some("terminate") => {
+                    assert_eq!(request.attr("sid"), Some("sid1"));
+                    terminated.send(()).unwrap();
+                    String::from(" type='terminate'>")
+                }
+                _ if request
+                    .attrs()
+                    .any(|(name, value)| name.ends_with("restart") && value == "true") =>
+                {
+                    format!(
+                        ">{}",
+                        features("<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>")
+                    )
+                }
+                Some(auth) if auth.is("auth", ns::SASL) => {
+                    String::from("><success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+                }
+                Some(iq) => {
+                    let iq = Iq::try_from(iq.clone()).unwrap();
+                    format!("><iq xmlns='jabber:client' type='result' id='{}'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>test@example.org/bosh</jid></bind></iq>", iq.id)
+                }
+                None => {
+                    // Empty poll, held until the client sends something
+                    let mut rids = max_rid.subscribe();
+                    let _ = timeout(Duration::from_secs(5), async {
+                        while *rids.borrow_and_update() <= rid {
+                            rids.changed().await.unwrap();
+                        }
+                    })
+                    .await;
+                    String::from(">")
+                }
+            };
+            send(
+                &mut stream,
+                &format!("<body xmlns='{}'{}</body>", ns::BOSH, payload),
+            )
+            .await;
+        }
+    }
+
+    async fn serve(listener: TcpListener, terminated: UnboundedSender<()>) {
+        let (max_rid, _) = watch::channel(0);
+        let max_rid = Arc::new(max_rid);
+        loop {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve_connection(
+                tcp_stream,
+                max_rid.clone(),
+                terminated.clone(),
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/http-bind", listener.local_addr().unwrap());
+        let (terminated_tx, mut terminated_rx) = unbounded_channel();
+        tokio::spawn(serve(listener, terminated_tx));
+
+        let mut config = AsyncConfig::new(Jid::from_str("test@example.org").unwrap(), "password");
+        config.server = AsyncServerConfig::Bosh { url };
+        config.allow_plain_without_tls = true;
+        let mut client = AsyncClient::new_with_config(config);
+        match client.next().await {
+            Some(Event::Online { bound_jid, resumed }) => {
+                assert_eq!(bound_jid, Jid::from_str("test@example.org/bosh").unwrap());
+                assert!(!resumed);
+            }
+            _ => panic!(),
+        }
+
+        client.send_end().await.unwrap();
+        terminated_rx.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_scheme() {
+        match connect(
+            "ftp://example.org/",
+            "example.org",
+            Duration::from_secs(1),
+            &TlsConfig::default(),
+        )
+        .await
+        {
+            Err(Error::Bosh(BoshError::UnsupportedScheme)) => (),
+            _ => panic!(),
+        }
+    }
+}
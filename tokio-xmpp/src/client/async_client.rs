@@ -1,29 +1,48 @@
 use futures::{sink::SinkExt, task::Poll, Future, Sink, Stream};
-use sasl::common::{ChannelBinding, Credentials};
+use log::{info, warn};
+use sasl::common::Credentials;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::mem::replace;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::task::Context;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
-#[cfg(feature = "tls-native")]
-use tokio_native_tls::TlsStream;
-#[cfg(feature = "tls-rust")]
-use tokio_rustls::client::TlsStream;
-use xmpp_parsers::{ns, Element, Jid, JidParseError};
+use tokio::time::{sleep, Sleep};
+use xmpp_parsers::iq::{Iq, IqGetPayload, IqSetPayload};
+use xmpp_parsers::sm::{StreamId, A, R};
+use xmpp_parsers::stream_error::{DefinedCondition, StreamError};
+use xmpp_parsers::{ns, BareJid, Element, Error as ParsersError, Jid, JidParseError};
+use zeroize::{Zeroize, Zeroizing};
 
-use super::auth::auth;
+use super::auth::{auth, AuthOptions};
 use super::bind::bind;
+use super::connect::{connect, ServerConnector};
+use super::oauth::{OAuthCredentials, OAuthToken};
+use super::reconnect::{allows_reconnect, parse_redirect, within, ReconnectPolicy, Timeouts};
+use super::register;
+use super::sasl2::{self, Config as Sasl2Config, FastToken};
+use super::scram::{ScramKeys, ScramKeysHook};
+use super::sm::{self, Negotiated, StreamManagement};
 use crate::event::Event;
-use crate::happy_eyeballs::{connect_to_host, connect_with_srv};
-use crate::starttls::starttls;
-use crate::xmpp_codec::Packet;
-use crate::xmpp_stream;
-use crate::{Error, ProtocolError};
+use crate::iq_tracker::{parse_response, IqTracker, DEFAULT_IQ_TIMEOUT};
+use crate::keepalive::{Keepalive, KeepaliveConfig};
+use crate::observer::PacketObserver;
+use crate::proxy::ProxyConfig;
+use crate::resolver::{default_resolver, Resolver};
+use crate::stream_features::StreamFeatures;
+use crate::tls::{TlsConfig, TlsPolicy};
+use crate::xmpp_codec::{CodecLimits, Packet, XMPPCodec};
+use crate::xmpp_stream::{self, AsyncReadAndWrite};
+use crate::{AuthError, ConnectionPhase, Error, IqError, ProtocolError};
 
 /// XMPP client connection and state
 ///
-/// It is able to reconnect. TODO: implement session management.
+/// It is able to reconnect, and then resumes the previous session
+/// if the server supports Stream Management (XEP-0198).
 ///
 /// This implements the `futures` crate's [`Stream`](#impl-Stream) and
 /// [`Sink`](#impl-Sink<Packet>) traits.
@@ -31,33 +50,206 @@ pub struct Client {
     config: Config,
     state: ClientState,
     reconnect: bool,
-    // TODO: tls_required=true
+    /// Consecutive reconnection attempts since the last successful
+    /// connection
+    attempts: u32,
+    /// Server the last `<see-other-host/>` sent us to, used instead of
+    /// `config.server` until connecting there fails
+    redirect: Option<ServerConfig>,
+    /// Redirects followed since the last successful connection
+    redirects: u32,
+    /// Whether the server rejected the credentials on the last
+    /// attempt, for the OAuth token to be refreshed
+    rejected: bool,
+    /// Password the server accepted with `change_password()`, to use
+    /// from the next connection on
+    new_password: Option<oneshot::Receiver<Zeroizing<String>>>,
+    /// OAuth token the connection task refreshed, kept as soon as it
+    /// is, the refresh token it came with possibly replacing the
+    /// previous one
+    refreshed_token: Option<oneshot::Receiver<OAuthToken>>,
+    iqs: IqTracker,
+    sm: Option<StreamManagement>,
+    /// Stanzas received while connecting, to deliver once online
+    received: VecDeque<Element>,
+    keepalive: Option<Keepalive>,
 }
 
 /// XMPP server connection configuration
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum ServerConfig {
+    /// Use SRV records to find the server host, for both Direct TLS
+    /// (`_xmpps-client._tcp`) and STARTTLS (`_xmpp-client._tcp`)
     UseSrv,
-    #[allow(unused)]
+    /// Manually define the server host and port
     Manual {
+        /// Server host name
         host: String,
+        /// Server port
         port: u16,
+        /// Whether the server expects Direct TLS (XEP-0368) on this
+        /// port, instead of `<starttls/>` after the stream header
+        direct_tls: bool,
+    },
+    /// Connect through a custom transport, such as a Unix socket
+    Connector(Arc<dyn ServerConnector>),
+    /// Connect through WebSocket (RFC 7395)
+    #[cfg(feature = "websocket")]
+    WebSocket {
+        /// Endpoint URL, the connection is only encrypted for `wss:`
+        /// URLs
+        url: String,
+    },
+    /// Connect through BOSH (XEP-0124 and XEP-0206)
+    #[cfg(feature = "bosh")]
+    Bosh {
+        /// Endpoint URL, the connection is only encrypted for
+        /// `https:` URLs
+        url: String,
     },
 }
 
 /// XMMPP client configuration
+#[derive(Clone)]
 pub struct Config {
+    /// Jabber-Id to log in as, or only the domain to log in
+    /// anonymously (with ANONYMOUS, neither password nor FAST token),
+    /// the server assigning the JID given in `Event::Online`
+    pub jid: Jid,
+    /// Account password, wiped from memory once dropped
+    pub password: Zeroizing<String>,
+    /// OAuth 2.0 access token to authenticate with instead of the
+    /// password, which is then unused
+    pub oauth: Option<OAuthCredentials>,
+    /// SASL mechanisms to use, in order of preference, instead of the
+    /// built-in order; FAST tokens are used regardless
+    pub mechanisms: Option<Vec<String>>,
+    /// Keys derived from the password to authenticate with SCRAM, as
+    /// long as the server keeps the same salt and iteration count.
+    ///
+    /// After the first login with SCRAM, the keys the server accepted
+    /// are kept here, and the password is forgotten.
+    pub scram_keys: Option<ScramKeys>,
+    /// Called whenever new SCRAM keys got accepted, for the application
+    /// to store them instead of the password
+    pub on_scram_keys: Option<ScramKeysHook>,
+    /// Server to connect to
+    pub server: ServerConfig,
+    /// Proxy to connect to the server through, for all but custom
+    /// connectors
+    pub proxy: Option<ProxyConfig>,
+    /// Resolver to look up the server with, for all but custom
+    /// connectors, shared by the reconnections
+    pub resolver: Arc<dyn Resolver>,
+    /// Time given to each step of a connection
+    pub timeouts: Timeouts,
+    /// Delays between reconnection attempts, when enabled with
+    /// `Client::set_reconnect()`
+    pub reconnect: ReconnectPolicy,
+    /// Probing of the server on idle connections, to notice when they
+    /// silently died
+    pub keepalive: Option<KeepaliveConfig>,
+    /// Whether and how to encrypt TCP connections
+    pub tls_policy: TlsPolicy,
+    /// Trusted roots, pins, client certificate and minimum version for
+    /// TLS
+    pub tls: TlsConfig,
+    /// Whether to authenticate with PLAIN, or to send the OAuth token,
+    /// over unencrypted connections, sending them in the clear
+    pub allow_plain_without_tls: bool,
+    /// Client identification and FAST token, used when the server
+    /// supports SASL2
+    pub sasl2: Sasl2Config,
+    /// Number of consecutive `<see-other-host/>` redirects to follow,
+    /// after which the redirect ends the connection like other stream
+    /// errors, whether `Client::set_reconnect()` is enabled or not
+    pub max_redirects: u32,
+    /// Time given to the recipients of `Client::send_iq()` requests
+    /// to answer
+    pub iq_timeout: Duration,
+    /// Bounds on the stanzas received from the server, past which the
+    /// stream is closed with a `<policy-violation/>`
+    pub limits: CodecLimits,
+    /// What gets the packets of each connection, credentials redacted,
+    /// from the secured stream on
+    pub observer: Option<Arc<dyn PacketObserver>>,
+}
+
+impl Config {
+    /// Configuration using SRV records to find the server, the
+    /// default timeouts of 30 seconds for each step, the default
+    /// reconnection delays, up to 5 redirects, 30 seconds to answer
+    /// requests, the default codec limits, no proxy, the system
+    /// resolver behind a cache, no keepalive, no observer, TLS
+    /// required with the default trust store, the built-in mechanism
+    /// order, and no PLAIN without TLS
+    pub fn new<P: Into<String>>(jid: Jid, password: P) -> Self {
+        Config {
+            jid,
+            password: Zeroizing::new(password.into()),
+            oauth: None,
+            mechanisms: None,
+            scram_keys: None,
+            on_scram_keys: None,
+            server: ServerConfig::UseSrv,
+            proxy: None,
+            resolver: default_resolver(),
+            timeouts: Timeouts::default(),
+            reconnect: ReconnectPolicy::default(),
+            keepalive: None,
+            tls_policy: TlsPolicy::default(),
+            tls: TlsConfig::default(),
+            allow_plain_without_tls: false,
+            sasl2: Sasl2Config::default(),
+            max_redirects: 5,
+            iq_timeout: DEFAULT_IQ_TIMEOUT,
+            limits: CodecLimits::default(),
+            observer: None,
+        }
+    }
+}
+
+type XMPPStream = xmpp_stream::XMPPStream<Box<dyn AsyncReadAndWrite>>;
+
+/// What a connection attempt takes from the configuration, leaving
+/// out what only the client uses
+struct Attempt {
     jid: Jid,
-    password: String,
+    /// Left empty when OAuth makes it unused
+    password: Zeroizing<String>,
+    oauth: Option<OAuthCredentials>,
+    mechanisms: Option<Vec<String>>,
+    scram_keys: Option<ScramKeys>,
     server: ServerConfig,
+    proxy: Option<ProxyConfig>,
+    resolver: Arc<dyn Resolver>,
+    timeouts: Timeouts,
+    tls_policy: TlsPolicy,
+    tls: TlsConfig,
+    allow_plain_without_tls: bool,
+    sasl2: Sasl2Config,
+    limits: CodecLimits,
+    observer: Option<Arc<dyn PacketObserver>>,
 }
 
-type XMPPStream = xmpp_stream::XMPPStream<TlsStream<TcpStream>>;
+/// Session ready to use, along with the credentials to keep for the
+/// next connections
+struct Connected {
+    stream: XMPPStream,
+    negotiated: Negotiated,
+    /// New FAST token
+    fast_token: Option<FastToken>,
+    /// SCRAM keys which got accepted
+    scram_keys: Option<ScramKeys>,
+    /// Stanzas received while negotiating Stream Management
+    received: Vec<Element>,
+}
 
 enum ClientState {
     Invalid,
     Disconnected,
-    Connecting(JoinHandle<Result<XMPPStream, Error>>),
+    Waiting(Pin<Box<Sleep>>),
+    Connecting(JoinHandle<Result<Connected, Error>>),
     Connected(XMPPStream),
 }
 
@@ -68,81 +260,377 @@ impl Client {
     /// and yield events.
     pub fn new<P: Into<String>>(jid: &str, password: P) -> Result<Self, JidParseError> {
         let jid = Jid::from_str(jid)?;
-        let config = Config {
-            jid: jid.clone(),
-            password: password.into(),
-            server: ServerConfig::UseSrv,
-        };
+        let config = Config::new(jid, password);
         let client = Self::new_with_config(config);
         Ok(client)
     }
 
     /// Start a new client given that the JID is already parsed.
     pub fn new_with_config(config: Config) -> Self {
-        let connect = tokio::spawn(Self::connect(
-            config.server.clone(),
-            config.jid.clone(),
-            config.password.clone(),
-        ));
-        let client = Client {
+        let iqs = IqTracker::new(config.iq_timeout);
+        let mut client = Client {
             config,
-            state: ClientState::Connecting(connect),
+            state: ClientState::Disconnected,
             reconnect: false,
+            attempts: 0,
+            redirect: None,
+            redirects: 0,
+            rejected: false,
+            new_password: None,
+            refreshed_token: None,
+            iqs,
+            sm: None,
+            received: VecDeque::new(),
+            keepalive: None,
         };
+        client.state = ClientState::Connecting(client.spawn_connect());
         client
     }
 
     /// Set whether to reconnect (`true`) or let the stream end
     /// (`false`) when a connection to the server has ended.
+    ///
+    /// Attempts are delayed following `Config::reconnect`, each one
+    /// announced with `Event::Reconnecting`. Reconnection gets disabled
+    /// when the server ends the stream with an error that would happen
+    /// again, such as `<conflict/>` or `<not-authorized/>`.
     pub fn set_reconnect(&mut self, reconnect: bool) -> &mut Self {
         self.reconnect = reconnect;
         self
     }
 
+    /// Starts connecting to the configured server, or to the one we
+    /// got redirected to, resuming the previous session if possible.
+    fn spawn_connect(&mut self) -> JoinHandle<Result<Connected, Error>> {
+        self.password_changed();
+        self.token_refreshed();
+        let config = &self.config;
+        let password = match config.oauth {
+            Some(_) => Zeroizing::new(String::new()),
+            None => config.password.clone(),
+        };
+        let attempt = Attempt {
+            jid: config.jid.clone(),
+            password,
+            oauth: config.oauth.clone(),
+            mechanisms: config.mechanisms.clone(),
+            scram_keys: config.scram_keys.clone(),
+            server: self.redirect.as_ref().unwrap_or(&config.server).clone(),
+            proxy: config.proxy.clone(),
+            resolver: config.resolver.clone(),
+            timeouts: config.timeouts.clone(),
+            tls_policy: config.tls_policy,
+            tls: config.tls.clone(),
+            allow_plain_without_tls: config.allow_plain_without_tls,
+            sasl2: config.sasl2.clone(),
+            limits: config.limits,
+            observer: config.observer.clone(),
+        };
+        let resume = self.sm.as_ref().and_then(StreamManagement::resume_token);
+        let (sender, receiver) = oneshot::channel();
+        self.refreshed_token = Some(receiver);
+        tokio::spawn(Self::connect(attempt, resume, self.rejected, sender))
+    }
+
+    /// Follows the `<see-other-host/>` redirect which ended the
+    /// connection, if that's what `error` is and the limit isn't
+    /// reached yet.
+    ///
+    /// Redirects are followed the same way while connecting and once
+    /// connected, whether reconnection is enabled or not: the server
+    /// moving us elsewhere isn't a failure, and `max_redirects` set
+    /// to 0 disables them. The JID domain stays the one TLS
+    /// certificates get verified against. Only TCP connections are
+    /// redirected, WebSocket and BOSH endpoints being URLs, and custom
+    /// connectors choosing their server on their own.
+    fn redirect(&mut self, error: &Error) -> Option<Event> {
+        let value = match error {
+            Error::StreamError(StreamError {
+                defined_condition: DefinedCondition::SeeOtherHost(value),
+                ..
+            }) => value,
+            _ => return None,
+        };
+        if !matches!(
+            self.config.server,
+            ServerConfig::UseSrv | ServerConfig::Manual { .. }
+        ) {
+            return None;
+        }
+        if self.redirects >= self.config.max_redirects {
+            warn!("Not following more than {} redirects", self.redirects);
+            return None;
+        }
+        let (host, port) = match parse_redirect(value) {
+            Some(target) => target,
+            None => {
+                warn!("Ignoring invalid redirect to {:?}", value);
+                return None;
+            }
+        };
+        info!("Redirected to {} on port {}", host, port);
+        self.redirects += 1;
+        let current = self.redirect.as_ref().unwrap_or(&self.config.server);
+        self.redirect = Some(redirected(current, host.clone(), port));
+        self.state = ClientState::Connecting(self.spawn_connect());
+        Some(Event::Redirected { host, port })
+    }
+
+    /// Connects and authenticates, first refreshing the OAuth token if
+    /// it expired or got `rejected`, and handing it to `refreshed`
     async fn connect(
-        server: ServerConfig,
-        jid: Jid,
-        password: String,
-    ) -> Result<XMPPStream, Error> {
-        let username = jid.clone().node().unwrap();
-        let password = password;
-
-        // TCP connection
-        let tcp_stream = match server {
-            ServerConfig::UseSrv => {
-                connect_with_srv(&jid.clone().domain(), "_xmpp-client._tcp", 5222).await?
+        attempt: Attempt,
+        resume: Option<(StreamId, u32)>,
+        rejected: bool,
+        refreshed: oneshot::Sender<OAuthToken>,
+    ) -> Result<Connected, Error> {
+        let Attempt {
+            jid,
+            password,
+            oauth,
+            mechanisms,
+            scram_keys,
+            server,
+            proxy,
+            resolver,
+            timeouts,
+            tls_policy,
+            tls,
+            allow_plain_without_tls,
+            mut sasl2,
+            limits,
+            observer,
+        } = attempt;
+        let username = jid.clone().node();
+        let oauth = match oauth {
+            Some(oauth) => {
+                let token = oauth.refreshed(rejected).await.token;
+                // Kept even if this attempt fails
+                let _ = refreshed.send(token.clone());
+                Some(token)
             }
-            ServerConfig::Manual { host, port } => connect_to_host(host.as_str(), port).await?,
+            None => None,
+        };
+        let anonymous = username.is_none() && oauth.is_none();
+        if anonymous {
+            // A new guest account each time, nothing to remember
+            sasl2.client_id = None;
+            sasl2.fast_token = None;
+        }
+
+        // Stream secured as the policy or the transport says
+        let connection =
+            connect(server, &jid, &timeouts, resolver, proxy, tls_policy, &tls).await?;
+        let mut options = AuthOptions {
+            mechanisms,
+            allow_plain: connection.encrypted || allow_plain_without_tls,
+            oauth: oauth.is_some(),
+            anonymous,
+            scram_keys,
         };
+        let stream = connection.stream;
 
-        // Unencryped XMPPStream
-        let xmpp_stream =
-            xmpp_stream::XMPPStream::start(tcp_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
+        let creds = match oauth {
+            // Along with the bare JID to authorize as
+            Some(ref token) => Credentials::default()
+                .with_username(String::from(BareJid::from(jid.clone())))
+                .with_password(token.token.clone()),
+            None => match username {
+                // Forgotten once there are SCRAM keys
+                Some(ref username) if password.is_empty() => {
+                    Credentials::default().with_username(username.clone())
+                }
+                Some(ref username) => Credentials::default()
+                    .with_username(username.clone())
+                    .with_password(password.as_str()),
+                None => Credentials::default(),
+            },
+        }
+        .with_channel_binding(connection.channel_binding);
+
+        let authenticate = async {
+            // XMPPStream, encrypted unless the policy says otherwise
+            let codec = XMPPCodec::with_limits(limits).with_observer(observer.clone());
+            let mut xmpp_stream = xmpp_stream::XMPPStream::start_with_codec(
+                stream,
+                jid.clone(),
+                ns::JABBER_CLIENT.to_owned(),
+                codec,
+            )
+            .await?;
+
+            if let Some(authentication) = xmpp_stream.stream_features.sasl2.clone() {
+                let inline_sm = authentication
+                    .inline
+                    .as_ref()
+                    .map_or(false, |inline| inline.sm.is_some());
+                // Authenticated, and possibly bound, without restarting
+                // the stream
+                let negotiated = sasl2::auth(
+                    &mut xmpp_stream,
+                    authentication,
+                    username.as_deref().unwrap_or_default(),
+                    creds,
+                    &mut sasl2,
+                    resume.clone(),
+                    &mut options,
+                )
                 .await?;
+                // Resumption was already attempted inline
+                let resume = if inline_sm { None } else { resume };
+                return Ok((xmpp_stream, negotiated, resume));
+            }
 
-        let xmpp_stream = if xmpp_stream.stream_features.can_starttls() {
-            // TlsStream
-            let tls_stream = starttls(xmpp_stream).await?;
-            // Encrypted XMPPStream
-            xmpp_stream::XMPPStream::start(tls_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
-                .await?
-        } else {
-            return Err(Error::Protocol(ProtocolError::NoTls));
+            // Authenticated (unspecified) stream
+            let stream = auth(xmpp_stream, creds, &mut options).await?;
+            // Authenticated XMPPStream
+            let codec = XMPPCodec::with_limits(limits).with_observer(observer);
+            let xmpp_stream = xmpp_stream::XMPPStream::start_with_codec(
+                stream,
+                jid,
+                ns::JABBER_CLIENT.to_owned(),
+                codec,
+            )
+            .await?;
+            Ok::<_, Error>((xmpp_stream, None, resume))
+        };
+        let (xmpp_stream, negotiated, resume) =
+            within(timeouts.auth, ConnectionPhase::Auth, authenticate).await?;
+
+        let mut received = Vec::new();
+        let (stream, negotiated) = match negotiated {
+            Some(negotiated) => (xmpp_stream, negotiated),
+            None => {
+                let bind = Self::bind_session(xmpp_stream, resume, &mut received);
+                within(timeouts.bind, ConnectionPhase::Bind, bind).await?
+            }
         };
+        Ok(Connected {
+            stream,
+            negotiated,
+            fast_token: sasl2.fast_token,
+            scram_keys: options.scram_keys,
+            received,
+        })
+    }
 
-        let creds = Credentials::default()
-            .with_username(username)
-            .with_password(password)
-            .with_channel_binding(ChannelBinding::None);
-        // Authenticated (unspecified) stream
-        let stream = auth(xmpp_stream, creds).await?;
-        // Authenticated XMPPStream
-        let xmpp_stream =
-            xmpp_stream::XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned()).await?;
+    /// Keeps the OAuth token the connection task refreshed, for the
+    /// next attempts to use it, or to refresh it again
+    fn token_refreshed(&mut self) {
+        let token = match self.refreshed_token.as_mut().map(|new| new.try_recv()) {
+            Some(Ok(token)) => token,
+            Some(Err(oneshot::error::TryRecvError::Empty)) | None => return,
+            Some(Err(oneshot::error::TryRecvError::Closed)) => {
+                self.refreshed_token = None;
+                return;
+            }
+        };
+        self.refreshed_token = None;
+        if let Some(ref mut oauth) = self.config.oauth {
+            oauth.token = token;
+        }
+    }
+
+    /// Uses the password `change_password()` got accepted, instead of
+    /// the previous one and the SCRAM keys derived from it
+    fn password_changed(&mut self) {
+        let password = match self.new_password.as_mut().map(|new| new.try_recv()) {
+            Some(Ok(password)) => password,
+            Some(Err(oneshot::error::TryRecvError::Empty)) | None => return,
+            Some(Err(oneshot::error::TryRecvError::Closed)) => {
+                self.new_password = None;
+                return;
+            }
+        };
+        self.new_password = None;
+        self.config.password = password;
+        self.config.scram_keys = None;
+    }
+
+    /// Keeps the SCRAM keys which got accepted instead of the password
+    fn scram_keys(&mut self, keys: Option<ScramKeys>) {
+        let keys = match keys {
+            Some(keys) => keys,
+            None => return,
+        };
+        if self.config.scram_keys.as_ref() != Some(&keys) {
+            if let Some(ref hook) = self.config.on_scram_keys {
+                hook(&keys);
+            }
+        }
+        self.config.scram_keys = Some(keys);
+        self.config.password.zeroize();
+    }
+
+    /// Resumes the previous session, or binds a new one and enables
+    /// Stream Management on an authenticated stream, keeping the
+    /// stanzas received meanwhile in `received`.
+    async fn bind_session(
+        mut xmpp_stream: XMPPStream,
+        resume: Option<(StreamId, u32)>,
+        received: &mut Vec<Element>,
+    ) -> Result<(XMPPStream, Negotiated), Error> {
+        let can_sm = xmpp_stream.stream_features.sm;
+        if let (true, Some((previd, h))) = (can_sm, resume) {
+            // Resumed previous session, no need to bind again
+            match sm::resume(&mut xmpp_stream, previd, h, received).await? {
+                Ok(resumed) => return Ok((xmpp_stream, Negotiated::Resumed(resumed))),
+                Err(_) => warn!("Session resumption failed, binding a new one"),
+            }
+        }
 
         // XMPPStream bound to user session
-        let xmpp_stream = bind(xmpp_stream).await?;
-        Ok(xmpp_stream)
+        let mut xmpp_stream = bind(xmpp_stream).await?;
+
+        let negotiated = if can_sm {
+            match sm::enable(&mut xmpp_stream, received).await? {
+                Some(enabled) => Negotiated::Enabled(enabled),
+                None => Negotiated::Unsupported,
+            }
+        } else {
+            Negotiated::Unsupported
+        };
+        Ok((xmpp_stream, negotiated))
+    }
+
+    /// Set up Stream Management state for a new connection, and get
+    /// the missed stanzas re-sent if the previous session was resumed.
+    ///
+    /// Returns whether the session was resumed.
+    fn negotiated(
+        &mut self,
+        stream: &mut XMPPStream,
+        negotiated: Negotiated,
+    ) -> Result<bool, Error> {
+        if let Negotiated::Resumed(ref resumed) = negotiated {
+            if let Some(ref mut sm) = self.sm {
+                stream.jid = sm.jid.clone();
+                for stanza in sm.resumed(resumed.h) {
+                    Pin::new(&mut *stream).start_send(Packet::Stanza(stanza.clone()))?;
+                    sm.sent(stanza);
+                }
+                if sm.unacked_count() > 0 {
+                    Pin::new(&mut *stream).start_send(Packet::Stanza(R.into()))?;
+                    sm.requested();
+                }
+                return Ok(true);
+            }
+        }
+
+        // The responses to the previous session's requests won't come
+        self.iqs.disconnected();
+        if let Some(sm) = self.sm.take() {
+            if sm.unacked_count() > 0 {
+                warn!(
+                    "Dropping {} stanzas unacknowledged by the previous session",
+                    sm.unacked_count()
+                );
+            }
+        }
+        if let Negotiated::Enabled(enabled) = negotiated {
+            self.sm = Some(StreamManagement::new(enabled, stream.jid.clone()));
+        }
+        Ok(false)
     }
 
     /// Get the client's bound JID (the one reported by the XMPP
@@ -154,11 +642,118 @@ impl Client {
         }
     }
 
+    /// Get the features the server advertised on the current
+    /// connection, after authentication.
+    pub fn stream_features(&self) -> Option<&StreamFeatures> {
+        match self.state {
+            ClientState::Connected(ref stream) => Some(&stream.stream_features),
+            _ => None,
+        }
+    }
+
     /// Send stanza
     pub async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
         self.send(Packet::Stanza(stanza)).await
     }
 
+    /// Send a request, and get the future of the payload of its result
+    ///
+    /// The request gets a unique id, replacing the one it has. The
+    /// response has to come from the recipient of the request, within
+    /// `Config::iq_timeout`, and before the connection ends, unless
+    /// Stream Management resumes the session on the next one.
+    ///
+    /// The request is sent, and its response received, while this
+    /// client is being polled for events.
+    pub fn send_iq(
+        &mut self,
+        mut iq: Iq,
+    ) -> impl Future<Output = Result<Option<Element>, IqError>> {
+        let response = self.iqs.request(&mut iq);
+        let id = iq.id.clone();
+        if let Err(e) = Pin::new(&mut *self).start_send(Packet::Stanza(iq.into())) {
+            self.iqs.failed(&id, e);
+        }
+        response
+    }
+
+    /// Send a get request, and get the future of its parsed result
+    ///
+    /// See `send_iq()`.
+    pub fn send_get<P: IqGetPayload>(
+        &mut self,
+        to: Option<Jid>,
+        payload: P,
+    ) -> impl Future<Output = Result<Option<P::Response>, IqError>>
+    where
+        P::Response: TryFrom<Element, Error = ParsersError>,
+    {
+        let mut iq = Iq::from_get("", payload);
+        iq.to = to;
+        let response = self.send_iq(iq);
+        async move { parse_response(response.await?) }
+    }
+
+    /// Send a set request, and get the future of its parsed result
+    ///
+    /// See `send_iq()`.
+    pub fn send_set<P: IqSetPayload>(
+        &mut self,
+        to: Option<Jid>,
+        payload: P,
+    ) -> impl Future<Output = Result<Option<P::Response>, IqError>>
+    where
+        P::Response: TryFrom<Element, Error = ParsersError>,
+    {
+        let mut iq = Iq::from_set("", payload);
+        iq.to = to;
+        let response = self.send_iq(iq);
+        async move { parse_response(response.await?) }
+    }
+
+    /// Changes the password of the account (XEP-0077), used from the
+    /// next connection on once the server accepted it
+    ///
+    /// The request is sent, and its response received, while this
+    /// client is being polled for events.
+    pub fn change_password<P: Into<String>>(
+        &mut self,
+        password: P,
+    ) -> impl Future<Output = Result<(), Error>> {
+        let password = Zeroizing::new(password.into());
+        let username = self.config.jid.clone().node().unwrap_or_default();
+        let query = register::password_change(username, (*password).clone());
+        let (sender, receiver) = oneshot::channel();
+        self.new_password = Some(receiver);
+        let server = self.server_jid();
+        let response = self.send_set(Some(server), query);
+        async move {
+            response.await.map_err(register::request_error)?;
+            let _ = sender.send(password);
+            Ok(())
+        }
+    }
+
+    /// Cancels the registration, deleting the account (XEP-0077)
+    ///
+    /// Reconnection gets disabled, the server ending the stream once
+    /// the account is gone. The request is sent, and its response
+    /// received, while this client is being polled for events.
+    pub fn cancel_registration(&mut self) -> impl Future<Output = Result<(), Error>> {
+        self.reconnect = false;
+        let server = self.server_jid();
+        let response = self.send_set(Some(server), register::removal());
+        async move {
+            response.await.map_err(register::request_error)?;
+            Ok(())
+        }
+    }
+
+    /// JID of the server the account is on
+    fn server_jid(&self) -> Jid {
+        Jid::Bare(BareJid::domain(self.config.jid.clone().domain()))
+    }
+
     /// End connection by sending `</stream:stream>`
     ///
     /// You may expect the server to respond with the same. This
@@ -170,49 +765,79 @@ impl Client {
     }
 }
 
-/// Incoming XMPP events
-///
-/// In an `async fn` you may want to use this with `use
-/// futures::stream::StreamExt;`
-impl Stream for Client {
-    type Item = Event;
-
-    /// Low-level read on the XMPP stream, allowing the underlying
-    /// machinery to:
-    ///
-    /// * connect,
-    /// * starttls,
-    /// * authenticate,
-    /// * bind a session, and finally
-    /// * receive stanzas
-    ///
-    /// ...for your client
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+impl Client {
+    /// Drives the connection through its states, up to the next event
+    fn poll_event(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        // As soon as the connection task refreshed it
+        self.token_refreshed();
         let state = replace(&mut self.state, ClientState::Invalid);
 
         match state {
             ClientState::Invalid => panic!("Invalid client state"),
             ClientState::Disconnected if self.reconnect => {
-                // TODO: add timeout
-                let connect = tokio::spawn(Self::connect(
-                    self.config.server.clone(),
-                    self.config.jid.clone(),
-                    self.config.password.clone(),
-                ));
-                self.state = ClientState::Connecting(connect);
-                self.poll_next(cx)
+                self.attempts += 1;
+                let attempt = self.attempts;
+                if !self.config.reconnect.allows(attempt) {
+                    self.state = ClientState::Disconnected;
+                    return Poll::Ready(None);
+                }
+                let delay = self.config.reconnect.delay(attempt);
+                self.state = ClientState::Waiting(Box::pin(sleep(delay)));
+                Poll::Ready(Some(Event::Reconnecting { attempt, delay }))
             }
             ClientState::Disconnected => Poll::Ready(None),
+            ClientState::Waiting(mut delay) => match delay.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    self.state = ClientState::Connecting(self.spawn_connect());
+                    self.poll_event(cx)
+                }
+                Poll::Pending => {
+                    self.state = ClientState::Waiting(delay);
+                    Poll::Pending
+                }
+            },
             ClientState::Connecting(mut connect) => match Pin::new(&mut connect).poll(cx) {
-                Poll::Ready(Ok(Ok(stream))) => {
+                Poll::Ready(Ok(Ok(connected))) => {
+                    let Connected {
+                        mut stream,
+                        negotiated,
+                        fast_token,
+                        scram_keys,
+                        received,
+                    } = connected;
+                    self.received = received.into();
+                    self.config.sasl2.fast_token = fast_token;
+                    self.scram_keys(scram_keys);
+                    self.rejected = false;
+                    self.attempts = 0;
+                    self.redirects = 0;
+                    let resumed = match self.negotiated(&mut stream, negotiated) {
+                        Ok(resumed) => resumed,
+                        Err(e) => {
+                            self.state = ClientState::Disconnected;
+                            return Poll::Ready(Some(Event::Disconnected(e)));
+                        }
+                    };
                     let bound_jid = stream.jid.clone();
+                    let server = Jid::Bare(BareJid::domain(bound_jid.clone().domain()));
+                    self.keepalive = self
+                        .config
+                        .keepalive
+                        .clone()
+                        .map(|config| Keepalive::new(config, server));
                     self.state = ClientState::Connected(stream);
-                    Poll::Ready(Some(Event::Online {
-                        bound_jid,
-                        resumed: false,
-                    }))
+                    Poll::Ready(Some(Event::Online { bound_jid, resumed }))
                 }
                 Poll::Ready(Ok(Err(e))) => {
+                    if let Some(event) = self.redirect(&e) {
+                        return Poll::Ready(Some(event));
+                    }
+                    // Start over from the configured server
+                    self.redirect = None;
+                    self.rejected = matches!(e, Error::Auth(AuthError::Fail(_)));
+                    if !allows_reconnect(&e) {
+                        self.reconnect = false;
+                    }
                     self.state = ClientState::Disconnected;
                     return Poll::Ready(Some(Event::Disconnected(e.into())));
                 }
@@ -226,8 +851,38 @@ impl Stream for Client {
                 }
             },
             ClientState::Connected(mut stream) => {
-                // Poll sink
-                match Pin::new(&mut stream).poll_ready(cx) {
+                // Probe the server if the connection has been idle
+                let probe = match self.keepalive.as_mut().map(|keepalive| keepalive.poll(cx)) {
+                    Some(Poll::Ready(Ok(probe))) => Some(probe),
+                    Some(Poll::Ready(Err(e))) => {
+                        self.state = ClientState::Disconnected;
+                        return Poll::Ready(Some(Event::Disconnected(e)));
+                    }
+                    Some(Poll::Pending) | None => None,
+                };
+                let mut request_ack = false;
+                if let Some(probe) = probe {
+                    if let (Some(sm), Packet::Stanza(stanza)) = (self.sm.as_mut(), &probe) {
+                        request_ack = sm.sent(stanza.clone());
+                    }
+                    if let Err(e) = Pin::new(&mut stream).start_send(probe) {
+                        self.state = ClientState::Disconnected;
+                        return Poll::Ready(Some(Event::Disconnected(e)));
+                    }
+                }
+                // Ask for the ack of the stanzas sent a while ago
+                if let Some(ref mut sm) = self.sm {
+                    request_ack |= sm.poll_request(cx);
+                }
+                if request_ack {
+                    if let Err(e) = Pin::new(&mut stream).start_send(Packet::Stanza(R.into())) {
+                        self.state = ClientState::Disconnected;
+                        return Poll::Ready(Some(Event::Disconnected(e)));
+                    }
+                }
+
+                // Poll sink, flushing what we sent on our own
+                match Pin::new(&mut stream).poll_flush(cx) {
                     Poll::Pending => (),
                     Poll::Ready(Ok(())) => (),
                     Poll::Ready(Err(e)) => {
@@ -236,22 +891,92 @@ impl Stream for Client {
                     }
                 };
 
+                // Deliver what was received while connecting first
+                if let Some(stanza) = self.received.pop_front() {
+                    let stanza = self.iqs.response(stanza, &stream.jid);
+                    self.state = ClientState::Connected(stream);
+                    return match stanza {
+                        Some(stanza) => Poll::Ready(Some(Event::Stanza(stanza))),
+                        None => self.poll_event(cx),
+                    };
+                }
+
                 // Poll stream
-                match Pin::new(&mut stream).poll_next(cx) {
+                let packet = Pin::new(&mut stream).poll_next(cx);
+                if let (Poll::Ready(Some(Ok(_))), Some(keepalive)) =
+                    (&packet, self.keepalive.as_mut())
+                {
+                    keepalive.received();
+                }
+                match packet {
                     Poll::Ready(None) => {
                         // EOF
                         self.state = ClientState::Disconnected;
                         Poll::Ready(Some(Event::Disconnected(Error::Disconnected)))
                     }
+                    Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) if stanza.is("r", ns::SM) => {
+                        // Stream Management ack request
+                        if let Some(ref sm) = self.sm {
+                            let ack = Packet::Stanza(sm.ack().into());
+                            if let Err(e) = Pin::new(&mut stream).start_send(ack) {
+                                self.state = ClientState::Disconnected;
+                                return Poll::Ready(Some(Event::Disconnected(e)));
+                            }
+                        }
+                        self.state = ClientState::Connected(stream);
+                        self.poll_event(cx)
+                    }
+                    Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) if stanza.is("a", ns::SM) => {
+                        // Stream Management ack
+                        match (self.sm.as_mut(), A::try_from(stanza)) {
+                            (Some(sm), Ok(a)) => sm.acked(a.h),
+                            _ => warn!("Ignoring unexpected or invalid <a/>"),
+                        }
+                        self.state = ClientState::Connected(stream);
+                        self.poll_event(cx)
+                    }
+                    Poll::Ready(Some(Ok(Packet::Stanza(stanza))))
+                        if stanza.is("error", ns::STREAM) =>
+                    {
+                        // <stream:error/>, the server is closing the stream
+                        let error = Error::from_stream_error(stanza);
+                        if let Some(event) = self.redirect(&error) {
+                            return Poll::Ready(Some(event));
+                        }
+                        if !allows_reconnect(&error) {
+                            self.reconnect = false;
+                        }
+                        self.state = ClientState::Disconnected;
+                        Poll::Ready(Some(Event::Disconnected(error)))
+                    }
                     Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
                         // Receive stanza
+                        if let Some(ref mut sm) = self.sm {
+                            if StreamManagement::is_stanza(&stanza) {
+                                sm.received();
+                            }
+                        }
+                        let pong = match self.keepalive {
+                            Some(ref mut keepalive) => keepalive.is_pong(&stanza),
+                            None => false,
+                        };
+                        if pong {
+                            // Answer to our keepalive ping
+                            self.state = ClientState::Connected(stream);
+                            return self.poll_event(cx);
+                        }
+                        let stanza = self.iqs.response(stanza, &stream.jid);
                         self.state = ClientState::Connected(stream);
-                        Poll::Ready(Some(Event::Stanza(stanza)))
+                        match stanza {
+                            Some(stanza) => Poll::Ready(Some(Event::Stanza(stanza))),
+                            // Response to send_iq()
+                            None => self.poll_event(cx),
+                        }
                     }
                     Poll::Ready(Some(Ok(Packet::Text(_)))) => {
                         // Ignore text between stanzas
                         self.state = ClientState::Connected(stream);
-                        Poll::Pending
+                        self.poll_event(cx)
                     }
                     Poll::Ready(Some(Ok(Packet::StreamStart(_)))) => {
                         // <stream:stream>
@@ -270,6 +995,19 @@ impl Stream for Client {
                         self.state = ClientState::Connected(stream);
                         Poll::Pending
                     }
+                    Poll::Ready(Some(Err(Error::Policy(e)))) => {
+                        // Tell the server why we close the stream, as
+                        // far as it can be sent right away
+                        let error = Packet::Stanza(e.to_stream_error().into());
+                        let sent = Pin::new(&mut stream)
+                            .start_send(error)
+                            .and_then(|()| Pin::new(&mut stream).start_send(Packet::StreamEnd));
+                        if sent.is_ok() {
+                            let _ = Pin::new(&mut stream).poll_flush(cx);
+                        }
+                        self.state = ClientState::Disconnected;
+                        Poll::Ready(Some(Event::Disconnected(e.into())))
+                    }
                     Poll::Ready(Some(Err(e))) => {
                         self.state = ClientState::Disconnected;
                         Poll::Ready(Some(Event::Disconnected(e.into())))
@@ -280,16 +1018,72 @@ impl Stream for Client {
     }
 }
 
+/// Incoming XMPP events
+///
+/// In an `async fn` you may want to use this with `use
+/// futures::stream::StreamExt;`
+impl Stream for Client {
+    type Item = Event;
+
+    /// Low-level read on the XMPP stream, allowing the underlying
+    /// machinery to:
+    ///
+    /// * connect,
+    /// * starttls,
+    /// * authenticate,
+    /// * bind a session, and finally
+    /// * receive stanzas
+    ///
+    /// ...for your client
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let event = self.as_mut().poll_event(cx);
+        let ended = match event {
+            Poll::Ready(Some(Event::Disconnected(_))) => !self.reconnect,
+            Poll::Ready(Some(Event::Redirected { .. })) => false,
+            Poll::Ready(None) => true,
+            _ => return event,
+        };
+        let resumable = self
+            .sm
+            .as_ref()
+            .map_or(false, |sm| sm.resume_token().is_some());
+        if ended || !resumable {
+            // Their responses only come if the session gets resumed,
+            // negotiated() failing them otherwise
+            self.iqs.disconnected();
+        }
+        event
+    }
+}
+
 /// Outgoing XMPP packets
 ///
 /// See `send_stanza()` for an `async fn`
 impl Sink<Packet> for Client {
     type Error = Error;
 
-    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
-        match self.state {
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match this.state {
             ClientState::Connected(ref mut stream) => {
-                Pin::new(stream).start_send(item).map_err(|e| e.into())
+                if let Some(ref mut keepalive) = this.keepalive {
+                    keepalive.sent();
+                }
+                let sm_stanza = match (this.sm.as_mut(), &item) {
+                    (Some(sm), Packet::Stanza(stanza)) if StreamManagement::is_stanza(stanza) => {
+                        Some((sm, stanza.clone()))
+                    }
+                    _ => None,
+                };
+                Pin::new(&mut *stream).start_send(item)?;
+                if let Some((sm, stanza)) = sm_stanza {
+                    // Keep it until acknowledged, asking for that once
+                    // enough stanzas were sent
+                    if sm.sent(stanza) {
+                        Pin::new(stream).start_send(Packet::Stanza(R.into()))?;
+                    }
+                }
+                Ok(())
             }
             _ => Err(Error::InvalidState),
         }
@@ -322,3 +1116,396 @@ impl Sink<Packet> for Client {
         }
     }
 }
+
+/// Server to connect to when `current` redirects us to `host` and
+/// `port`, with the same TLS mode
+///
+/// After SRV lookups, the TLS policy decides, as it does for the
+/// fallback port.
+fn redirected(current: &ServerConfig, host: String, port: u16) -> ServerConfig {
+    let direct_tls = match *current {
+        ServerConfig::Manual { direct_tls, .. } => direct_tls,
+        _ => false,
+    };
+    ServerConfig::Manual {
+        host,
+        port,
+        direct_tls,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_util::{expect, id_of, DuplexConnector, STREAM_HEADER};
+    use crate::{KeepaliveProbe, OAuthRefresher};
+    use futures::future::{Either, FutureExt};
+    use futures::stream::StreamExt;
+    use std::sync::Mutex;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::Instant;
+
+    /// Plays the server side of a login with `mechanism`, expecting
+    /// `data` from the client, and resource binding
+    async fn scripted_server(mut server: DuplexStream, mechanism: &str, data: &str) {
+        expect(&mut server, "<stream:stream").await;
+        let features = format!("<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>{}</mechanism></mechanisms></stream:features>", mechanism);
+        server.write_all(STREAM_HEADER.as_bytes()).await.unwrap();
+        server.write_all(features.as_bytes()).await.unwrap();
+
+        let auth = expect(&mut server, "</auth>").await;
+        assert!(auth.contains(&format!("mechanism='{}'", mechanism)));
+        assert!(auth.contains(data));
+        let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+        server.write_all(success.as_bytes()).await.unwrap();
+
+        expect(&mut server, "<stream:stream").await;
+        let features =
+            "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>";
+        server.write_all(STREAM_HEADER.as_bytes()).await.unwrap();
+        server.write_all(features.as_bytes()).await.unwrap();
+
+        let bind = expect(&mut server, "</iq>").await;
+        assert!(bind.contains("<resource>balcony</resource>"));
+        let result = "<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>juliet@capulet.example/balcony</jid></bind></iq>";
+        server.write_all(result.as_bytes()).await.unwrap();
+
+        let message = "<message from='romeo@montague.example/orchard' type='chat'><body>Hello</body></message>";
+        server.write_all(message.as_bytes()).await.unwrap();
+        let answer = expect(&mut server, "</message>").await;
+        assert!(answer.contains("<body>Hi</body>"));
+        server.write_all(b"</stream:stream>").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connector() {
+        let (client_end, server_end) = duplex(65536);
+        let server = tokio::spawn(scripted_server(server_end, "PLAIN", "AGp1bGlldABzZWNyZXQ="));
+
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid.clone(), "secret");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        config.allow_plain_without_tls = true;
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Online {
+                bound_jid,
+                resumed: false,
+            }) => assert_eq!(bound_jid, jid),
+            event => panic!("{:?}", event),
+        }
+        match client.next().await {
+            Some(Event::Stanza(stanza)) => {
+                assert!(stanza.is("message", ns::JABBER_CLIENT));
+                assert_eq!(stanza.attr("from"), Some("romeo@montague.example/orchard"));
+            }
+            event => panic!("{:?}", event),
+        }
+
+        let answer = Element::builder("message", ns::JABBER_CLIENT)
+            .attr("to", "romeo@montague.example/orchard")
+            .attr("type", "chat")
+            .append(
+                Element::builder("body", ns::JABBER_CLIENT)
+                    .append("Hi")
+                    .build(),
+            )
+            .build();
+        client.send_stanza(answer).await.unwrap();
+        match client.next().await {
+            Some(Event::Disconnected(Error::Disconnected)) => (),
+            event => panic!("{:?}", event),
+        }
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_redirected() {
+        let server = ServerConfig::Manual {
+            host: String::from("xmpp.capulet.example"),
+            port: 5223,
+            direct_tls: true,
+        };
+        match redirected(&server, String::from("192.0.2.1"), 443) {
+            ServerConfig::Manual {
+                host,
+                port: 443,
+                direct_tls: true,
+            } => assert_eq!(host, "192.0.2.1"),
+            server => panic!("{:?}", server),
+        }
+        match redirected(&ServerConfig::UseSrv, String::from("192.0.2.1"), 5222) {
+            ServerConfig::Manual {
+                port: 5222,
+                direct_tls: false,
+                ..
+            } => (),
+            server => panic!("{:?}", server),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plain_refused() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            let mut rest = String::new();
+            server_end.read_to_string(&mut rest).await.unwrap();
+            assert!(!rest.contains("<auth"));
+        });
+
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid, "secret");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Disconnected(Error::Auth(AuthError::PlainWithoutTls))) => (),
+            event => panic!("{:?}", event),
+        }
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oauth() {
+        let (client_end, server_end) = duplex(65536);
+        let data = "bixhPWp1bGlldEBjYXB1bGV0LmV4YW1wbGUsAWF1dGg9QmVhcmVyIHMzY3IzdAEB";
+        let server = tokio::spawn(scripted_server(server_end, "OAUTHBEARER", data));
+
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid.clone(), "");
+        config.oauth = Some(OAuthCredentials::new(OAuthToken::new("s3cr3t")));
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        config.allow_plain_without_tls = true;
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Online { bound_jid, .. }) => assert_eq!(bound_jid, jid),
+            event => panic!("{:?}", event),
+        }
+        // The rest of the script is test_connector()'s
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_oauth_refreshed() {
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid, "");
+        let expired = OAuthToken {
+            token: String::from("old"),
+            expiry: Some(std::time::SystemTime::now()),
+        };
+        let refresh: OAuthRefresher = Arc::new(|_| async { Some(OAuthToken::new("new")) }.boxed());
+        config.oauth = Some(OAuthCredentials {
+            token: expired,
+            refresh: Some(refresh),
+        });
+        // Nothing to connect to
+        let connector = DuplexConnector(Mutex::new(None));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Disconnected(Error::Disconnected)) => (),
+            event => panic!("{:?}", event),
+        }
+        // Kept even though the attempt failed, as the refresh token
+        // may have been replaced
+        assert_eq!(client.config.oauth.as_ref().unwrap().token.token, "new");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism><mechanism>ANONYMOUS</mechanism></mechanisms></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            expect(&mut server_end, "mechanism='ANONYMOUS'").await;
+            let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+            server_end.write_all(success.as_bytes()).await.unwrap();
+
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            let bind = expect(&mut server_end, "</iq>").await;
+            assert!(!bind.contains("<resource>"));
+            let result = "<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>f81d4fae@capulet.example/guest</jid></bind></iq>";
+            server_end.write_all(result.as_bytes()).await.unwrap();
+            server_end.write_all(b"</stream:stream>").await.unwrap();
+        });
+
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let mut config = Config::new(jid, "");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Online { bound_jid, .. }) => {
+                assert_eq!(
+                    bound_jid,
+                    Jid::from_str("f81d4fae@capulet.example/guest").unwrap()
+                )
+            }
+            event => panic!("{:?}", event),
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_unavailable() {
+        let (client_end, server_end) = duplex(65536);
+        let server = tokio::spawn(scripted_server(server_end, "SCRAM-SHA-1", ""));
+
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let mut config = Config::new(jid, "");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        let mut client = Client::new_with_config(config);
+
+        match client.next().await {
+            Some(Event::Disconnected(Error::Auth(AuthError::AnonymousUnavailable))) => (),
+            event => panic!("{:?}", event),
+        }
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_change_password() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            expect(&mut server_end, "</auth>").await;
+            let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+            server_end.write_all(success.as_bytes()).await.unwrap();
+
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            expect(&mut server_end, "</iq>").await;
+            let result = "<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>juliet@capulet.example/balcony</jid></bind></iq>";
+            server_end.write_all(result.as_bytes()).await.unwrap();
+
+            let set = expect(&mut server_end, "</iq>").await;
+            assert!(set.contains("<username>juliet</username>"));
+            assert!(set.contains("<password>n3w</password>"));
+            let result = format!(
+                "<iq type='result' from='capulet.example' id='{}'/>",
+                id_of(&set)
+            );
+            server_end.write_all(result.as_bytes()).await.unwrap();
+        });
+
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid, "secret");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        config.allow_plain_without_tls = true;
+        let mut client = Client::new_with_config(config);
+        match client.next().await {
+            Some(Event::Online { .. }) => (),
+            event => panic!("{:?}", event),
+        }
+
+        let change = client.change_password("n3w");
+        futures::pin_mut!(change);
+        match futures::future::select(change, client.next()).await {
+            Either::Left((result, _)) => result.unwrap(),
+            Either::Right((event, _)) => panic!("{:?}", event),
+        }
+        // Used from the next connection on
+        client.password_changed();
+        assert_eq!(*client.config.password, "n3w");
+        server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_timeout() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            expect(&mut server_end, "</auth>").await;
+            let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+            server_end.write_all(success.as_bytes()).await.unwrap();
+
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+            expect(&mut server_end, "</iq>").await;
+            let result = "<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>juliet@capulet.example/balcony</jid></bind></iq>";
+            server_end.write_all(result.as_bytes()).await.unwrap();
+
+            // Never answered, the stream being kept open
+            let ping = expect(&mut server_end, "</iq>").await;
+            assert!(ping.contains("urn:xmpp:ping"));
+            assert!(ping.contains("to='capulet.example'"));
+            server_end
+        });
+
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        let mut config = Config::new(jid, "secret");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        config.allow_plain_without_tls = true;
+        config.keepalive = Some(KeepaliveConfig {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            probe: KeepaliveProbe::Ping,
+        });
+        let mut client = Client::new_with_config(config);
+        match client.next().await {
+            Some(Event::Online { .. }) => (),
+            event => panic!("{:?}", event),
+        }
+
+        // Polled only once, the timer has to wake the client up after
+        // sending the ping
+        let start = Instant::now();
+        match client.next().await {
+            Some(Event::Disconnected(Error::KeepaliveTimeout)) => (),
+            event => panic!("{:?}", event),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(70));
+        server.await.unwrap();
+    }
+}
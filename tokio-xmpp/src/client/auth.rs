@@ -1,80 +1,368 @@
 use futures::stream::StreamExt;
-use sasl::client::mechanisms::{Anonymous, Plain, Scram};
+use sasl::client::mechanisms::{Anonymous, Plain};
 use sasl::client::Mechanism;
-use sasl::common::scram::{Sha1, Sha256};
-use sasl::common::Credentials;
+use sasl::common::{ChannelBinding, Credentials, Identity, Password, Secret};
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncWrite};
 use xmpp_parsers::sasl::{Auth, Challenge, Failure, Mechanism as XMPPMechanism, Response, Success};
 
+use super::oauth::{username_and_token, OAuthBearer, XOAuth2};
+use super::scram::{Scram, ScramHash, ScramKeys};
 use crate::xmpp_codec::Packet;
 use crate::xmpp_stream::XMPPStream;
 use crate::{AuthError, Error, ProtocolError};
 
+/// Password mechanisms, in the built-in order of preference
+const MECHANISMS: &[&str] = &[
+    "SCRAM-SHA-256-PLUS",
+    "SCRAM-SHA-1-PLUS",
+    "SCRAM-SHA-256",
+    "SCRAM-SHA-1",
+    "PLAIN",
+];
+
+/// Token mechanisms, in the built-in order of preference
+const TOKEN_MECHANISMS: &[&str] = &["OAUTHBEARER", "X-OAUTH2"];
+
+/// Client side of a SASL mechanism, be it one of the `sasl` crate or
+/// one implemented here
+pub trait ClientMechanism: Send + Sync {
+    /// Initial response, sent along with the mechanism name
+    fn initial(&mut self) -> Vec<u8>;
+
+    /// Response to a challenge of the server
+    fn response(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Checks the additional data of the server success
+    fn success(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// SCRAM keys the server accepted, after success
+    fn scram_keys(&self) -> Option<&ScramKeys> {
+        None
+    }
+}
+
+impl<M: Mechanism + Send + Sync> ClientMechanism for M {
+    fn initial(&mut self) -> Vec<u8> {
+        Mechanism::initial(self)
+    }
+
+    fn response(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        Mechanism::response(self, challenge).map_err(|e| AuthError::Sasl(e).into())
+    }
+
+    fn success(&mut self, data: &[u8]) -> Result<(), Error> {
+        Mechanism::success(self, data).map_err(|e| AuthError::Sasl(e).into())
+    }
+}
+
+/// How to pick the mechanism, besides the credentials
+#[derive(Clone, Debug, Default)]
+pub struct AuthOptions {
+    /// Mechanisms to use, in order of preference, instead of the
+    /// built-in order
+    pub mechanisms: Option<Vec<String>>,
+    /// Whether PLAIN, or sending an OAuth token, is allowed
+    pub allow_plain: bool,
+    /// Whether the credentials carry the bare JID as username and an
+    /// OAuth 2.0 access token as password, which only OAUTHBEARER and
+    /// X-OAUTH2 can send
+    pub oauth: bool,
+    /// Whether to log in anonymously, with ANONYMOUS, and nothing else
+    pub anonymous: bool,
+    /// Keys to use for SCRAM instead of the password, replaced by the
+    /// accepted ones after success
+    pub scram_keys: Option<ScramKeys>,
+}
+
+impl AuthOptions {
+    /// Local mechanisms out of `all`, in order of preference
+    fn local_mechanisms(&self, all: &[&'static str]) -> Vec<&'static str> {
+        match self.mechanisms {
+            Some(ref mechanisms) => mechanisms
+                .iter()
+                .filter_map(|name| all.iter().find(|known| **known == name.as_str()).copied())
+                .collect(),
+            None => all.to_vec(),
+        }
+    }
+}
+
+/// Picks the best mechanism supported by both sides among
+/// `remote_mechs`, preferring SCRAM-*-PLUS when `creds` carry channel
+/// binding data, unless `options` say otherwise.
+///
+/// SCRAM is used with the password, or with the stored keys for their
+/// hash function. PLAIN, and OAuth tokens, are only sent if allowed.
+///
+/// Returns the name to announce, as the sasl mechanisms don't tell
+/// -PLUS apart in theirs.
+pub fn select_mechanism(
+    creds: &Credentials,
+    remote_mechs: &HashSet<String>,
+    options: &AuthOptions,
+) -> Result<(&'static str, Box<dyn ClientMechanism>), Error> {
+    if options.oauth {
+        return select_token_mechanism(creds, remote_mechs, options);
+    }
+    if options.anonymous {
+        return select_anonymous(remote_mechs, options);
+    }
+    let local_mechs = options.local_mechanisms(MECHANISMS);
+    let username = match creds.identity {
+        Identity::Username(ref username) => Some(username.clone()),
+        Identity::None => None,
+    };
+    let password = match creds.secret {
+        Secret::Password(Password::Plain(ref password)) => Some(password.clone()),
+        _ => None,
+    };
+    // Channel binding is only announced when a -PLUS mechanism may be
+    // used.
+    let can_bind = !matches!(
+        creds.channel_binding,
+        ChannelBinding::None | ChannelBinding::Unsupported
+    ) && local_mechs.iter().any(|name| name.ends_with("-PLUS"));
+    let server_binds = remote_mechs.iter().any(|mech| mech.ends_with("-PLUS"));
+    let scram_hash = |name: &str| {
+        if name.starts_with("SCRAM-SHA-256") {
+            ScramHash::Sha256
+        } else {
+            ScramHash::Sha1
+        }
+    };
+    let has_keys = |name: &str| {
+        options
+            .scram_keys
+            .as_ref()
+            .map_or(false, |keys| keys.hash == scram_hash(name))
+    };
+
+    let usable = |name: &&str| match *name {
+        "PLAIN" => options.allow_plain && username.is_some() && password.is_some(),
+        name if name.ends_with("-PLUS") => {
+            can_bind && username.is_some() && (password.is_some() || has_keys(name))
+        }
+        name => username.is_some() && (password.is_some() || has_keys(name)),
+    };
+    let name = match local_mechs
+        .iter()
+        .copied()
+        .filter(usable)
+        .find(|name| remote_mechs.contains(*name))
+    {
+        Some(name) => name,
+        None if !options.allow_plain
+            && local_mechs.contains(&"PLAIN")
+            && remote_mechs.contains("PLAIN") =>
+        {
+            return Err(AuthError::PlainWithoutTls.into())
+        }
+        None => return Err(AuthError::NoMechanism.into()),
+    };
+    if can_bind && server_binds && !name.ends_with("-PLUS") {
+        // The server binds the channel, but none of its -PLUS
+        // mechanisms are ours: don't fall back to an unbound one.
+        return Err(AuthError::NoChannelBinding.into());
+    }
+
+    // Without channel binding, still tell the server whether we support
+    // it, so that it notices if the -PLUS mechanisms were stripped from
+    // its list on the way.
+    let unbound = if can_bind {
+        ChannelBinding::Unsupported
+    } else {
+        ChannelBinding::None
+    };
+
+    let mechanism: Box<dyn ClientMechanism> = match name {
+        "PLAIN" => Box::new(Plain::from_credentials(creds.clone()).map_err(AuthError::Sasl)?),
+        scram => {
+            let channel_binding = if scram.ends_with("-PLUS") {
+                creds.channel_binding.clone()
+            } else {
+                unbound
+            };
+            Box::new(Scram::new(
+                scram_hash(scram),
+                username.unwrap_or_default(),
+                password,
+                options.scram_keys.clone(),
+                channel_binding,
+            ))
+        }
+    };
+    Ok((name, mechanism))
+}
+
+/// Picks OAUTHBEARER, or the legacy X-OAUTH2, to send the token in
+/// `creds` with
+fn select_token_mechanism(
+    creds: &Credentials,
+    remote_mechs: &HashSet<String>,
+    options: &AuthOptions,
+) -> Result<(&'static str, Box<dyn ClientMechanism>), Error> {
+    let name = match options
+        .local_mechanisms(TOKEN_MECHANISMS)
+        .into_iter()
+        .find(|name| remote_mechs.contains(*name))
+    {
+        Some(_) if !options.allow_plain => return Err(AuthError::TokenWithoutTls.into()),
+        Some(name) => name,
+        None => return Err(AuthError::NoMechanism.into()),
+    };
+    let (username, token) = username_and_token(creds).ok_or(AuthError::NoToken)?;
+    let mechanism: Box<dyn ClientMechanism> = match name {
+        "OAUTHBEARER" => Box::new(OAuthBearer::new(username, token)),
+        _ => Box::new(XOAuth2::new(username, token)),
+    };
+    Ok((name, mechanism))
+}
+
+/// Picks ANONYMOUS, unless the server or `options` leave it out
+fn select_anonymous(
+    remote_mechs: &HashSet<String>,
+    options: &AuthOptions,
+) -> Result<(&'static str, Box<dyn ClientMechanism>), Error> {
+    if options.local_mechanisms(&["ANONYMOUS"]).is_empty() {
+        return Err(AuthError::NoMechanism.into());
+    }
+    if !remote_mechs.contains("ANONYMOUS") {
+        return Err(AuthError::AnonymousUnavailable.into());
+    }
+    Ok(("ANONYMOUS", Box::new(Anonymous::new())))
+}
+
+/// Authenticates with the best mechanism supported by both sides, as
+/// picked by `select_mechanism()`, keeping the SCRAM keys which got
+/// accepted in `options`.
 pub async fn auth<S: AsyncRead + AsyncWrite + Unpin>(
     mut stream: XMPPStream<S>,
     creds: Credentials,
+    options: &mut AuthOptions,
 ) -> Result<S, Error> {
-    let local_mechs: Vec<Box<dyn Fn() -> Box<dyn Mechanism + Send + Sync> + Send>> = vec![
-        Box::new(|| Box::new(Scram::<Sha256>::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Scram::<Sha1>::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Plain::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Anonymous::new())),
-    ];
-
-    let remote_mechs: HashSet<String> = stream.stream_features.sasl_mechanisms()?.collect();
-
-    for local_mech in local_mechs {
-        let mut mechanism = local_mech();
-        if remote_mechs.contains(mechanism.name()) {
-            let initial = mechanism.initial();
-            let mechanism_name =
-                XMPPMechanism::from_str(mechanism.name()).map_err(ProtocolError::Parsers)?;
-
-            stream
-                .send_stanza(Auth {
-                    mechanism: mechanism_name,
-                    data: initial,
-                })
-                .await?;
-
-            loop {
-                match stream.next().await {
-                    Some(Ok(Packet::Stanza(stanza))) => {
-                        if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
-                            let response = mechanism
-                                .response(&challenge.data)
-                                .map_err(|e| AuthError::Sasl(e))?;
-
-                            // Send response and loop
-                            stream.send_stanza(Response { data: response }).await?;
-                        } else if let Ok(_) = Success::try_from(stanza.clone()) {
-                            return Ok(stream.into_inner());
-                        } else if let Ok(failure) = Failure::try_from(stanza.clone()) {
-                            return Err(Error::Auth(AuthError::Fail(failure.defined_condition)));
-                        // TODO: This code was needed for compatibility with some broken server,
-                        // but it’s been forgotten which.  It is currently commented out so that we
-                        // can find it and fix the server software instead.
-                        /*
-                        } else if stanza.name() == "failure" {
-                            // Workaround for https://gitlab.com/xmpp-rs/xmpp-parsers/merge_requests/1
-                            return Err(Error::Auth(AuthError::Sasl("failure".to_string())));
-                        */
-                        } else {
-                            // ignore and loop
-                        }
-                    }
-                    Some(Ok(_)) => {
-                        // ignore and loop
+    let remote_mechs: HashSet<String> = stream
+        .stream_features
+        .sasl_mechanisms()
+        .map(String::from)
+        .collect();
+    let (name, mut mechanism) = select_mechanism(&creds, &remote_mechs, options)?;
+
+    let initial = mechanism.initial();
+    // The sasl mechanisms don't tell -PLUS apart in their name.
+    let mechanism_name = XMPPMechanism::from_str(name).map_err(ProtocolError::Parsers)?;
+
+    stream
+        .send_stanza(Auth {
+            mechanism: mechanism_name,
+            data: initial,
+        })
+        .await?;
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => {
+                if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
+                    let response = mechanism.response(&challenge.data)?;
+
+                    // Send response and loop
+                    stream.send_stanza(Response { data: response }).await?;
+                } else if let Ok(success) = Success::try_from(stanza.clone()) {
+                    mechanism.success(&success.data)?;
+                    if let Some(keys) = mechanism.scram_keys() {
+                        options.scram_keys = Some(keys.clone());
                     }
-                    Some(Err(e)) => return Err(e),
-                    None => return Err(Error::Disconnected),
+                    return Ok(stream.into_inner());
+                } else if let Ok(failure) = Failure::try_from(stanza.clone()) {
+                    return Err(Error::Auth(AuthError::Fail(failure.defined_condition)));
+                // TODO: This code was needed for compatibility with some broken server,
+                // but it’s been forgotten which.  It is currently commented out so that we
+                // can find it and fix the server software instead.
+                /*
+                } else if stanza.name() == "failure" {
+                    // Workaround for https://gitlab.com/xmpp-rs/xmpp-parsers/merge_requests/1
+                    return Err(Error::Auth(AuthError::Sasl("failure".to_string())));
+                */
+                } else {
+                    // ignore and loop
                 }
             }
+            Some(Ok(_)) => {
+                // ignore and loop
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(mechs: &[&str]) -> HashSet<String> {
+        mechs.iter().map(|mech| String::from(*mech)).collect()
+    }
+
+    fn selected(
+        creds: &Credentials,
+        mechs: &[&str],
+        options: &AuthOptions,
+    ) -> Result<&'static str, Error> {
+        select_mechanism(creds, &remote(mechs), options).map(|(name, _)| name)
+    }
+
+    #[test]
+    fn test_select_mechanism() {
+        let creds = Credentials::default()
+            .with_username("juliet")
+            .with_password("s3cr3t")
+            .with_channel_binding(ChannelBinding::TlsExporter(vec![0; 32]));
+        let all = [
+            "SCRAM-SHA-1",
+            "SCRAM-SHA-256",
+            "SCRAM-SHA-256-PLUS",
+            "PLAIN",
+        ];
+        let mut options = AuthOptions::default();
+        assert_eq!(
+            selected(&creds, &all, &options).unwrap(),
+            "SCRAM-SHA-256-PLUS"
+        );
+        match selected(&creds, &["PLAIN"], &options) {
+            Err(Error::Auth(AuthError::PlainWithoutTls)) => (),
+            _ => panic!(),
         }
+        options.allow_plain = true;
+        assert_eq!(selected(&creds, &["PLAIN"], &options).unwrap(), "PLAIN");
+
+        // Pinned, without channel binding then
+        options.mechanisms = Some(vec![String::from("SCRAM-SHA-256")]);
+        assert_eq!(selected(&creds, &all, &options).unwrap(), "SCRAM-SHA-256");
+        match selected(&creds, &["SCRAM-SHA-1", "PLAIN"], &options) {
+            Err(Error::Auth(AuthError::NoMechanism)) => (),
+            _ => panic!(),
+        }
+        options.mechanisms = Some(vec![String::from("PLAIN"), String::from("SCRAM-SHA-1")]);
+        assert_eq!(selected(&creds, &all, &options).unwrap(), "PLAIN");
     }
 
-    Err(AuthError::NoMechanism.into())
+    #[test]
+    fn test_select_scram_keys() {
+        let creds = Credentials::default().with_username("juliet");
+        let keys = ScramKeys::derive(ScramHash::Sha1, "s3cr3t", b"salt".to_vec(), 4096);
+        let options = AuthOptions {
+            scram_keys: Some(keys),
+            allow_plain: true,
+            ..AuthOptions::default()
+        };
+        let all = ["SCRAM-SHA-1", "SCRAM-SHA-256", "PLAIN"];
+        assert_eq!(selected(&creds, &all, &options).unwrap(), "SCRAM-SHA-1");
+        match selected(&creds, &["SCRAM-SHA-256", "PLAIN"], &options) {
+            Err(Error::Auth(AuthError::NoMechanism)) => (),
+            _ => panic!(),
+        }
+    }
 }
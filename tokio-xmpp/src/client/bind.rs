@@ -15,7 +15,7 @@ const BIND_REQ_ID: &str = "resource-bind";
 pub async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
     mut stream: XMPPStream<S>,
 ) -> Result<XMPPStream<S>, Error> {
-    if stream.stream_features.can_bind() {
+    if stream.stream_features.bind {
         let resource = if let Jid::Full(jid) = stream.jid.clone() {
             Some(jid.resource)
         } else {
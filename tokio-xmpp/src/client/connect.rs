@@ -0,0 +1,576 @@
+use futures::future::BoxFuture;
+use futures::sink::SinkExt;
+use sasl::common::ChannelBinding;
+use std::fmt;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(unix)]
+use tokio::time::timeout;
+#[cfg(feature = "tls-native")]
+use tokio_native_tls::TlsStream;
+#[cfg(feature = "tls-rust")]
+use tokio_rustls::client::TlsStream;
+use xmpp_parsers::{ns, Jid};
+
+use super::async_client::ServerConfig;
+use super::reconnect::{within, Timeouts};
+#[cfg(feature = "bosh")]
+use crate::bosh;
+use crate::happy_eyeballs::{
+    connect_to_host, connect_to_host_with_endpoint, connect_with_srv, Endpoint,
+};
+use crate::proxy::ProxyConfig;
+use crate::resolver::{default_resolver, Resolver};
+use crate::starttls::{channel_binding, direct_tls, starttls};
+use crate::tls::{TlsConfig, TlsPolicy};
+#[cfg(feature = "websocket")]
+use crate::websocket;
+use crate::xmpp_codec::Packet;
+use crate::xmpp_stream::{AsyncReadAndWrite, XMPPStream};
+use crate::{ConnectionPhase, Error, ProtocolError, TlsError};
+
+/// Client SRV services, along with whether they use Direct TLS
+const CLIENT_SERVICES: &[(&str, bool)] =
+    &[("_xmpps-client._tcp", true), ("_xmpp-client._tcp", false)];
+
+/// Client SRV services using Direct TLS
+const DIRECT_TLS_SERVICES: &[(&str, bool)] = &[("_xmpps-client._tcp", true)];
+
+/// Client SRV services using `<starttls/>`, or plaintext
+const STARTTLS_SERVICES: &[(&str, bool)] = &[("_xmpp-client._tcp", false)];
+
+/// Stream to speak XMPP over, along with what secures it
+pub struct Connection {
+    /// Stream to the server
+    pub stream: Box<dyn AsyncReadAndWrite>,
+    /// Channel binding data of its TLS connection,
+    /// `ChannelBinding::None` without TLS or when the TLS
+    /// implementation gives none
+    pub channel_binding: ChannelBinding,
+    /// Whether the stream is encrypted, PLAIN being refused otherwise
+    /// unless explicitly allowed
+    pub encrypted: bool,
+}
+
+impl Connection {
+    /// Unencrypted stream
+    pub fn plaintext<S: AsyncReadAndWrite + 'static>(stream: S) -> Self {
+        Connection {
+            stream: Box::new(stream),
+            channel_binding: ChannelBinding::None,
+            encrypted: false,
+        }
+    }
+}
+
+/// Transport establishing the connection to the server, given to the
+/// client as `ServerConfig::Connector`
+///
+/// The client then runs stream negotiation, authentication and
+/// resource binding over whatever stream the connector returns, be it
+/// a Unix socket, a tunnel, or an in-memory stream in tests.
+pub trait ServerConnector: fmt::Debug + Send + Sync {
+    /// Connects to the server of `jid`, and secures the connection as
+    /// this transport requires
+    fn connect<'a>(
+        &'a self,
+        jid: &'a Jid,
+        timeouts: &'a Timeouts,
+        tls: &'a TlsConfig,
+    ) -> BoxFuture<'a, Result<Connection, Error>>;
+}
+
+/// Connects to the server of `jid` found through SRV records, over
+/// Direct TLS or STARTTLS depending on the record and `policy`
+#[derive(Debug, Clone)]
+pub struct SrvConnector {
+    /// Resolver to look up the SRV records and hosts with
+    pub resolver: Arc<dyn Resolver>,
+    /// Proxy to connect through, if any
+    pub proxy: Option<ProxyConfig>,
+    /// Whether and how to encrypt the connection
+    pub policy: TlsPolicy,
+}
+
+impl Default for SrvConnector {
+    /// The system resolver, behind a cache, no proxy, and TLS required
+    fn default() -> Self {
+        SrvConnector {
+            resolver: default_resolver(),
+            proxy: None,
+            policy: TlsPolicy::default(),
+        }
+    }
+}
+
+impl ServerConnector for SrvConnector {
+    fn connect<'a>(
+        &'a self,
+        jid: &'a Jid,
+        timeouts: &'a Timeouts,
+        tls: &'a TlsConfig,
+    ) -> BoxFuture<'a, Result<Connection, Error>> {
+        Box::pin(async move {
+            let domain = jid.clone().domain();
+            let (services, fallback) = match self.policy {
+                TlsPolicy::DirectTls => (DIRECT_TLS_SERVICES, (5223, true)),
+                TlsPolicy::InsecurePlaintext => (STARTTLS_SERVICES, (5222, false)),
+                TlsPolicy::RequireStartTls | TlsPolicy::Opportunistic => {
+                    (CLIENT_SERVICES, (5222, false))
+                }
+            };
+            let (tcp_stream, direct_tls, endpoint) = connect_with_srv(
+                &domain,
+                services,
+                fallback,
+                timeouts.connect,
+                &self.resolver,
+                self.proxy.as_ref(),
+            )
+            .await?;
+            if let Some(connection) = secure_tcp(
+                tcp_stream,
+                jid,
+                direct_tls,
+                self.policy,
+                &self.proxy,
+                timeouts,
+                tls,
+            )
+            .await?
+            {
+                return Ok(connection);
+            }
+
+            // No <starttls/> on offer, which the policy makes do with,
+            // on the very server which didn't offer it
+            let tcp_stream = reconnect(&endpoint, timeouts, &self.resolver, &self.proxy).await?;
+            Ok(Connection::plaintext(tcp_stream))
+        })
+    }
+}
+
+/// Connects to a server host and port over TCP
+#[derive(Debug, Clone)]
+pub struct TcpConnector {
+    /// Server host name
+    pub host: String,
+    /// Server port
+    pub port: u16,
+    /// Whether the server expects Direct TLS (XEP-0368) on this port,
+    /// instead of `<starttls/>` after the stream header, unless
+    /// `policy` decides
+    pub direct_tls: bool,
+    /// Resolver to look up the host with
+    pub resolver: Arc<dyn Resolver>,
+    /// Proxy to connect through, if any
+    pub proxy: Option<ProxyConfig>,
+    /// Whether and how to encrypt the connection
+    pub policy: TlsPolicy,
+}
+
+impl ServerConnector for TcpConnector {
+    fn connect<'a>(
+        &'a self,
+        jid: &'a Jid,
+        timeouts: &'a Timeouts,
+        tls: &'a TlsConfig,
+    ) -> BoxFuture<'a, Result<Connection, Error>> {
+        Box::pin(async move {
+            let direct_tls = match self.policy {
+                TlsPolicy::DirectTls => true,
+                TlsPolicy::InsecurePlaintext => false,
+                TlsPolicy::RequireStartTls | TlsPolicy::Opportunistic => self.direct_tls,
+            };
+            let (tcp_stream, endpoint) = connect_to_host_with_endpoint(
+                &self.host,
+                self.port,
+                timeouts.connect,
+                &self.resolver,
+                self.proxy.as_ref(),
+            )
+            .await?;
+            if let Some(connection) = secure_tcp(
+                tcp_stream,
+                jid,
+                direct_tls,
+                self.policy,
+                &self.proxy,
+                timeouts,
+                tls,
+            )
+            .await?
+            {
+                return Ok(connection);
+            }
+
+            // No <starttls/> on offer, which the policy makes do with,
+            // on the very server which didn't offer it
+            let tcp_stream = reconnect(&endpoint, timeouts, &self.resolver, &self.proxy).await?;
+            Ok(Connection::plaintext(tcp_stream))
+        })
+    }
+}
+
+/// Connects to a server listening on a Unix domain socket
+///
+/// The connection is only encrypted with `starttls`, the socket being
+/// local otherwise. It then counts as unencrypted all the same, PLAIN
+/// having to be explicitly allowed.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    /// Path of the socket
+    pub path: PathBuf,
+    /// Whether to negotiate `<starttls/>` after the stream header
+    pub starttls: bool,
+}
+
+#[cfg(unix)]
+impl ServerConnector for UnixConnector {
+    fn connect<'a>(
+        &'a self,
+        jid: &'a Jid,
+        timeouts: &'a Timeouts,
+        tls: &'a TlsConfig,
+    ) -> BoxFuture<'a, Result<Connection, Error>> {
+        Box::pin(async move {
+            let stream = timeout(timeouts.connect, UnixStream::connect(&self.path))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            if !self.starttls {
+                return Ok(Connection::plaintext(stream));
+            }
+            let secure = secure(stream, jid, false, TlsPolicy::RequireStartTls, tls);
+            within(timeouts.tls, ConnectionPhase::Tls, secure)
+                .await?
+                .ok_or_else(|| ProtocolError::NoTls.into())
+        })
+    }
+}
+
+/// Connects to the server of `jid` through the configured transport,
+/// looking up hosts with `resolver`, going through `proxy` if set and
+/// encrypting the connection as `policy` requires. Custom connectors
+/// get neither the resolver, nor the proxy, nor the policy.
+///
+/// Also returns the channel binding data of the TLS connection, when
+/// available.
+///
+/// Each step is given up after its duration in `timeouts`.
+pub async fn connect(
+    server: ServerConfig,
+    jid: &Jid,
+    timeouts: &Timeouts,
+    resolver: Arc<dyn Resolver>,
+    proxy: Option<ProxyConfig>,
+    policy: TlsPolicy,
+    tls: &TlsConfig,
+) -> Result<Connection, Error> {
+    match server {
+        ServerConfig::UseSrv => {
+            let connector = SrvConnector {
+                resolver,
+                proxy,
+                policy,
+            };
+            connector.connect(jid, timeouts, tls).await
+        }
+        ServerConfig::Manual {
+            host,
+            port,
+            direct_tls,
+        } => {
+            let connector = TcpConnector {
+                host,
+                port,
+                direct_tls,
+                resolver,
+                proxy,
+                policy,
+            };
+            connector.connect(jid, timeouts, tls).await
+        }
+        ServerConfig::Connector(connector) => connector.connect(jid, timeouts, tls).await,
+        // TLS is handled by the HTTP layer of these transports, no
+        // channel binding then.
+        #[cfg(feature = "websocket")]
+        ServerConfig::WebSocket { url } => {
+            let stream =
+                websocket::connect(&url, timeouts.connect, &resolver, proxy.as_ref(), tls).await?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                channel_binding: ChannelBinding::None,
+                encrypted: url.to_ascii_lowercase().starts_with("wss:"),
+            })
+        }
+        #[cfg(feature = "bosh")]
+        ServerConfig::Bosh { url } => {
+            let domain = jid.clone().domain();
+            let stream = bosh::connect(
+                &url,
+                &domain,
+                timeouts.connect,
+                &resolver,
+                proxy.as_ref(),
+                tls,
+            )
+            .await?;
+            Ok(Connection {
+                stream: Box::new(stream),
+                channel_binding: ChannelBinding::None,
+                encrypted: url.to_ascii_lowercase().starts_with("https:"),
+            })
+        }
+    }
+}
+
+/// Connects again to `endpoint`, rather than to whichever server the
+/// host name or SRV records lead to, lest an opportunistic connection
+/// end up in plaintext with a server offering TLS
+async fn reconnect(
+    endpoint: &Endpoint,
+    timeouts: &Timeouts,
+    resolver: &Arc<dyn Resolver>,
+    proxy: &Option<ProxyConfig>,
+) -> Result<TcpStream, Error> {
+    // Only looked up again when a proxy resolves it
+    connect_to_host(
+        &endpoint.host,
+        endpoint.port,
+        timeouts.connect,
+        resolver,
+        proxy.as_ref(),
+    )
+    .await
+}
+
+/// Boxes a TLS stream along with its channel binding data
+fn secured<S: AsyncReadAndWrite + 'static>(tls_stream: TlsStream<S>) -> Result<Connection, Error> {
+    let channel_binding = channel_binding(&tls_stream)?;
+    Ok(Connection {
+        stream: Box::new(tls_stream),
+        channel_binding,
+        encrypted: true,
+    })
+}
+
+/// Secures a TCP connection to the server of `jid` as `policy`
+/// requires, giving up after `timeouts.tls`.
+///
+/// Returns `None` if the server doesn't offer `<starttls/>` and the
+/// policy makes do without TLS, the connection having been closed.
+async fn secure_tcp(
+    tcp_stream: TcpStream,
+    jid: &Jid,
+    use_direct_tls: bool,
+    policy: TlsPolicy,
+    proxy: &Option<ProxyConfig>,
+    timeouts: &Timeouts,
+    tls: &TlsConfig,
+) -> Result<Option<Connection>, Error> {
+    if policy == TlsPolicy::InsecurePlaintext {
+        // Whatever goes through a proxy leaves this host
+        let loopback = tcp_stream.peer_addr()?.ip().is_loopback();
+        if !loopback || proxy.is_some() {
+            return Err(TlsError::PlaintextNotLoopback.into());
+        }
+        return Ok(Some(Connection::plaintext(tcp_stream)));
+    }
+    let secure = secure(tcp_stream, jid, use_direct_tls, policy, tls);
+    within(timeouts.tls, ConnectionPhase::Tls, secure).await
+}
+
+/// Secures a connection to the server of `jid`, either with Direct
+/// TLS (XEP-0368) or with `<starttls/>`.
+///
+/// Returns `None` if the server doesn't offer `<starttls/>` and the
+/// policy is opportunistic, the stream having been closed as it can't
+/// go on without a new header.
+async fn secure<S: AsyncReadAndWrite + 'static>(
+    stream: S,
+    jid: &Jid,
+    use_direct_tls: bool,
+    policy: TlsPolicy,
+    tls: &TlsConfig,
+) -> Result<Option<Connection>, Error> {
+    if use_direct_tls {
+        // TLS right away, before any stream header
+        let tls_stream = direct_tls(stream, &jid.clone().domain(), tls).await?;
+        return secured(tls_stream).map(Some);
+    }
+
+    // Unencryped XMPPStream
+    let mut xmpp_stream =
+        XMPPStream::start(stream, jid.clone(), ns::JABBER_CLIENT.to_owned()).await?;
+
+    if xmpp_stream.stream_features.can_starttls() {
+        // TlsStream
+        secured(starttls(xmpp_stream, tls).await?).map(Some)
+    } else if policy == TlsPolicy::Opportunistic {
+        let _ = xmpp_stream.send(Packet::StreamEnd).await;
+        Ok(None)
+    } else {
+        Err(Error::Protocol(ProtocolError::NoTls))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::{Answer, SrvRecord, StaticResolver};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use trust_dns_resolver::error::ResolveError;
+
+    /// Answers A records only once, as if the server had moved since
+    #[derive(Debug)]
+    struct MovingResolver {
+        inner: StaticResolver,
+        ipv4_lookups: AtomicUsize,
+    }
+
+    impl Resolver for MovingResolver {
+        fn srv<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>> {
+            self.inner.srv(name)
+        }
+
+        fn ipv6<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>> {
+            self.inner.ipv6(host)
+        }
+
+        fn ipv4<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>> {
+            if self.ipv4_lookups.fetch_add(1, Ordering::SeqCst) == 0 {
+                return self.inner.ipv4(host);
+            }
+            Box::pin(async {
+                Ok(Answer {
+                    records: Vec::new(),
+                    ttl: Default::default(),
+                })
+            })
+        }
+    }
+
+    /// Reads what the client sends, up to `end`
+    async fn expect(server: &mut TcpStream, end: &str) {
+        let mut data = String::new();
+        while !data.contains(end) {
+            let mut buf = [0; 4096];
+            let len = server.read(&mut buf).await.unwrap();
+            assert!(len > 0, "client closed the stream before sending {}", end);
+            data.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+        }
+    }
+
+    fn connector(port: u16, policy: TlsPolicy) -> TcpConnector {
+        TcpConnector {
+            host: String::from("127.0.0.1"),
+            port,
+            direct_tls: false,
+            resolver: default_resolver(),
+            proxy: None,
+            policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_opportunistic() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut probe, _) = listener.accept().await.unwrap();
+            expect(&mut probe, "<stream:stream").await;
+            let header = "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='capulet.example' version='1.0'><stream:features/>";
+            probe.write_all(header.as_bytes()).await.unwrap();
+            expect(&mut probe, "</stream:stream>").await;
+            // Opened again, left to the client
+            listener.accept().await.unwrap();
+        });
+
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let timeouts = Timeouts::default();
+        let tls = TlsConfig::default();
+        let connection = connector(port, TlsPolicy::Opportunistic)
+            .connect(&jid, &timeouts, &tls)
+            .await
+            .unwrap();
+        assert!(!connection.encrypted);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_opportunistic_same_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut probe, _) = listener.accept().await.unwrap();
+            expect(&mut probe, "<stream:stream").await;
+            let header = "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='capulet.example' version='1.0'><stream:features/>";
+            probe.write_all(header.as_bytes()).await.unwrap();
+            expect(&mut probe, "</stream:stream>").await;
+            // Opened again, left to the client
+            listener.accept().await.unwrap();
+        });
+
+        let record = SrvRecord {
+            priority: 0,
+            weight: 0,
+            port,
+            target: String::from("xmpp.capulet.example."),
+        };
+        let inner = StaticResolver::new()
+            .with_srv("_xmpp-client._tcp.capulet.example", vec![record])
+            .with_ipv4("xmpp.capulet.example", vec!["127.0.0.1".parse().unwrap()]);
+        let resolver = MovingResolver {
+            inner,
+            ipv4_lookups: AtomicUsize::new(0),
+        };
+        let connector = SrvConnector {
+            resolver: Arc::new(resolver),
+            proxy: None,
+            policy: TlsPolicy::Opportunistic,
+        };
+
+        // Reconnected to the address of the first connection, without
+        // looking up the target again
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let connection = connector
+            .connect(&jid, &Timeouts::default(), &TlsConfig::default())
+            .await
+            .unwrap();
+        assert!(!connection.encrypted);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_insecure_plaintext() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let connection = connector(port, TlsPolicy::InsecurePlaintext)
+            .connect(&jid, &Timeouts::default(), &TlsConfig::default())
+            .await
+            .unwrap();
+        assert!(!connection.encrypted);
+        assert!(matches!(connection.channel_binding, ChannelBinding::None));
+    }
+}
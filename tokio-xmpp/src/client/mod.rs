@@ -1,5 +1,14 @@
 mod auth;
 mod bind;
+pub(crate) mod connect;
+pub(crate) mod oauth;
+pub(crate) mod reconnect;
+pub(crate) mod register;
+pub(crate) mod sasl2;
+pub(crate) mod scram;
+mod sm;
+#[cfg(test)]
+mod test_util;
 
 pub mod async_client;
 pub mod simple_client;
@@ -0,0 +1,214 @@
+//! OAuth 2.0 access tokens, and the OAUTHBEARER (RFC 7628) and
+//! X-OAUTH2 mechanisms sending them
+
+use futures::future::BoxFuture;
+use sasl::client::{Mechanism, MechanismError};
+use sasl::common::{Credentials, Identity, Password, Secret};
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Access token issued by an OAuth 2.0 authorization server
+#[derive(Clone, PartialEq, Eq)]
+pub struct OAuthToken {
+    /// The bearer token itself
+    pub token: String,
+    /// When the token stops being valid, if the authorization server
+    /// said so
+    pub expiry: Option<SystemTime>,
+}
+
+impl OAuthToken {
+    /// Token without a known expiry
+    pub fn new<T: Into<String>>(token: T) -> Self {
+        OAuthToken {
+            token: token.into(),
+            expiry: None,
+        }
+    }
+
+    pub(crate) fn expired(&self) -> bool {
+        self.expiry
+            .map_or(false, |expiry| expiry <= SystemTime::now())
+    }
+}
+
+impl fmt::Debug for OAuthToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("OAuthToken")
+            .field("token", &"[redacted]")
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+
+/// Gets a new token to replace the expired or rejected one it is
+/// called with, `None` if it can't, in which case the previous token
+/// is tried again
+pub type OAuthRefresher =
+    Arc<dyn Fn(OAuthToken) -> BoxFuture<'static, Option<OAuthToken>> + Send + Sync>;
+
+/// Credentials to authenticate with instead of a password
+#[derive(Clone)]
+pub struct OAuthCredentials {
+    /// Token to authenticate with, replaced as `refresh` gives new ones
+    pub token: OAuthToken,
+    /// Called before connecting again when the token expired, or when
+    /// the server rejected it
+    pub refresh: Option<OAuthRefresher>,
+}
+
+impl OAuthCredentials {
+    /// Credentials with a token that never gets refreshed
+    pub fn new(token: OAuthToken) -> Self {
+        OAuthCredentials {
+            token,
+            refresh: None,
+        }
+    }
+
+    /// Refreshes the token if it expired or if `rejected`, and there
+    /// is a way to
+    pub(crate) async fn refreshed(mut self, rejected: bool) -> Self {
+        if let Some(ref refresh) = self.refresh {
+            if rejected || self.token.expired() {
+                if let Some(token) = refresh(self.token.clone()).await {
+                    self.token = token;
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Gets the username and token `Credentials` carry, the token taking
+/// the place of the password and the bare JID that of the username
+pub(crate) fn username_and_token(credentials: &Credentials) -> Option<(String, String)> {
+    match (&credentials.identity, &credentials.secret) {
+        (Identity::Username(username), Secret::Password(Password::Plain(token))) => {
+            Some((username.clone(), token.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// OAUTHBEARER, sending the token in a single step (RFC 7628)
+pub(crate) struct OAuthBearer {
+    authzid: String,
+    token: String,
+}
+
+impl OAuthBearer {
+    /// Sends `token`, authorizing as `authzid`
+    pub(crate) fn new(authzid: String, token: String) -> Self {
+        OAuthBearer { authzid, token }
+    }
+}
+
+impl Mechanism for OAuthBearer {
+    fn name(&self) -> &str {
+        "OAUTHBEARER"
+    }
+
+    /// Only required by the trait: sasl has no error for a missing
+    /// token, which `auth` reports as `AuthError::NoToken` before
+    /// calling `new()` instead
+    fn from_credentials(credentials: Credentials) -> Result<Self, MechanismError> {
+        let (authzid, token) = username_and_token(&credentials)
+            .ok_or(MechanismError::PlainRequiresUsernameAndPassword)?;
+        Ok(OAuthBearer::new(authzid, token))
+    }
+
+    fn initial(&mut self) -> Vec<u8> {
+        // GS2 header, without channel binding
+        let authzid = self.authzid.replace('=', "=3D").replace(',', "=2C");
+        format!("n,a={},\x01auth=Bearer {}\x01\x01", authzid, self.token).into_bytes()
+    }
+
+    fn response(&mut self, _challenge: &[u8]) -> Result<Vec<u8>, MechanismError> {
+        // The only challenge is the error telling why the token got
+        // rejected, which has to be acknowledged for the server to
+        // send its failure.
+        Ok(vec![0x01])
+    }
+}
+
+/// X-OAUTH2, sending the token the way PLAIN sends the password
+pub(crate) struct XOAuth2 {
+    username: String,
+    token: String,
+}
+
+impl XOAuth2 {
+    /// Sends `token` for `username`
+    pub(crate) fn new(username: String, token: String) -> Self {
+        XOAuth2 { username, token }
+    }
+}
+
+impl Mechanism for XOAuth2 {
+    fn name(&self) -> &str {
+        "X-OAUTH2"
+    }
+
+    /// See `OAuthBearer::from_credentials()`
+    fn from_credentials(credentials: Credentials) -> Result<Self, MechanismError> {
+        let (username, token) = username_and_token(&credentials)
+            .ok_or(MechanismError::PlainRequiresUsernameAndPassword)?;
+        Ok(XOAuth2::new(username, token))
+    }
+
+    fn initial(&mut self) -> Vec<u8> {
+        format!("\0{}\0{}", self.username, self.token).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::time::Duration;
+
+    #[test]
+    fn test_oauthbearer() {
+        let creds = Credentials::default()
+            .with_username("juliet@capulet.example")
+            .with_password("vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==");
+        let mut mechanism = OAuthBearer::from_credentials(creds).unwrap();
+        assert_eq!(
+            mechanism.initial(),
+            b"n,a=juliet@capulet.example,\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01"
+        );
+        let error = br#"{"status":"invalid_token","scope":"xmpp"}"#;
+        assert_eq!(mechanism.response(error).unwrap(), b"\x01");
+
+        let creds = Credentials::default()
+            .with_username("juliet@capulet.example")
+            .with_password("s3cr3t");
+        let mut mechanism = XOAuth2::from_credentials(creds).unwrap();
+        assert_eq!(mechanism.initial(), b"\0juliet@capulet.example\0s3cr3t");
+    }
+
+    #[test]
+    fn test_refresh() {
+        let expired = OAuthToken {
+            token: String::from("old"),
+            expiry: Some(SystemTime::now() - Duration::from_secs(1)),
+        };
+        let refresh: OAuthRefresher = Arc::new(|_| async { Some(OAuthToken::new("new")) }.boxed());
+        let credentials = OAuthCredentials {
+            token: expired,
+            refresh: Some(refresh),
+        };
+        let refreshed = credentials.refreshed(false).now_or_never().unwrap();
+        assert_eq!(refreshed.token.token, "new");
+
+        // Valid and accepted, kept as it is
+        let kept = refreshed.refreshed(false).now_or_never().unwrap();
+        assert_eq!(kept.token.token, "new");
+        // Nothing to refresh it with
+        let rejected = OAuthCredentials::new(OAuthToken::new("old"));
+        let rejected = rejected.refreshed(true).now_or_never().unwrap();
+        assert_eq!(rejected.token.token, "old");
+    }
+}
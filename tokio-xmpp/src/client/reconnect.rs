@@ -0,0 +1,255 @@
+//! Delays between reconnection attempts, redirections, and timeouts
+//! of each step of a connection
+
+use futures::Future;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use tokio::time::timeout;
+use xmpp_parsers::stream_error::DefinedCondition;
+
+use crate::happy_eyeballs::DEFAULT_CONNECT_TIMEOUT;
+use crate::{ConnectionPhase, Error};
+
+/// How long to wait before each reconnection attempt
+///
+/// The delay grows exponentially from `initial_delay` up to
+/// `max_delay`, and is shortened by a random part of up to `jitter`
+/// times itself so that many clients disconnected at once don't come
+/// back all together.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt
+    pub initial_delay: Duration,
+    /// Factor applied to the delay after each failed attempt
+    pub multiplier: f64,
+    /// Upper bound of the delay
+    pub max_delay: Duration,
+    /// Random part of the delay, between 0 and 1
+    pub jitter: f64,
+    /// Number of consecutive attempts after which to give up, ending
+    /// the stream
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    /// Starts at one second, doubling up to five minutes, with 20%
+    /// jitter and no limit on the number of attempts
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(300),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before this attempt, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_delay.as_secs_f64()).max(0.);
+        let jitter = self.jitter.max(0.).min(1.) * rand::random::<f64>();
+        Duration::from_secs_f64(delay * (1. - jitter))
+    }
+
+    /// Whether this attempt is still allowed
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.map_or(true, |max| attempt <= max)
+    }
+}
+
+/// Whether reconnecting after this error has any chance to work
+///
+/// The server closing the stream because another connection took over
+/// the resource, because the credentials aren't valid anymore, or
+/// because it doesn't serve the domain, will do it again.
+pub fn allows_reconnect(error: &Error) -> bool {
+    match error {
+        Error::StreamError(e) => !matches!(
+            e.defined_condition,
+            DefinedCondition::Conflict
+                | DefinedCondition::NotAuthorized
+                | DefinedCondition::HostGone
+                | DefinedCondition::HostUnknown
+        ),
+        _ => true,
+    }
+}
+
+/// Port to use when a `<see-other-host/>` redirect doesn't specify one
+const DEFAULT_REDIRECT_PORT: u16 = 5222;
+
+/// Parses the `host`, `host:port`, `[IPv6]` or `[IPv6]:port` value of a
+/// `<see-other-host/>` stream error into a host and a port
+pub fn parse_redirect(value: &str) -> Option<(String, u16)> {
+    let value = value.trim();
+    let (host, port) = if value.starts_with('[') {
+        let end = value.find(']')?;
+        let host = &value[1..end];
+        host.parse::<Ipv6Addr>().ok()?;
+        let port = match &value[end + 1..] {
+            "" => None,
+            rest if rest.starts_with(':') => Some(&rest[1..]),
+            _ => return None,
+        };
+        (host, port)
+    } else if value.parse::<Ipv6Addr>().is_ok() {
+        // Not bracketed as it should be, but unambiguous without a port
+        (value, None)
+    } else {
+        match value.split_once(':') {
+            // Any other colon would be an unbracketed IPv6 address
+            Some((_, port)) if port.contains(':') => return None,
+            Some((host, port)) => (host, Some(port)),
+            None => (value, None),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => port.parse::<u16>().ok().filter(|&port| port != 0)?,
+        None => DEFAULT_REDIRECT_PORT,
+    };
+    Some((host.to_owned(), port))
+}
+
+/// Time given to each step of a connection
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// Establishing the TCP connection, trying all addresses of the
+    /// server
+    pub connect: Duration,
+    /// Securing the connection with Direct TLS or STARTTLS
+    pub tls: Duration,
+    /// Authentication, including the stream restart
+    pub auth: Duration,
+    /// Resource binding and Stream Management negotiation
+    pub bind: Duration,
+    /// Each request of an in-band registration with `Registration`
+    pub register: Duration,
+}
+
+impl Default for Timeouts {
+    /// 30 seconds for each step
+    fn default() -> Self {
+        Timeouts {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            tls: DEFAULT_CONNECT_TIMEOUT,
+            auth: DEFAULT_CONNECT_TIMEOUT,
+            bind: DEFAULT_CONNECT_TIMEOUT,
+            register: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+/// Runs a step of the connection, failing with `Error::Timeout` if it
+/// takes longer than `duration`
+pub async fn within<T, F: Future<Output = Result<T, Error>>>(
+    duration: Duration,
+    phase: ConnectionPhase,
+    future: F,
+) -> Result<T, Error> {
+    timeout(duration, future)
+        .await
+        .map_err(|_| Error::Timeout(phase))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = ReconnectPolicy {
+            jitter: 0.,
+            ..ReconnectPolicy::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(5), Duration::from_secs(16));
+        assert_eq!(policy.delay(10), Duration::from_secs(300));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(300));
+
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..ReconnectPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(3);
+            assert!(delay > Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let mut policy = ReconnectPolicy::default();
+        assert!(policy.allows(1000));
+        policy.max_attempts = Some(3);
+        assert!(policy.allows(3));
+        assert!(!policy.allows(4));
+    }
+
+    #[test]
+    fn test_allows_reconnect() {
+        use xmpp_parsers::stream_error::StreamError;
+
+        let conflict = StreamError::new(DefinedCondition::Conflict);
+        assert!(!allows_reconnect(&Error::StreamError(conflict)));
+        let shutdown = StreamError::new(DefinedCondition::SystemShutdown);
+        assert!(allows_reconnect(&Error::StreamError(shutdown)));
+        assert!(allows_reconnect(&Error::Disconnected));
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(
+            parse_redirect("example.org"),
+            Some((String::from("example.org"), 5222))
+        );
+        assert_eq!(
+            parse_redirect(" xmpp.example.org:5223 "),
+            Some((String::from("xmpp.example.org"), 5223))
+        );
+        assert_eq!(
+            parse_redirect("192.0.2.1:443"),
+            Some((String::from("192.0.2.1"), 443))
+        );
+        assert_eq!(
+            parse_redirect("[2001:db8::1]"),
+            Some((String::from("2001:db8::1"), 5222))
+        );
+        assert_eq!(
+            parse_redirect("[2001:db8::1]:5223"),
+            Some((String::from("2001:db8::1"), 5223))
+        );
+        assert_eq!(
+            parse_redirect("2001:db8::1"),
+            Some((String::from("2001:db8::1"), 5222))
+        );
+        assert_eq!(parse_redirect(""), None);
+        assert_eq!(parse_redirect(":5222"), None);
+        assert_eq!(parse_redirect("example.org:0"), None);
+        assert_eq!(parse_redirect("example.org:http"), None);
+        assert_eq!(parse_redirect("[example.org]:5222"), None);
+        assert_eq!(parse_redirect("[2001:db8::1]5222"), None);
+        assert_eq!(parse_redirect("a:b:5222"), None);
+    }
+
+    #[tokio::test]
+    async fn test_within() {
+        let result = within(
+            Duration::from_millis(10),
+            ConnectionPhase::Bind,
+            futures::future::pending::<Result<(), Error>>(),
+        )
+        .await;
+        match result {
+            Err(Error::Timeout(ConnectionPhase::Bind)) => (),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! In-band registration (XEP-0077): creating an account before logging
+//! in, then changing its password or cancelling it
+
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, FieldType};
+use xmpp_parsers::ibr::Query;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use super::async_client::Config;
+use super::connect::connect;
+use super::reconnect::within;
+use crate::xmpp_codec::{Packet, XMPPCodec};
+use crate::xmpp_stream::{self, AsyncReadAndWrite};
+use crate::{ConnectionPhase, Error, IqError, ProtocolError, RegisterError};
+
+type XMPPStream = xmpp_stream::XMPPStream<Box<dyn AsyncReadAndWrite>>;
+
+/// Ids of the requests sent on a registration connection
+struct RequestIds {
+    /// Random part of the ids, so that they can't be guessed
+    prefix: u64,
+    /// Number of requests sent, making the ids unique
+    count: u64,
+}
+
+impl RequestIds {
+    fn new() -> Self {
+        RequestIds {
+            prefix: rand::random(),
+            count: 0,
+        }
+    }
+
+    fn next(&mut self) -> String {
+        self.count += 1;
+        format!("{:016x}-register-{}", self.prefix, self.count)
+    }
+}
+
+/// Query with nothing in it
+fn query() -> Query {
+    Query {
+        fields: HashMap::new(),
+        registered: false,
+        remove: false,
+        form: None,
+    }
+}
+
+/// Query setting the password of the account `username`
+pub(crate) fn password_change(username: String, password: String) -> Query {
+    let mut query = query();
+    query.fields.insert(String::from("username"), username);
+    query.fields.insert(String::from("password"), password);
+    query
+}
+
+/// Query removing the account
+pub(crate) fn removal() -> Query {
+    Query {
+        remove: true,
+        ..query()
+    }
+}
+
+/// Error of a registration request sent on an authenticated session
+pub(crate) fn request_error(error: IqError) -> Error {
+    match error {
+        IqError::Send(e) => e,
+        IqError::Stanza(e) => RegisterError::from(e).into(),
+        IqError::InvalidResponse(e) => ProtocolError::Parsers(e).into(),
+        IqError::Timeout => Error::Timeout(ConnectionPhase::Register),
+        IqError::Disconnected => Error::Disconnected,
+        IqError::Protocol(e) => e.into(),
+    }
+}
+
+/// Connection to a server offering in-band registration, to create an
+/// account before logging in with it
+///
+/// Once registered, the account is used with a new `AsyncClient`, and
+/// this connection dropped.
+pub struct Registration {
+    stream: XMPPStream,
+    form: Query,
+    /// Whether the form may be sent, the connection being encrypted or
+    /// allowed not to be
+    can_submit: bool,
+    timeout: Duration,
+    ids: RequestIds,
+}
+
+impl Registration {
+    /// Connects to the server of `config.jid` as `AsyncClient` would,
+    /// and fetches its registration form
+    pub async fn connect(config: Config) -> Result<Self, Error> {
+        let Config {
+            jid,
+            server,
+            proxy,
+            resolver,
+            timeouts,
+            tls_policy,
+            tls,
+            allow_plain_without_tls,
+            limits,
+            observer,
+            ..
+        } = config;
+        let connection =
+            connect(server, &jid, &timeouts, resolver, proxy, tls_policy, &tls).await?;
+        let can_submit = connection.encrypted || allow_plain_without_tls;
+
+        let codec = XMPPCodec::with_limits(limits).with_observer(observer);
+        let mut stream = xmpp_stream::XMPPStream::start_with_codec(
+            connection.stream,
+            jid,
+            ns::JABBER_CLIENT.to_owned(),
+            codec,
+        )
+        .await?;
+        if !stream.stream_features.register {
+            return Err(RegisterError::Unsupported.into());
+        }
+
+        let mut ids = RequestIds::new();
+        let iq = Iq::from_get(ids.next(), query());
+        let payload = within(
+            timeouts.register,
+            ConnectionPhase::Register,
+            request(&mut stream, iq),
+        )
+        .await?;
+        let form = match payload {
+            Some(payload) => Query::try_from(payload).map_err(ProtocolError::Parsers)?,
+            None => return Err(ProtocolError::InvalidRegisterResponse.into()),
+        };
+        Ok(Registration {
+            stream,
+            form,
+            can_submit,
+            timeout: timeouts.register,
+            ids,
+        })
+    }
+
+    /// Form the server asks to fill, with the legacy fields, a data
+    /// form, or both
+    pub fn form(&self) -> &Query {
+        &self.form
+    }
+
+    /// Answer to the form with `username` and `password`, in the data
+    /// form if there is one, in the legacy fields otherwise
+    ///
+    /// Other fields keep the value the server gave them, those it
+    /// requires can still be filled in the returned query.
+    pub fn fill(&self, username: &str, password: &str) -> Query {
+        fill(&self.form, username, password)
+    }
+
+    /// Submits the filled form, registering the account
+    ///
+    /// The form can be submitted again after a `RegisterError`, such as
+    /// with another username after a `Conflict`.
+    pub async fn submit(&mut self, query: Query) -> Result<(), Error> {
+        if !self.can_submit {
+            return Err(RegisterError::PasswordWithoutTls.into());
+        }
+        let iq = Iq::from_set(self.ids.next(), query);
+        within(
+            self.timeout,
+            ConnectionPhase::Register,
+            request(&mut self.stream, iq),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Answer to `form`, see `Registration::fill()`
+fn fill(form: &Query, username: &str, password: &str) -> Query {
+    let value = |var: &str| match var {
+        "username" => Some(username),
+        "password" => Some(password),
+        _ => None,
+    };
+    let mut answer = query();
+    match form.form {
+        Some(ref form) => {
+            let fields = form
+                .fields
+                .iter()
+                .filter(|field| field.type_ != FieldType::Fixed)
+                .map(|field| {
+                    let mut field = field.clone();
+                    if let Some(value) = value(&field.var) {
+                        field.values = vec![String::from(value)];
+                    }
+                    field.label = None;
+                    field.required = false;
+                    field.options.clear();
+                    field.media.clear();
+                    field
+                })
+                .collect();
+            answer.form = Some(DataForm::new(DataFormType::Submit, ns::REGISTER, fields));
+        }
+        None => {
+            for (name, given) in form.fields.iter() {
+                if name == "instructions" {
+                    continue;
+                }
+                let value = value(name).map_or_else(|| given.clone(), String::from);
+                answer.fields.insert(name.clone(), value);
+            }
+        }
+    }
+    answer
+}
+
+/// Sends `iq`, and waits for the payload of its result
+///
+/// The response has to come from the server, on its behalf or on the
+/// one of its domain.
+async fn request(stream: &mut XMPPStream, iq: Iq) -> Result<Option<Element>, Error> {
+    let id = iq.id.clone();
+    let server = Jid::Bare(BareJid::domain(stream.jid.clone().domain()));
+    stream.send_stanza(iq).await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("error", ns::STREAM) => {
+                return Err(Error::from_stream_error(stanza));
+            }
+            Some(Ok(Packet::Stanza(stanza))) => match Iq::try_from(stanza) {
+                Ok(iq) if iq.id == id && iq.from.as_ref().map_or(true, |from| *from == server) => {
+                    match iq.payload {
+                        IqType::Result(payload) => return Ok(payload),
+                        IqType::Error(error) => return Err(RegisterError::from(error).into()),
+                        // A request of the server, not the response
+                        IqType::Get(_) | IqType::Set(_) => {}
+                    }
+                }
+                _ => {}
+            },
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::async_client::ServerConfig;
+    use crate::client::test_util::{expect, id_of, DuplexConnector, STREAM_HEADER};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use xmpp_parsers::Jid;
+
+    fn config(client_end: DuplexStream) -> Config {
+        let jid = Jid::from_str("capulet.example").unwrap();
+        let mut config = Config::new(jid, "");
+        let connector = DuplexConnector(Mutex::new(Some(client_end)));
+        config.server = ServerConfig::Connector(Arc::new(connector));
+        config.allow_plain_without_tls = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><register xmlns='http://jabber.org/features/iq-register'/></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+
+            let get = expect(&mut server_end, "</iq>").await;
+            assert!(get.contains("jabber:iq:register"));
+            // Ignored, coming from someone else
+            let spoofed = format!("<iq type='result' from='romeo@montague.example' id='{}'><query xmlns='jabber:iq:register'/></iq>", id_of(&get));
+            server_end.write_all(spoofed.as_bytes()).await.unwrap();
+            let form = format!("<iq type='result' id='{}'><query xmlns='jabber:iq:register'><instructions>Choose a username and password.</instructions><username/><password/></query></iq>", id_of(&get));
+            server_end.write_all(form.as_bytes()).await.unwrap();
+
+            let set = expect(&mut server_end, "</iq>").await;
+            assert!(set.contains("<username>juliet</username>"));
+            assert!(!set.contains("instructions"));
+            let conflict = format!("<iq type='error' from='capulet.example' id='{}'><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>", id_of(&set));
+            server_end.write_all(conflict.as_bytes()).await.unwrap();
+
+            let resubmitted = expect(&mut server_end, "</iq>").await;
+            assert!(resubmitted.contains("<username>juliet.capulet</username>"));
+            assert_ne!(id_of(&resubmitted), id_of(&set));
+            let result = format!("<iq type='result' id='{}'/>", id_of(&resubmitted));
+            server_end.write_all(result.as_bytes()).await.unwrap();
+        });
+
+        let mut registration = match Registration::connect(config(client_end)).await {
+            Ok(registration) => registration,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(registration.form().fields.contains_key("username"));
+        let query = registration.fill("juliet", "s3cr3t");
+        match registration.submit(query).await {
+            Err(Error::Register(RegisterError::Conflict)) => (),
+            result => panic!("{:?}", result),
+        }
+        let query = registration.fill("juliet.capulet", "s3cr3t");
+        registration.submit(query).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported() {
+        let (client_end, mut server_end) = duplex(65536);
+        let server = tokio::spawn(async move {
+            expect(&mut server_end, "<stream:stream").await;
+            let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+            server_end
+                .write_all(STREAM_HEADER.as_bytes())
+                .await
+                .unwrap();
+            server_end.write_all(features.as_bytes()).await.unwrap();
+        });
+
+        match Registration::connect(config(client_end)).await {
+            Err(Error::Register(RegisterError::Unsupported)) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!(),
+        }
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_fill_form() {
+        let elem: Element = "<query xmlns='jabber:iq:register'><x xmlns='jabber:x:data' type='form'><field type='hidden' var='FORM_TYPE'><value>jabber:iq:register</value></field><field type='fixed' var='intro'><value>Pick an account</value></field><field type='text-single' label='Username' var='username'><required/></field><field type='text-private' label='Password' var='password'><required/></field><field type='hidden' var='captcha-id'><value>c7e3</value></field></x></query>"
+            .parse()
+            .unwrap();
+        let form = Query::try_from(elem).unwrap();
+        let answer = fill(&form, "juliet", "s3cr3t").form.unwrap();
+        assert_eq!(answer.type_, DataFormType::Submit);
+        assert_eq!(answer.form_type.as_deref(), Some(ns::REGISTER));
+        let values: Vec<_> = answer
+            .fields
+            .iter()
+            .map(|field| (field.var.as_str(), field.values.clone()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("username", vec![String::from("juliet")]),
+                ("password", vec![String::from("s3cr3t")]),
+                ("captcha-id", vec![String::from("c7e3")]),
+            ]
+        );
+    }
+}
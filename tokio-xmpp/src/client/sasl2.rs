@@ -0,0 +1,451 @@
+//! XEP-0388: Extensible SASL Profile, along with inline resource
+//! binding (XEP-0386) and FAST tokens (XEP-0484)
+
+use futures::stream::StreamExt;
+use hmac::{Hmac, Mac};
+use log::warn;
+use sasl::common::{ChannelBinding, Credentials};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::marker::Unpin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use xmpp_parsers::bind2::{BindQuery, Bound};
+use xmpp_parsers::carbons;
+use xmpp_parsers::date::DateTime;
+use xmpp_parsers::fast::{Fast, FastQuery, RequestToken, Token};
+use xmpp_parsers::sasl2::{
+    Authenticate, Authentication, Challenge, Failure, Inline, Response, Success, UserAgent,
+};
+use xmpp_parsers::sm::{Enable, Enabled, Resume, Resumed, StreamId};
+use xmpp_parsers::{ns, Element};
+
+use super::auth::{select_mechanism, AuthOptions, ClientMechanism};
+use super::sm::Negotiated;
+use crate::stream_features::StreamFeatures;
+use crate::xmpp_codec::Packet;
+use crate::xmpp_stream::XMPPStream;
+use crate::{AuthError, Error, ProtocolError};
+
+/// Token mechanism bound to the TLS session through tls-exporter
+const HT_SHA_256_EXPR: &str = "HT-SHA-256-EXPR";
+/// Token mechanism without channel binding
+const HT_SHA_256_NONE: &str = "HT-SHA-256-NONE";
+
+/// Token given by the server to authenticate again without the
+/// password (XEP-0484)
+#[derive(Clone, Debug, PartialEq)]
+pub struct FastToken {
+    /// Mechanism the token has to be used with
+    pub mechanism: String,
+    /// The secret token
+    pub token: String,
+    /// When the server stops accepting the token
+    pub expiry: DateTime,
+    /// Number of times the token has been used, which the server
+    /// checks to detect replays
+    pub count: u32,
+}
+
+impl FastToken {
+    fn expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or(0);
+        self.expiry.0.timestamp() <= now
+    }
+}
+
+/// Called with the new state of the FAST token, `None` once it got
+/// rejected, so that the application can store it across runs
+pub type FastTokenHook = Arc<dyn Fn(Option<&FastToken>) + Send + Sync>;
+
+/// How to present the client when the server supports SASL2
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Stable identifier of this installation, usually a UUID, without
+    /// which no FAST token is requested
+    pub client_id: Option<String>,
+    /// Name of the client software, also used as the tag of the bound
+    /// resource
+    pub software: Option<String>,
+    /// Name of the device the client runs on
+    pub device: Option<String>,
+    /// Whether to enable Message Carbons (XEP-0280) along with the
+    /// resource binding
+    pub carbons: bool,
+    /// Token from a previous login, used instead of the password when
+    /// the server still offers its mechanism
+    pub fast_token: Option<FastToken>,
+    /// Called whenever the token changes, including its use count
+    pub on_fast_token: Option<FastTokenHook>,
+}
+
+impl Config {
+    fn set_fast_token(&mut self, token: Option<FastToken>) {
+        if let Some(ref hook) = self.on_fast_token {
+            hook(token.as_ref());
+        }
+        self.fast_token = token;
+    }
+}
+
+/// HT-SHA-256-* mechanisms from XEP-0484
+struct HtSha256 {
+    token: Vec<u8>,
+    cb_data: Vec<u8>,
+}
+
+impl HtSha256 {
+    fn hash(&self, label: &[u8]) -> Vec<u8> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.token).expect("HMAC takes keys of any size");
+        mac.update(label);
+        mac.update(&self.cb_data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn initial(&self, username: &str) -> Vec<u8> {
+        let mut data = username.as_bytes().to_vec();
+        data.push(0);
+        data.extend(self.hash(b"Initiator"));
+        data
+    }
+
+    fn verify(&self, data: &[u8]) -> bool {
+        self.hash(b"Responder") == data
+    }
+}
+
+/// Mechanism of an authentication attempt
+enum Attempt {
+    Password(Box<dyn ClientMechanism>),
+    Token(HtSha256),
+}
+
+/// Token mechanism to request, depending on what both sides support
+fn token_mechanism(fast: &Fast, creds: &Credentials) -> Option<&'static str> {
+    let exporter = matches!(creds.channel_binding, ChannelBinding::TlsExporter(_));
+    if exporter && fast.supports(HT_SHA_256_EXPR) {
+        Some(HT_SHA_256_EXPR)
+    } else if fast.supports(HT_SHA_256_NONE) {
+        Some(HT_SHA_256_NONE)
+    } else {
+        None
+    }
+}
+
+/// Channel binding data to hash the token with, if the mechanism can
+/// be used on this connection
+fn token_cb_data(mechanism: &str, creds: &Credentials) -> Option<Vec<u8>> {
+    match (mechanism, &creds.channel_binding) {
+        (HT_SHA_256_EXPR, ChannelBinding::TlsExporter(data)) => Some(data.clone()),
+        (HT_SHA_256_NONE, _) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Requests to negotiate along with the authentication
+fn inline_requests(
+    inline: &Inline,
+    config: &Config,
+    resume: Option<(StreamId, u32)>,
+    token_mechanism: Option<&str>,
+) -> Vec<Element> {
+    let mut requests = Vec::new();
+    if let (Some(_), Some((previd, h))) = (&inline.sm, resume) {
+        requests.push(Resume { h, previd }.into());
+    }
+    if let Some(ref bind) = inline.bind {
+        let mut query = BindQuery::new(config.software.clone());
+        if let Some(ref features) = bind.inline {
+            if config.carbons && features.supports(ns::CARBONS) {
+                query = query.with_payload(carbons::Enable);
+            }
+            if features.supports(ns::SM) {
+                query = query.with_payload(Enable::new().with_resume());
+            }
+        }
+        requests.push(query.into());
+    }
+    if let (Some(_), Some(mechanism)) = (&config.client_id, token_mechanism) {
+        requests.push(RequestToken::new(mechanism).into());
+    }
+    requests
+}
+
+/// Names of the tasks a `<continue/>` asks for
+fn continue_tasks(element: &Element) -> Vec<String> {
+    element
+        .get_child("tasks", ns::SASL2)
+        .map(|tasks| {
+            tasks
+                .children()
+                .filter(|task| task.is("task", ns::SASL2))
+                .map(Element::text)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Sends `<authenticate/>` and runs the mechanism until the server
+/// answers with `<success/>` or `<failure/>`
+async fn attempt<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    authenticate: Authenticate,
+    mechanism: &mut Attempt,
+) -> Result<Result<Success, Failure>, Error> {
+    stream.send_stanza(authenticate).await?;
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => {
+                if stanza.is("challenge", ns::SASL2) {
+                    let challenge = Challenge::try_from(stanza).map_err(ProtocolError::Parsers)?;
+                    let response = match mechanism {
+                        Attempt::Password(mechanism) => mechanism.response(&challenge.data)?,
+                        // Tokens are checked in a single step.
+                        Attempt::Token(_) => return Err(AuthError::TokenProof.into()),
+                    };
+                    stream.send_stanza(Response { data: response }).await?;
+                } else if stanza.is("success", ns::SASL2) {
+                    let success = Success::try_from(stanza).map_err(ProtocolError::Parsers)?;
+                    let data = success.additional_data.as_deref().unwrap_or(&[]);
+                    match mechanism {
+                        Attempt::Password(mechanism) => mechanism.success(data)?,
+                        Attempt::Token(ht) => {
+                            if !ht.verify(data) {
+                                return Err(AuthError::TokenProof.into());
+                            }
+                        }
+                    }
+                    return Ok(Ok(success));
+                } else if stanza.is("failure", ns::SASL2) {
+                    return Ok(Err(
+                        Failure::try_from(stanza).map_err(ProtocolError::Parsers)?
+                    ));
+                } else if stanza.is("continue", ns::SASL2) {
+                    // None of the tasks are supported, rather than
+                    // waiting for the timeout
+                    return Err(
+                        ProtocolError::UnsupportedSasl2Tasks(continue_tasks(&stanza)).into(),
+                    );
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+/// Authenticates through SASL2, with a FAST token from `config` if
+/// possible, then with the mechanism `select_mechanism()` picks,
+/// keeping the SCRAM keys which got accepted in `options`.
+///
+/// Resumes the previous session, binds a resource and enables Stream
+/// Management inline when the server offers it. Returns the outcome
+/// of Stream Management if the session is ready, or `None` if a
+/// resource still has to be bound, in which case the new stream
+/// features have been read.
+pub async fn auth<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    authentication: Authentication,
+    username: &str,
+    creds: Credentials,
+    config: &mut Config,
+    resume: Option<(StreamId, u32)>,
+    options: &mut AuthOptions,
+) -> Result<Option<Negotiated>, Error> {
+    let inline = authentication.inline.clone().unwrap_or(Inline {
+        bind: None,
+        fast: None,
+        sm: None,
+        payloads: Vec::new(),
+    });
+    let requested_token = inline
+        .fast
+        .as_ref()
+        .and_then(|fast| token_mechanism(fast, &creds));
+    let user_agent = UserAgent {
+        id: config.client_id.clone(),
+        software: config.software.clone(),
+        device: config.device.clone(),
+    };
+    let requests = inline_requests(&inline, config, resume, requested_token);
+    let authenticate = |mechanism: &str, initial_response| {
+        let mut authenticate =
+            Authenticate::new(mechanism, initial_response).with_user_agent(user_agent.clone());
+        authenticate.payloads = requests.clone();
+        authenticate
+    };
+
+    let mut success = None;
+    let usable_token = match config.fast_token {
+        Some(ref token) if !token.expired() && authentication.supports(&token.mechanism) => {
+            token_cb_data(&token.mechanism, &creds).map(|cb_data| (token.clone(), cb_data))
+        }
+        _ => None,
+    };
+    if let Some((mut token, cb_data)) = usable_token {
+        token.count += 1;
+        let mechanism = token.mechanism.clone();
+        config.set_fast_token(Some(token.clone()));
+        let ht = HtSha256 {
+            token: token.token.into_bytes(),
+            cb_data,
+        };
+        let authenticate = authenticate(&mechanism, Some(ht.initial(username)))
+            .with_payload(FastQuery::new(token.count));
+        match attempt(stream, authenticate, &mut Attempt::Token(ht)).await? {
+            Ok(result) => success = Some(result),
+            Err(failure) => {
+                warn!(
+                    "FAST token rejected ({:?}), authenticating with the password",
+                    failure.defined_condition
+                );
+                config.set_fast_token(None);
+            }
+        }
+    }
+
+    let success = match success {
+        Some(success) => success,
+        None => {
+            let remote_mechs: HashSet<String> = authentication
+                .mechanisms
+                .iter()
+                .map(|mechanism| mechanism.name.clone())
+                .collect();
+            let (name, mut mechanism) = select_mechanism(&creds, &remote_mechs, options)?;
+            let initial = mechanism.initial();
+            let mut mechanism = Attempt::Password(mechanism);
+            let success =
+                match attempt(stream, authenticate(name, Some(initial)), &mut mechanism).await? {
+                    Ok(success) => success,
+                    Err(failure) => return Err(AuthError::Fail(failure.defined_condition).into()),
+                };
+            if let Attempt::Password(ref mechanism) = mechanism {
+                if let Some(keys) = mechanism.scram_keys() {
+                    options.scram_keys = Some(keys.clone());
+                }
+            }
+            success
+        }
+    };
+
+    stream.jid = success.authorization_identifier;
+    let mut negotiated = None;
+    for payload in success.payloads {
+        if payload.is("resumed", ns::SM) {
+            let resumed = Resumed::try_from(payload).map_err(ProtocolError::Parsers)?;
+            negotiated = Some(Negotiated::Resumed(resumed));
+        } else if payload.is("failed", ns::SM) {
+            warn!("Session resumption failed, binding a new one");
+        } else if payload.is("bound", ns::BIND2) {
+            let bound = Bound::try_from(payload).map_err(ProtocolError::Parsers)?;
+            let enabled = match bound.payloads.into_iter().find(|p| p.is("enabled", ns::SM)) {
+                Some(enabled) => Some(Enabled::try_from(enabled).map_err(ProtocolError::Parsers)?),
+                None => None,
+            };
+            if negotiated.is_none() {
+                negotiated = Some(enabled.map_or(Negotiated::Unsupported, Negotiated::Enabled));
+            }
+        } else if payload.is("token", ns::FAST) {
+            let token = Token::try_from(payload).map_err(ProtocolError::Parsers)?;
+            // A token can also be renewed while using the previous one.
+            let mechanism = match (requested_token, &config.fast_token) {
+                (Some(mechanism), _) => mechanism.to_owned(),
+                (None, Some(previous)) => previous.mechanism.clone(),
+                (None, None) => continue,
+            };
+            config.set_fast_token(Some(FastToken {
+                mechanism,
+                token: token.token,
+                expiry: token.expiry,
+                count: 0,
+            }));
+        }
+    }
+
+    if negotiated.is_none() {
+        // The stream isn't restarted, the server sends its features
+        // again for the client to bind a resource.
+        loop {
+            match stream.next().await {
+                Some(Ok(Packet::Stanza(stanza))) if stanza.is("features", ns::STREAM) => {
+                    stream.stream_features =
+                        StreamFeatures::try_from(stanza).map_err(ProtocolError::Parsers)?;
+                    break;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Err(Error::Disconnected),
+            }
+        }
+    }
+    Ok(negotiated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ht_sha_256() {
+        let ht = HtSha256 {
+            token: b"s3cr3t".to_vec(),
+            cb_data: Vec::new(),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cr3t").unwrap();
+        mac.update(b"Initiator");
+        let mut expected = b"juliet\0".to_vec();
+        expected.extend(mac.finalize().into_bytes());
+        assert_eq!(ht.initial("juliet"), expected);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cr3t").unwrap();
+        mac.update(b"Responder");
+        assert!(ht.verify(&mac.finalize().into_bytes()));
+        assert!(!ht.verify(&ht.hash(b"Initiator")));
+
+        let bound = HtSha256 {
+            token: b"s3cr3t".to_vec(),
+            cb_data: vec![1, 2, 3],
+        };
+        assert_ne!(bound.initial("juliet"), ht.initial("juliet"));
+    }
+
+    #[test]
+    fn test_token_mechanism() {
+        let fast = Fast {
+            mechanisms: vec![
+                xmpp_parsers::fast::Mechanism {
+                    name: String::from(HT_SHA_256_EXPR),
+                },
+                xmpp_parsers::fast::Mechanism {
+                    name: String::from(HT_SHA_256_NONE),
+                },
+            ],
+        };
+        let creds = Credentials::default();
+        assert_eq!(token_mechanism(&fast, &creds), Some(HT_SHA_256_NONE));
+        let creds = creds.with_channel_binding(ChannelBinding::TlsExporter(vec![0; 32]));
+        assert_eq!(token_mechanism(&fast, &creds), Some(HT_SHA_256_EXPR));
+        assert_eq!(token_cb_data(HT_SHA_256_EXPR, &creds), Some(vec![0; 32]));
+        assert_eq!(
+            token_cb_data(HT_SHA_256_EXPR, &Credentials::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_continue_tasks() {
+        let elem: Element = "<continue xmlns='urn:xmpp:sasl:2'><additional-data>SSdtIGJvcmVkIG5vdy4=</additional-data><tasks><task>HOTP-EXAMPLE</task><task>TOTP-EXAMPLE</task></tasks><text>This account requires 2FA</text></continue>"
+            .parse()
+            .unwrap();
+        assert_eq!(continue_tasks(&elem), ["HOTP-EXAMPLE", "TOTP-EXAMPLE"]);
+    }
+}
@@ -0,0 +1,405 @@
+//! SCRAM (RFC 5802), from the password or from keys derived from it
+//! beforehand
+
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2;
+use sasl::common::ChannelBinding;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+
+use super::auth::ClientMechanism;
+use crate::{AuthError, Error, ProtocolError};
+
+/// Hash function of a SCRAM mechanism
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScramHash {
+    /// SCRAM-SHA-1 and SCRAM-SHA-1-PLUS
+    Sha1,
+    /// SCRAM-SHA-256 and SCRAM-SHA-256-PLUS
+    Sha256,
+}
+
+impl ScramHash {
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac =
+                    Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha256 => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn salt_password(self, password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut salted = vec![0; 20];
+                pbkdf2::<Hmac<Sha1>>(password.as_bytes(), salt, iterations, &mut salted);
+                salted
+            }
+            ScramHash::Sha256 => {
+                let mut salted = vec![0; 32];
+                pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut salted);
+                salted
+            }
+        }
+    }
+}
+
+/// Keys derived from the password for SCRAM, which authenticate
+/// without it as long as the server keeps the same salt and iteration
+/// count
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScramKeys {
+    /// Hash function the keys are for
+    pub hash: ScramHash,
+    /// Salt given by the server
+    pub salt: Vec<u8>,
+    /// PBKDF2 iteration count given by the server
+    pub iterations: u32,
+    /// HMAC of the salted password with "Client Key"
+    pub client_key: Vec<u8>,
+    /// HMAC of the salted password with "Server Key"
+    pub server_key: Vec<u8>,
+}
+
+impl ScramKeys {
+    /// Derives the keys from the password
+    pub fn derive(hash: ScramHash, password: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let salted_password = hash.salt_password(password, &salt, iterations);
+        Self::from_salted_password(hash, &salted_password, salt, iterations)
+    }
+
+    /// Derives the keys from the password once salted with PBKDF2, as
+    /// servers store it
+    pub fn from_salted_password(
+        hash: ScramHash,
+        salted_password: &[u8],
+        salt: Vec<u8>,
+        iterations: u32,
+    ) -> Self {
+        ScramKeys {
+            hash,
+            salt,
+            iterations,
+            client_key: hash.hmac(salted_password, b"Client Key"),
+            server_key: hash.hmac(salted_password, b"Server Key"),
+        }
+    }
+}
+
+impl fmt::Debug for ScramKeys {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ScramKeys")
+            .field("hash", &self.hash)
+            .field("iterations", &self.iterations)
+            .finish()
+    }
+}
+
+/// Called with the SCRAM keys derived from the password after they
+/// got accepted, so that the application can store them instead
+pub type ScramKeysHook = Arc<dyn Fn(&ScramKeys) + Send + Sync>;
+
+/// Where the mechanism is in the exchange
+enum State {
+    Initial,
+    ClientFirst {
+        client_first_bare: String,
+    },
+    ClientFinal {
+        server_signature: Vec<u8>,
+        keys: ScramKeys,
+    },
+    Verified {
+        keys: ScramKeys,
+    },
+}
+
+/// SCRAM-* and SCRAM-*-PLUS mechanisms
+pub(crate) struct Scram {
+    hash: ScramHash,
+    username: String,
+    password: Option<String>,
+    keys: Option<ScramKeys>,
+    channel_binding: ChannelBinding,
+    nonce: String,
+    state: State,
+}
+
+impl Scram {
+    /// Authenticates as `username` with the keys if they still match
+    /// the server's salt and iteration count, with the password
+    /// otherwise
+    pub(crate) fn new(
+        hash: ScramHash,
+        username: String,
+        password: Option<String>,
+        keys: Option<ScramKeys>,
+        channel_binding: ChannelBinding,
+    ) -> Self {
+        Scram {
+            hash,
+            username,
+            password,
+            keys: keys.filter(|keys| keys.hash == hash),
+            channel_binding,
+            nonce: base64::encode(rand::random::<[u8; 24]>()),
+            state: State::Initial,
+        }
+    }
+
+    fn gs2_header(&self) -> &'static str {
+        match self.channel_binding {
+            ChannelBinding::None => "n,,",
+            ChannelBinding::Unsupported => "y,,",
+            ChannelBinding::TlsUnique(_) => "p=tls-unique,,",
+            ChannelBinding::TlsExporter(_) => "p=tls-exporter,,",
+        }
+    }
+
+    fn cb_data(&self) -> &[u8] {
+        match self.channel_binding {
+            ChannelBinding::TlsUnique(ref data) | ChannelBinding::TlsExporter(ref data) => data,
+            ChannelBinding::None | ChannelBinding::Unsupported => &[],
+        }
+    }
+
+    /// Keys for this salt and iteration count
+    fn keys(&self, salt: Vec<u8>, iterations: u32) -> Result<ScramKeys, Error> {
+        match (&self.keys, &self.password) {
+            (Some(keys), _) if keys.salt == salt && keys.iterations == iterations => {
+                Ok(keys.clone())
+            }
+            (_, Some(password)) => Ok(ScramKeys::derive(self.hash, password, salt, iterations)),
+            (_, None) => Err(AuthError::ScramKeysMismatch.into()),
+        }
+    }
+
+    /// Answers the server-first-message with the client proof
+    fn client_final(
+        &mut self,
+        client_first_bare: &str,
+        server_first: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let server_first =
+            std::str::from_utf8(server_first).map_err(|_| ProtocolError::InvalidScram)?;
+        let attributes = parse_attributes(server_first)?;
+        if attributes.iter().any(|&(key, _)| key == 'm') {
+            // No extension is defined, mandatory ones can't be honoured.
+            return Err(ProtocolError::InvalidScram.into());
+        }
+        let nonce = find(&attributes, 'r')?;
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err(ProtocolError::InvalidScram.into());
+        }
+        let salt =
+            base64::decode(find(&attributes, 's')?).map_err(|_| ProtocolError::InvalidScram)?;
+        let iterations = find(&attributes, 'i')?
+            .parse()
+            .ok()
+            .filter(|&iterations| iterations > 0)
+            .ok_or(ProtocolError::InvalidScram)?;
+        let keys = self.keys(salt, iterations)?;
+
+        let mut cbind_input = self.gs2_header().as_bytes().to_vec();
+        cbind_input.extend_from_slice(self.cb_data());
+        let without_proof = format!("c={},r={}", base64::encode(cbind_input), nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+
+        let stored_key = self.hash.hash(&keys.client_key);
+        let client_signature = self.hash.hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = keys
+            .client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+        let server_signature = self.hash.hmac(&keys.server_key, auth_message.as_bytes());
+        self.state = State::ClientFinal {
+            server_signature,
+            keys,
+        };
+        Ok(format!("{},p={}", without_proof, base64::encode(proof)).into_bytes())
+    }
+
+    /// Checks the server signature in the server-final-message
+    fn verify(&mut self, server_final: &[u8]) -> Result<(), Error> {
+        let (server_signature, keys) = match std::mem::replace(&mut self.state, State::Initial) {
+            State::ClientFinal {
+                server_signature,
+                keys,
+            } => (server_signature, keys),
+            _ => return Err(ProtocolError::InvalidScram.into()),
+        };
+        let server_final =
+            std::str::from_utf8(server_final).map_err(|_| ProtocolError::InvalidScram)?;
+        let attributes = parse_attributes(server_final)?;
+        let verifier = find(&attributes, 'v').map_err(|_| AuthError::ServerProof)?;
+        if base64::decode(verifier).ok() != Some(server_signature) {
+            return Err(AuthError::ServerProof.into());
+        }
+        self.state = State::Verified { keys };
+        Ok(())
+    }
+}
+
+impl ClientMechanism for Scram {
+    fn initial(&mut self) -> Vec<u8> {
+        let username = self.username.replace('=', "=3D").replace(',', "=2C");
+        let client_first_bare = format!("n={},r={}", username, self.nonce);
+        let initial = format!("{}{}", self.gs2_header(), client_first_bare);
+        self.state = State::ClientFirst { client_first_bare };
+        initial.into_bytes()
+    }
+
+    fn response(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        match std::mem::replace(&mut self.state, State::Initial) {
+            State::ClientFirst { client_first_bare } => {
+                self.client_final(&client_first_bare, challenge)
+            }
+            // Some servers send the server-final-message as a challenge,
+            // and succeed with no data once it got acknowledged.
+            state @ State::ClientFinal { .. } => {
+                self.state = state;
+                self.verify(challenge)?;
+                Ok(Vec::new())
+            }
+            State::Initial | State::Verified { .. } => Err(ProtocolError::InvalidScram.into()),
+        }
+    }
+
+    fn success(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.state {
+            State::Verified { .. } if data.is_empty() => Ok(()),
+            _ => self.verify(data),
+        }
+    }
+
+    fn scram_keys(&self) -> Option<&ScramKeys> {
+        match self.state {
+            State::Verified { ref keys } => Some(keys),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a SCRAM message into its attributes
+fn parse_attributes(message: &str) -> Result<Vec<(char, &str)>, Error> {
+    message
+        .split(',')
+        .map(|attribute| {
+            let mut chars = attribute.chars();
+            match (chars.next(), chars.next()) {
+                (Some(key), Some('=')) => Ok((key, &attribute[2..])),
+                _ => Err(ProtocolError::InvalidScram.into()),
+            }
+        })
+        .collect()
+}
+
+fn find<'a>(attributes: &[(char, &'a str)], key: char) -> Result<&'a str, Error> {
+    attributes
+        .iter()
+        .find(|&&(k, _)| k == key)
+        .map(|&(_, value)| value)
+        .ok_or_else(|| ProtocolError::InvalidScram.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the exchange of RFC 7677, section 3, with `scram`
+    fn rfc7677(mut scram: Scram) -> Result<Vec<u8>, Error> {
+        scram.nonce = String::from("rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.initial(), b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram.response(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")?;
+        assert_eq!(
+            client_final,
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        scram.success(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")?;
+        Ok(scram.scram_keys().unwrap().client_key.clone())
+    }
+
+    #[test]
+    fn test_scram_sha_256() {
+        let password = Scram::new(
+            ScramHash::Sha256,
+            String::from("user"),
+            Some(String::from("pencil")),
+            None,
+            ChannelBinding::None,
+        );
+        let client_key = rfc7677(password).unwrap();
+
+        // Same exchange from the keys alone
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let keys = ScramKeys::derive(ScramHash::Sha256, "pencil", salt, 4096);
+        assert_eq!(keys.client_key, client_key);
+        let stored = Scram::new(
+            ScramHash::Sha256,
+            String::from("user"),
+            None,
+            Some(keys.clone()),
+            ChannelBinding::None,
+        );
+        rfc7677(stored).unwrap();
+
+        // Keys for another iteration count, and no password to derive
+        // new ones from
+        let outdated = ScramKeys {
+            iterations: 8192,
+            ..keys
+        };
+        let outdated = Scram::new(
+            ScramHash::Sha256,
+            String::from("user"),
+            None,
+            Some(outdated),
+            ChannelBinding::None,
+        );
+        match rfc7677(outdated) {
+            Err(Error::Auth(AuthError::ScramKeysMismatch)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_server_proof() {
+        let mut scram = Scram::new(
+            ScramHash::Sha1,
+            String::from("user"),
+            Some(String::from("pencil")),
+            None,
+            ChannelBinding::None,
+        );
+        scram.nonce = String::from("fyko+d2lbbFgONRv9qkxdawL");
+        scram.initial();
+        scram
+            .response(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096")
+            .unwrap();
+        match scram.success(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAA=") {
+            Err(Error::Auth(AuthError::ServerProof)) => (),
+            _ => panic!(),
+        }
+        assert!(scram.scram_keys().is_none());
+    }
+}
@@ -1,24 +1,21 @@
 use futures::{sink::SinkExt, Sink, Stream};
-use idna;
-use sasl::common::{ChannelBinding, Credentials};
+use sasl::common::Credentials;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::task::{Context, Poll};
-use tokio::net::TcpStream;
-#[cfg(feature = "tls-native")]
-use tokio_native_tls::TlsStream;
-#[cfg(feature = "tls-rust")]
-use tokio_rustls::client::TlsStream;
 use tokio_stream::StreamExt;
 use xmpp_parsers::{ns, Element, Jid};
 
-use super::auth::auth;
+use super::auth::{auth, AuthOptions};
 use super::bind::bind;
-use crate::happy_eyeballs::connect_with_srv;
-use crate::starttls::starttls;
+use super::connect::{ServerConnector, SrvConnector};
+use super::reconnect::Timeouts;
+use crate::proxy::ProxyConfig;
+use crate::resolver::default_resolver;
+use crate::tls::{TlsConfig, TlsPolicy};
 use crate::xmpp_codec::Packet;
-use crate::xmpp_stream;
-use crate::{Error, ProtocolError};
+use crate::xmpp_stream::{self, AsyncReadAndWrite};
+use crate::Error;
 
 /// A simple XMPP client connection
 ///
@@ -28,10 +25,12 @@ pub struct Client {
     stream: XMPPStream,
 }
 
-type XMPPStream = xmpp_stream::XMPPStream<TlsStream<TcpStream>>;
+type XMPPStream = xmpp_stream::XMPPStream<Box<dyn AsyncReadAndWrite>>;
 
 impl Client {
     /// Start a new XMPP client and wait for a usable session
+    ///
+    /// A domain-only JID logs in anonymously, ignoring the password.
     pub async fn new<P: Into<String>>(jid: &str, password: P) -> Result<Self, Error> {
         let jid = Jid::from_str(jid)?;
         let client = Self::new_with_jid(jid, password.into()).await?;
@@ -40,7 +39,29 @@ impl Client {
 
     /// Start a new client given that the JID is already parsed.
     pub async fn new_with_jid(jid: Jid, password: String) -> Result<Self, Error> {
-        let stream = Self::connect(jid.clone(), password.clone()).await?;
+        let stream = Self::connect(jid, password, None, TlsPolicy::default()).await?;
+        Ok(Client { stream })
+    }
+
+    /// Start a new client connecting to the server through `proxy`
+    pub async fn new_with_proxy(
+        jid: Jid,
+        password: String,
+        proxy: ProxyConfig,
+    ) -> Result<Self, Error> {
+        let stream = Self::connect(jid, password, Some(proxy), TlsPolicy::default()).await?;
+        Ok(Client { stream })
+    }
+
+    /// Start a new client encrypting the connection as `policy` says
+    ///
+    /// PLAIN is only used over encrypted connections.
+    pub async fn new_with_policy(
+        jid: Jid,
+        password: String,
+        policy: TlsPolicy,
+    ) -> Result<Self, Error> {
+        let stream = Self::connect(jid, password, None, policy).await?;
         Ok(Client { stream })
     }
 
@@ -49,35 +70,48 @@ impl Client {
         self.stream
     }
 
-    async fn connect(jid: Jid, password: String) -> Result<XMPPStream, Error> {
-        let username = jid.clone().node().unwrap();
+    async fn connect(
+        jid: Jid,
+        password: String,
+        proxy: Option<ProxyConfig>,
+        policy: TlsPolicy,
+    ) -> Result<XMPPStream, Error> {
+        let username = jid.clone().node();
         let password = password;
-        let domain = idna::domain_to_ascii(&jid.clone().domain()).map_err(|_| Error::Idna)?;
-
-        // TCP connection
-        let tcp_stream = connect_with_srv(&domain, "_xmpp-client._tcp", 5222).await?;
 
-        // Unencryped XMPPStream
-        let xmpp_stream =
-            xmpp_stream::XMPPStream::start(tcp_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
-                .await?;
-
-        let xmpp_stream = if xmpp_stream.stream_features.can_starttls() {
-            // TlsStream
-            let tls_stream = starttls(xmpp_stream).await?;
-            // Encrypted XMPPStream
-            xmpp_stream::XMPPStream::start(tls_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
-                .await?
-        } else {
-            return Err(Error::Protocol(ProtocolError::NoTls));
+        // Stream over Direct TLS or STARTTLS, unless the policy says
+        // otherwise
+        let connector = SrvConnector {
+            resolver: default_resolver(),
+            proxy,
+            policy,
         };
-
-        let creds = Credentials::default()
-            .with_username(username)
-            .with_password(password)
-            .with_channel_binding(ChannelBinding::None);
+        let connection = connector
+            .connect(&jid, &Timeouts::default(), &TlsConfig::default())
+            .await?;
+        // XMPPStream, encrypted unless the policy says otherwise
+        let xmpp_stream = xmpp_stream::XMPPStream::start(
+            connection.stream,
+            jid.clone(),
+            ns::JABBER_CLIENT.to_owned(),
+        )
+        .await?;
+
+        // Without a username, logging in anonymously
+        let creds = match username {
+            Some(ref username) => Credentials::default()
+                .with_username(username.clone())
+                .with_password(password),
+            None => Credentials::default(),
+        }
+        .with_channel_binding(connection.channel_binding);
         // Authenticated (unspecified) stream
-        let stream = auth(xmpp_stream, creds).await?;
+        let mut options = AuthOptions {
+            allow_plain: connection.encrypted,
+            anonymous: username.is_none(),
+            ..AuthOptions::default()
+        };
+        let stream = auth(xmpp_stream, creds, &mut options).await?;
         // Authenticated XMPPStream
         let xmpp_stream =
             xmpp_stream::XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned()).await?;
@@ -127,6 +161,11 @@ impl Stream for Client {
         loop {
             match Pin::new(&mut self.stream).poll_next(cx) {
                 Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) if stanza.is("error", ns::STREAM) => {
+                    // The server is closing the stream
+                    let error = Error::from_stream_error(stanza);
+                    return Poll::Ready(Some(Err(error)));
+                }
                 Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
                     return Poll::Ready(Some(Ok(stanza)))
                 }
@@ -0,0 +1,316 @@
+//! XEP-0198: Stream Management
+
+use futures::stream::StreamExt;
+use futures::Future;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::task::Context;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, Sleep};
+use xmpp_parsers::sm::{Enable, Enabled, Failed, Resume, ResumeAttr, Resumed, StreamId, A};
+use xmpp_parsers::{ns, Element, Jid};
+
+use crate::xmpp_codec::Packet;
+use crate::xmpp_stream::XMPPStream;
+use crate::{Error, ProtocolError};
+
+/// Stanzas sent without asking for their acknowledgement, past which
+/// `<r/>` gets sent
+const ACK_REQUEST_COUNT: u32 = 5;
+
+/// Time after which the acknowledgement of sent stanzas gets asked for,
+/// even if there are fewer than `ACK_REQUEST_COUNT`
+const ACK_REQUEST_DELAY: Duration = Duration::from_secs(2);
+
+/// Outcome of the Stream Management negotiation on a new connection
+pub enum Negotiated {
+    /// A previous session was resumed
+    Resumed(Resumed),
+    /// Stream Management was enabled on a fresh session
+    Enabled(Enabled),
+    /// The server doesn't support Stream Management
+    Unsupported,
+}
+
+/// Stream Management state of a client session, kept across
+/// connections so that the session can be resumed.
+pub struct StreamManagement {
+    /// JID bound to the session
+    pub jid: Jid,
+    /// Resumption id given by the server, if it allows resumption
+    id: Option<StreamId>,
+    /// Number of stanzas handled from the server
+    inbound: u32,
+    /// Number of stanzas sent to the server
+    outbound: u32,
+    /// Sent stanzas not yet acknowledged by the server, oldest first
+    unacked: VecDeque<Element>,
+    /// Stanzas sent since the last `<r/>`
+    unrequested: u32,
+    /// Running from the first stanza sent since the last `<r/>`
+    request_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl StreamManagement {
+    /// Start counting on a session where `<enabled/>` was received
+    pub fn new(enabled: Enabled, jid: Jid) -> Self {
+        let id = match enabled.resume {
+            ResumeAttr::True => enabled.id,
+            ResumeAttr::False => None,
+        };
+        StreamManagement {
+            jid,
+            id,
+            inbound: 0,
+            outbound: 0,
+            unacked: VecDeque::new(),
+            unrequested: 0,
+            request_timer: None,
+        }
+    }
+
+    /// Is this element counted by Stream Management?
+    ///
+    /// Only stanzas are, nonzas such as `<r/>` and `<a/>` aren't.
+    pub fn is_stanza(element: &Element) -> bool {
+        element.is("message", ns::JABBER_CLIENT)
+            || element.is("presence", ns::JABBER_CLIENT)
+            || element.is("iq", ns::JABBER_CLIENT)
+    }
+
+    /// Previous stream id and handled stanza count to use in
+    /// `<resume/>`, if the server allowed resumption
+    pub fn resume_token(&self) -> Option<(StreamId, u32)> {
+        self.id.clone().map(|id| (id, self.inbound))
+    }
+
+    /// Count a stanza received from the server
+    pub fn received(&mut self) {
+        self.inbound = self.inbound.wrapping_add(1);
+    }
+
+    /// Acknowledgement of our received stanzas, to answer `<r/>`
+    pub fn ack(&self) -> A {
+        A::new(self.inbound)
+    }
+
+    /// Keep a stanza sent to the server until it is acknowledged
+    ///
+    /// Returns whether to send `<r/>` now, enough stanzas having been
+    /// sent since the last one.
+    pub fn sent(&mut self, stanza: Element) -> bool {
+        self.outbound = self.outbound.wrapping_add(1);
+        self.unacked.push_back(stanza);
+        self.unrequested += 1;
+        if self.unrequested >= ACK_REQUEST_COUNT {
+            self.requested();
+            return true;
+        }
+        if self.request_timer.is_none() {
+            self.request_timer = Some(Box::pin(sleep(ACK_REQUEST_DELAY)));
+        }
+        false
+    }
+
+    /// Note that `<r/>` was sent
+    pub fn requested(&mut self) {
+        self.unrequested = 0;
+        self.request_timer = None;
+    }
+
+    /// Whether to send `<r/>` now, stanzas having been sent a while ago
+    /// without asking for their acknowledgement
+    pub fn poll_request(&mut self, cx: &mut Context) -> bool {
+        let due = match self.request_timer {
+            Some(ref mut timer) => timer.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if due {
+            self.requested();
+        }
+        due
+    }
+
+    /// Drop the stanzas the server reported as handled
+    pub fn acked(&mut self, h: u32) {
+        let pending = self.outbound.wrapping_sub(h) as usize;
+        while self.unacked.len() > pending {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Process `<resumed/>`, returning the stanzas the server
+    /// missed, which have to be sent again through `sent()`.
+    pub fn resumed(&mut self, h: u32) -> Vec<Element> {
+        self.acked(h);
+        self.outbound = h;
+        self.requested();
+        self.unacked.drain(..).collect()
+    }
+
+    /// Number of sent stanzas the server hasn't acknowledged yet
+    pub fn unacked_count(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+/// Sends `<enable/>` on a bound stream and waits for the server's
+/// answer, keeping the stanzas received meanwhile in `received`.
+///
+/// Returns `None` if the server replied with `<failed/>`.
+pub async fn enable<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    received: &mut Vec<Element>,
+) -> Result<Option<Enabled>, Error> {
+    stream.send_stanza(Enable::new().with_resume()).await?;
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => {
+                if stanza.is("enabled", ns::SM) {
+                    return Ok(Some(
+                        Enabled::try_from(stanza).map_err(ProtocolError::Parsers)?,
+                    ));
+                } else if stanza.is("failed", ns::SM) {
+                    return Ok(None);
+                } else if StreamManagement::is_stanza(&stanza) {
+                    received.push(stanza);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+/// Sends `<resume/>` on an authenticated stream in place of
+/// resource binding, and waits for the server's answer, keeping the
+/// stanzas received meanwhile in `received`.
+///
+/// Returns `Err(Failed)` in the inner result if the previous session
+/// could not be resumed, in which case a new one has to be bound.
+pub async fn resume<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    previd: StreamId,
+    h: u32,
+    received: &mut Vec<Element>,
+) -> Result<Result<Resumed, Failed>, Error> {
+    stream.send_stanza(Resume { h, previd }).await?;
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => {
+                if stanza.is("resumed", ns::SM) {
+                    return Ok(Ok(
+                        Resumed::try_from(stanza).map_err(ProtocolError::Parsers)?
+                    ));
+                } else if stanza.is("failed", ns::SM) {
+                    return Ok(Err(
+                        Failed::try_from(stanza).map_err(ProtocolError::Parsers)?
+                    ));
+                } else if StreamManagement::is_stanza(&stanza) {
+                    received.push(stanza);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn message(body: &str) -> Element {
+        Element::builder("message", ns::JABBER_CLIENT)
+            .append(Element::builder("body", ns::JABBER_CLIENT).append(body))
+            .build()
+    }
+
+    fn enabled() -> StreamManagement {
+        let enabled = Enabled {
+            id: Some(StreamId(String::from("s1"))),
+            location: None,
+            max: None,
+            resume: ResumeAttr::True,
+        };
+        let jid = Jid::from_str("juliet@capulet.example/balcony").unwrap();
+        StreamManagement::new(enabled, jid)
+    }
+
+    #[tokio::test]
+    async fn test_counter_wrap() {
+        let mut sm = enabled();
+        sm.inbound = u32::MAX;
+        sm.received();
+        assert_eq!(sm.ack().h, 0);
+
+        sm.outbound = u32::MAX - 1;
+        sm.sent(message("1"));
+        sm.sent(message("2"));
+        sm.sent(message("3"));
+        assert_eq!(sm.outbound, 1);
+        // Handled up to the first one, before the counter wrapped
+        sm.acked(u32::MAX);
+        assert_eq!(sm.unacked_count(), 2);
+        sm.acked(1);
+        assert_eq!(sm.unacked_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_acked() {
+        let mut sm = enabled();
+        for i in 0..4 {
+            assert!(!sm.sent(message(&i.to_string())));
+        }
+        // Enough stanzas sent to ask for their ack
+        assert!(sm.sent(message("4")));
+        assert_eq!(sm.unacked_count(), 5);
+        sm.acked(3);
+        assert_eq!(sm.unacked_count(), 2);
+        assert_eq!(sm.unacked[0], message("3"));
+        // Acks don't go backwards
+        sm.acked(3);
+        assert_eq!(sm.unacked_count(), 2);
+        sm.acked(5);
+        assert_eq!(sm.unacked_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resumed() {
+        let mut sm = enabled();
+        for i in 0..3 {
+            sm.sent(message(&i.to_string()));
+        }
+        sm.received();
+        assert_eq!(sm.resume_token(), Some((StreamId(String::from("s1")), 1)));
+
+        // The server got the first one before the connection broke
+        let missed = sm.resumed(1);
+        assert_eq!(missed, [message("1"), message("2")]);
+        assert_eq!(sm.unacked_count(), 0);
+        for stanza in missed {
+            sm.sent(stanza);
+        }
+        sm.acked(3);
+        assert_eq!(sm.unacked_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_delay() {
+        let mut sm = enabled();
+        assert!(!sm.sent(message("1")));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(!sm.poll_request(&mut cx));
+        tokio::time::advance(ACK_REQUEST_DELAY).await;
+        assert!(sm.poll_request(&mut cx));
+        assert!(!sm.poll_request(&mut cx));
+    }
+}
@@ -0,0 +1,52 @@
+//! Fixtures shared by the client tests, connecting to a server whose
+//! side of the stream the test scripts
+
+use futures::future::BoxFuture;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, DuplexStream};
+use xmpp_parsers::Jid;
+
+use super::connect::{Connection, ServerConnector};
+use super::reconnect::Timeouts;
+use crate::tls::TlsConfig;
+use crate::Error;
+
+/// Stream header of the scripted server
+pub(crate) const STREAM_HEADER: &str = "<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='capulet.example' version='1.0'>";
+
+/// Hands out one end of an in-memory stream, left unencrypted
+#[derive(Debug)]
+pub(crate) struct DuplexConnector(pub(crate) Mutex<Option<DuplexStream>>);
+
+impl ServerConnector for DuplexConnector {
+    fn connect<'a>(
+        &'a self,
+        _jid: &'a Jid,
+        _timeouts: &'a Timeouts,
+        _tls: &'a TlsConfig,
+    ) -> BoxFuture<'a, Result<Connection, Error>> {
+        let stream = self.0.lock().unwrap().take();
+        Box::pin(async move {
+            let stream = stream.ok_or(Error::Disconnected)?;
+            Ok(Connection::plaintext(stream))
+        })
+    }
+}
+
+/// Reads what the client sends, up to `end`
+pub(crate) async fn expect(server: &mut DuplexStream, end: &str) -> String {
+    let mut data = String::new();
+    while !data.contains(end) {
+        let mut buf = [0; 4096];
+        let len = server.read(&mut buf).await.unwrap();
+        assert!(len > 0, "client closed the stream before sending {}", end);
+        data.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+    }
+    data
+}
+
+/// Id of the request in `data`
+pub(crate) fn id_of(data: &str) -> &str {
+    let start = data.find(" id='").unwrap() + 5;
+    &data[start..start + data[start..].find('\'').unwrap()]
+}
@@ -1,17 +1,27 @@
 //! Components in XMPP are services/gateways that are logged into an
 //! XMPP server under a JID consisting of just a domain name. They are
 //! allowed to use any user and resource identifiers in their stanzas.
-use futures::{sink::SinkExt, task::Poll, Sink, Stream};
+use futures::{sink::SinkExt, task::Poll, Future, Sink, Stream};
+use log::warn;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::task::Context;
-use tokio::net::TcpStream;
-use xmpp_parsers::{ns, Element, Jid};
+use std::time::Duration;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::{ns, BareJid, Element, Jid};
 
-use super::happy_eyeballs::connect_to_host;
-use super::xmpp_codec::Packet;
-use super::xmpp_stream;
-use super::Error;
+use super::happy_eyeballs::{connect_to_host, DEFAULT_CONNECT_TIMEOUT};
+use super::iq_tracker::{IqTracker, DEFAULT_IQ_TIMEOUT};
+use super::keepalive::{Keepalive, KeepaliveConfig};
+use super::observer::PacketObserver;
+use super::proxy::ProxyConfig;
+use super::resolver::{default_resolver, Resolver};
+use super::starttls::get_tls_stream;
+use super::tls::TlsConfig;
+use super::xmpp_codec::{CodecLimits, Packet, XMPPCodec};
+use super::xmpp_stream::{self, AsyncReadAndWrite};
+use super::{Error, IqError};
 
 mod auth;
 
@@ -23,39 +33,173 @@ pub struct Component {
     /// The component's Jabber-Id
     pub jid: Jid,
     stream: XMPPStream,
+    keepalive: Option<Keepalive>,
+    iqs: IqTracker,
+    /// Why the stream ended, if it did because of an error
+    error: Option<Error>,
 }
 
-type XMPPStream = xmpp_stream::XMPPStream<TcpStream>;
+type XMPPStream = xmpp_stream::XMPPStream<Box<dyn AsyncReadAndWrite>>;
+
+/// Component connection configuration
+#[derive(Clone)]
+pub struct Config {
+    /// The component's Jabber-Id
+    pub jid: Jid,
+    /// Secret shared with the server
+    pub password: String,
+    /// Server host name
+    pub server: String,
+    /// Server port
+    pub port: u16,
+    /// Proxy to connect to the server through
+    pub proxy: Option<ProxyConfig>,
+    /// Resolver to look up the server host name with
+    pub resolver: Arc<dyn Resolver>,
+    /// TLS configuration if the server expects TLS on this port,
+    /// the connection is plain TCP otherwise
+    pub tls: Option<TlsConfig>,
+    /// Probing of the server on idle connections; the stream ends if
+    /// it doesn't answer
+    pub keepalive: Option<KeepaliveConfig>,
+    /// Recipient of the keepalive pings, `server` as a domain if
+    /// `None`, as the component domain needn't be one of its
+    /// subdomains
+    pub keepalive_target: Option<Jid>,
+    /// Time given to the recipients of `Component::send_iq()` requests
+    /// to answer
+    pub iq_timeout: Duration,
+    /// Bounds on the stanzas received from the server, past which the
+    /// stream is closed with a `<policy-violation/>`
+    pub limits: CodecLimits,
+    /// What gets the packets of the connection, the handshake redacted
+    pub observer: Option<Arc<dyn PacketObserver>>,
+}
+
+impl Config {
+    /// Configuration for a plain TCP connection
+    pub fn new<P: Into<String>, S: Into<String>>(
+        jid: Jid,
+        password: P,
+        server: S,
+        port: u16,
+    ) -> Self {
+        Config {
+            jid,
+            password: password.into(),
+            server: server.into(),
+            port,
+            proxy: None,
+            resolver: default_resolver(),
+            tls: None,
+            keepalive: None,
+            keepalive_target: None,
+            iq_timeout: DEFAULT_IQ_TIMEOUT,
+            limits: CodecLimits::default(),
+            observer: None,
+        }
+    }
+}
 
 impl Component {
     /// Start a new XMPP component
     pub async fn new(jid: &str, password: &str, server: &str, port: u16) -> Result<Self, Error> {
         let jid = Jid::from_str(jid)?;
-        let password = password.to_owned();
-        let stream = Self::connect(jid.clone(), password, server, port).await?;
-        Ok(Component { jid, stream })
+        Self::new_with_config(Config::new(jid, password, server, port)).await
     }
 
-    async fn connect(
-        jid: Jid,
-        password: String,
-        server: &str,
-        port: u16,
-    ) -> Result<XMPPStream, Error> {
-        let password = password;
-        let tcp_stream = connect_to_host(server, port).await?;
-        let mut xmpp_stream =
-            xmpp_stream::XMPPStream::start(tcp_stream, jid, ns::COMPONENT_ACCEPT.to_owned())
-                .await?;
+    /// Start a new XMPP component with the given configuration
+    pub async fn new_with_config(config: Config) -> Result<Self, Error> {
+        let jid = config.jid.clone();
+        let keepalive_config = config.keepalive.clone();
+        let keepalive_target = config
+            .keepalive_target
+            .clone()
+            .unwrap_or_else(|| Jid::Bare(BareJid::domain(config.server.clone())));
+        let iqs = IqTracker::new(config.iq_timeout);
+        let stream = Self::connect(config).await?;
+        // Idle from the end of the handshake on
+        let keepalive = keepalive_config
+            .map(|keepalive| Keepalive::new(keepalive, keepalive_target).with_from(jid.clone()));
+        Ok(Component {
+            jid,
+            stream,
+            keepalive,
+            iqs,
+            error: None,
+        })
+    }
+
+    async fn connect(config: Config) -> Result<XMPPStream, Error> {
+        let Config {
+            jid,
+            password,
+            server,
+            port,
+            proxy,
+            resolver,
+            tls,
+            limits,
+            observer,
+            ..
+        } = config;
+        let tcp_stream = connect_to_host(
+            &server,
+            port,
+            DEFAULT_CONNECT_TIMEOUT,
+            &resolver,
+            proxy.as_ref(),
+        )
+        .await?;
+        let stream: Box<dyn AsyncReadAndWrite> = match tls {
+            // The server certificate is checked against the host name
+            // we connect to, the component domain being our own.
+            Some(tls) => Box::new(get_tls_stream(tcp_stream, &server, None, &tls).await?),
+            None => Box::new(tcp_stream),
+        };
+        let codec = XMPPCodec::with_limits(limits).with_observer(observer);
+        let mut xmpp_stream = xmpp_stream::XMPPStream::start_with_codec(
+            stream,
+            jid,
+            ns::COMPONENT_ACCEPT.to_owned(),
+            codec,
+        )
+        .await?;
         auth::auth(&mut xmpp_stream, password).await?;
         Ok(xmpp_stream)
     }
 
+    /// Get the error which ended the stream, such as the
+    /// `Error::StreamError` the server closed it with
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
     /// Send stanza
     pub async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
         self.send(stanza).await
     }
 
+    /// Send a request, and get the future of the payload of its result
+    ///
+    /// The request gets a unique id, replacing the one it has. The
+    /// response has to come from the recipient of the request, within
+    /// `Config::iq_timeout`, and before the stream ends.
+    ///
+    /// The request is sent, and its response received, while this
+    /// component is being polled for stanzas.
+    pub fn send_iq(
+        &mut self,
+        mut iq: Iq,
+    ) -> impl Future<Output = Result<Option<Element>, IqError>> {
+        let response = self.iqs.request(&mut iq);
+        let id = iq.id.clone();
+        if let Err(e) = Pin::new(&mut *self).start_send(iq.into()) {
+            self.iqs.failed(&id, e);
+        }
+        response
+    }
+
     /// End connection
     pub async fn send_end(&mut self) -> Result<(), Error> {
         self.close().await
@@ -66,9 +210,70 @@ impl Stream for Component {
     type Item = Element;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let stanza = self.as_mut().poll_stanza(cx);
+        if let Poll::Ready(None) = stanza {
+            // Their responses won't come anymore
+            self.iqs.disconnected();
+        }
+        stanza
+    }
+}
+
+impl Component {
+    /// Reads the next stanza, probing the server when idle
+    fn poll_stanza(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Element>> {
+        // Polled again after each packet received, which restarts the
+        // idle timer, so that it wakes us up when it expires
         loop {
-            match Pin::new(&mut self.stream).poll_next(cx) {
-                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => return Poll::Ready(Some(stanza)),
+            // Probe the server if the connection has been idle
+            match self.keepalive.as_mut().map(|keepalive| keepalive.poll(cx)) {
+                Some(Poll::Ready(Ok(probe))) => {
+                    if let Err(e) = Pin::new(&mut self.stream).start_send(probe) {
+                        warn!("Ending the component stream: {}", e);
+                        self.error = Some(e);
+                        return Poll::Ready(None);
+                    }
+                }
+                Some(Poll::Ready(Err(e))) => {
+                    warn!("Ending the component stream: {}", e);
+                    self.error = Some(e);
+                    return Poll::Ready(None);
+                }
+                Some(Poll::Pending) | None => (),
+            }
+
+            // Send the probe and what send_iq() queued
+            if let Poll::Ready(Err(e)) = Pin::new(&mut self.stream).poll_flush(cx) {
+                warn!("Ending the component stream: {}", e);
+                self.error = Some(e);
+                return Poll::Ready(None);
+            }
+
+            let packet = Pin::new(&mut self.stream).poll_next(cx);
+            if let (Poll::Ready(Some(Ok(_))), Some(keepalive)) = (&packet, self.keepalive.as_mut())
+            {
+                keepalive.received();
+            }
+            match packet {
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) if stanza.is("error", ns::STREAM) => {
+                    // The server is closing the stream
+                    let error = Error::from_stream_error(stanza);
+                    warn!("Ending the component stream: {}", error);
+                    self.error = Some(error);
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
+                    let this = &mut *self;
+                    match this.keepalive {
+                        // Answer to our keepalive ping
+                        Some(ref mut keepalive) if keepalive.is_pong(&stanza) => (),
+                        _ => match this.iqs.response(stanza, &this.jid) {
+                            Some(stanza) => return Poll::Ready(Some(stanza)),
+                            // Response to send_iq()
+                            None => (),
+                        },
+                    }
+                }
                 Poll::Ready(Some(Ok(Packet::Text(_)))) => {
                     // retry
                 }
@@ -77,7 +282,24 @@ impl Stream for Component {
                 {
                     return Poll::Ready(None)
                 }
-                Poll::Ready(Some(Err(_))) => return Poll::Ready(None),
+                Poll::Ready(Some(Err(Error::Policy(e)))) => {
+                    // Tell the server why we close the stream, as far
+                    // as it can be sent right away
+                    warn!("Ending the component stream: {}", e);
+                    let error = Packet::Stanza(e.to_stream_error().into());
+                    let sent = Pin::new(&mut self.stream)
+                        .start_send(error)
+                        .and_then(|()| Pin::new(&mut self.stream).start_send(Packet::StreamEnd));
+                    if sent.is_ok() {
+                        let _ = Pin::new(&mut self.stream).poll_flush(cx);
+                    }
+                    self.error = Some(e.into());
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    self.error = Some(e);
+                    return Poll::Ready(None);
+                }
                 Poll::Ready(None) => return Poll::Ready(None),
                 Poll::Pending => return Poll::Pending,
             }
@@ -89,6 +311,9 @@ impl Sink<Element> for Component {
     type Error = Error;
 
     fn start_send(mut self: Pin<&mut Self>, item: Element) -> Result<(), Self::Error> {
+        if let Some(ref mut keepalive) = self.keepalive {
+            keepalive.sent();
+        }
         Pin::new(&mut self.stream)
             .start_send(Packet::Stanza(item))
             .map_err(|e| e.into())
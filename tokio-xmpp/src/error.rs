@@ -1,19 +1,27 @@
+#[cfg(feature = "bosh")]
+use hyper::http::uri::InvalidUri;
 #[cfg(feature = "tls-native")]
-use native_tls::Error as TlsError;
+use native_tls::Error as TlsBackendError;
 use sasl::client::MechanismError as SaslMechanismError;
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io::Error as IoError;
+use std::net::SocketAddr;
 #[cfg(feature = "tls-rust")]
 use tokio_rustls::rustls::client::InvalidDnsNameError;
 #[cfg(feature = "tls-rust")]
-use tokio_rustls::rustls::Error as TlsError;
+use tokio_rustls::rustls::Error as TlsBackendError;
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::Error as WebSocketError;
 use trust_dns_proto::error::ProtoError;
 use trust_dns_resolver::error::ResolveError;
 
 use xmpp_parsers::sasl::DefinedCondition as SaslDefinedCondition;
-use xmpp_parsers::{Error as ParsersError, JidParseError};
+use xmpp_parsers::stanza_error::{DefinedCondition as StanzaDefinedCondition, StanzaError};
+use xmpp_parsers::stream_error::{DefinedCondition as StreamDefinedCondition, StreamError};
+use xmpp_parsers::{Element, Error as ParsersError, JidParseError};
 
 /// Top-level error type
 #[derive(Debug)]
@@ -31,11 +39,27 @@ pub enum Error {
     Protocol(ProtocolError),
     /// Authentication error
     Auth(AuthError),
+    /// In-band registration error
+    Register(RegisterError),
     /// TLS error
     Tls(TlsError),
     #[cfg(feature = "tls-rust")]
     /// DNS name parsing error
     DnsNameError(InvalidDnsNameError),
+    #[cfg(feature = "websocket")]
+    /// WebSocket transport error
+    WebSocket(WebSocketError),
+    #[cfg(feature = "bosh")]
+    /// BOSH transport error
+    Bosh(BoshError),
+    /// A step of the connection took too long
+    Timeout(ConnectionPhase),
+    /// The server didn't answer a keepalive ping in time
+    KeepaliveTimeout,
+    /// The server closed the stream with a `<stream:error/>`
+    StreamError(StreamError),
+    /// The server sent more than the configured `CodecLimits` allow
+    Policy(PolicyError),
     /// Connection closed
     Disconnected,
     /// Shoud never happen
@@ -51,9 +75,24 @@ impl fmt::Display for Error {
             Error::JidParse(e) => write!(fmt, "jid parse error: {}", e),
             Error::Protocol(e) => write!(fmt, "protocol error: {}", e),
             Error::Auth(e) => write!(fmt, "authentication error: {}", e),
+            Error::Register(e) => write!(fmt, "registration error: {}", e),
             Error::Tls(e) => write!(fmt, "TLS error: {}", e),
             #[cfg(feature = "tls-rust")]
             Error::DnsNameError(e) => write!(fmt, "DNS name error: {}", e),
+            #[cfg(feature = "websocket")]
+            Error::WebSocket(e) => write!(fmt, "WebSocket error: {}", e),
+            #[cfg(feature = "bosh")]
+            Error::Bosh(e) => write!(fmt, "BOSH error: {}", e),
+            Error::Timeout(phase) => write!(fmt, "timed out during {}", phase),
+            Error::KeepaliveTimeout => write!(fmt, "no answer to the keepalive ping"),
+            Error::StreamError(e) => {
+                write!(fmt, "stream error: {:?}", e.defined_condition)?;
+                match e.texts.values().next() {
+                    Some(text) => write!(fmt, " ({})", text),
+                    None => Ok(()),
+                }
+            }
+            Error::Policy(e) => write!(fmt, "policy violation: {}", e),
             Error::Disconnected => write!(fmt, "disconnected"),
             Error::InvalidState => write!(fmt, "invalid state"),
         }
@@ -62,6 +101,17 @@ impl fmt::Display for Error {
 
 impl StdError for Error {}
 
+impl Error {
+    /// Turns the `<stream:error/>` the server closed the stream with
+    /// into an error
+    pub(crate) fn from_stream_error(elem: Element) -> Self {
+        match StreamError::try_from(elem) {
+            Ok(e) => Error::StreamError(e),
+            Err(e) => ProtocolError::Parsers(e).into(),
+        }
+    }
+}
+
 impl From<IoError> for Error {
     fn from(e: IoError) -> Self {
         Error::Io(e)
@@ -92,12 +142,36 @@ impl From<AuthError> for Error {
     }
 }
 
+impl From<RegisterError> for Error {
+    fn from(e: RegisterError) -> Self {
+        Error::Register(e)
+    }
+}
+
+impl From<StreamError> for Error {
+    fn from(e: StreamError) -> Self {
+        Error::StreamError(e)
+    }
+}
+
+impl From<PolicyError> for Error {
+    fn from(e: PolicyError) -> Self {
+        Error::Policy(e)
+    }
+}
+
 impl From<TlsError> for Error {
     fn from(e: TlsError) -> Self {
         Error::Tls(e)
     }
 }
 
+impl From<TlsBackendError> for Error {
+    fn from(e: TlsBackendError) -> Self {
+        Error::Tls(TlsError::Backend(e))
+    }
+}
+
 #[cfg(feature = "tls-rust")]
 impl From<InvalidDnsNameError> for Error {
     fn from(e: InvalidDnsNameError) -> Self {
@@ -105,6 +179,27 @@ impl From<InvalidDnsNameError> for Error {
     }
 }
 
+#[cfg(feature = "websocket")]
+impl From<WebSocketError> for Error {
+    fn from(e: WebSocketError) -> Self {
+        Error::WebSocket(e)
+    }
+}
+
+#[cfg(feature = "bosh")]
+impl From<BoshError> for Error {
+    fn from(e: BoshError) -> Self {
+        Error::Bosh(e)
+    }
+}
+
+/// Wraps an error into an I/O error, for the transports exposed as
+/// byte streams
+#[cfg(any(feature = "websocket", feature = "bosh"))]
+pub(crate) fn to_io_error<E: ToString>(e: E) -> IoError {
+    IoError::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
 /// XML parse error wrapper type
 #[derive(Debug)]
 pub struct ParseError(pub Cow<'static, str>);
@@ -135,6 +230,8 @@ pub enum ProtocolError {
     NoTls,
     /// Invalid response to resource binding
     InvalidBindResponse,
+    /// Invalid response to an in-band registration request
+    InvalidRegisterResponse,
     /// No xmlns attribute in <stream:stream>
     NoStreamNamespace,
     /// No id attribute in <stream:stream>
@@ -143,6 +240,15 @@ pub enum ProtocolError {
     InvalidToken,
     /// Unexpected <stream:stream> (shouldn't occur)
     InvalidStreamStart,
+    /// The server didn't agree on the `xmpp` WebSocket subprotocol
+    NoWebSocketSubprotocol,
+    /// Invalid SCRAM message from the server
+    InvalidScram,
+    /// The server asked to go on with SASL2 tasks, none of which we
+    /// support, with their names
+    UnsupportedSasl2Tasks(Vec<String>),
+    /// Response to a request which is neither a result nor an error
+    InvalidIqResponse,
 }
 
 impl fmt::Display for ProtocolError {
@@ -154,12 +260,25 @@ impl fmt::Display for ProtocolError {
             ProtocolError::InvalidBindResponse => {
                 write!(fmt, "invalid response to resource binding")
             }
+            ProtocolError::InvalidRegisterResponse => {
+                write!(fmt, "invalid response to an in-band registration request")
+            }
             ProtocolError::NoStreamNamespace => {
                 write!(fmt, "no xmlns attribute in <stream:stream>")
             }
             ProtocolError::NoStreamId => write!(fmt, "no id attribute in <stream:stream>"),
             ProtocolError::InvalidToken => write!(fmt, "encountered an unexpected XML token"),
             ProtocolError::InvalidStreamStart => write!(fmt, "unexpected <stream:stream>"),
+            ProtocolError::NoWebSocketSubprotocol => {
+                write!(fmt, "server didn't agree on the xmpp WebSocket subprotocol")
+            }
+            ProtocolError::InvalidScram => write!(fmt, "invalid SCRAM message from the server"),
+            ProtocolError::UnsupportedSasl2Tasks(tasks) => {
+                write!(fmt, "unsupported SASL2 tasks: {}", tasks.join(", "))
+            }
+            ProtocolError::InvalidIqResponse => {
+                write!(fmt, "response which is neither a result nor an error")
+            }
         }
     }
 }
@@ -195,6 +314,27 @@ pub enum AuthError {
     Fail(SaslDefinedCondition),
     /// Component authentication failure
     ComponentFail,
+    /// The server binds the channel, but not with any SCRAM-*-PLUS
+    /// mechanism we support
+    NoChannelBinding,
+    /// The server accepted a FAST token without proving it knows it
+    TokenProof,
+    /// PLAIN is the only mechanism in common, refused as the
+    /// connection isn't encrypted
+    PlainWithoutTls,
+    /// The OAuth 2.0 token would be sent over a connection which isn't
+    /// encrypted
+    TokenWithoutTls,
+    /// The stored SCRAM keys are for another salt or iteration count,
+    /// and there is no password to derive new ones from
+    ScramKeysMismatch,
+    /// The server didn't prove it knows the password
+    ServerProof,
+    /// Anonymous login was requested, but the server doesn't offer
+    /// ANONYMOUS
+    AnonymousUnavailable,
+    /// An OAuth mechanism was selected without a token to send
+    NoToken,
 }
 
 impl StdError for AuthError {}
@@ -206,25 +346,340 @@ impl fmt::Display for AuthError {
             AuthError::Sasl(s) => write!(fmt, "local SASL implementation error: {}", s),
             AuthError::Fail(c) => write!(fmt, "failure from the server: {:?}", c),
             AuthError::ComponentFail => write!(fmt, "component authentication failure"),
+            AuthError::NoChannelBinding => {
+                write!(fmt, "no supported mechanism with channel binding")
+            }
+            AuthError::TokenProof => write!(fmt, "the server didn't prove it knows the token"),
+            AuthError::PlainWithoutTls => {
+                write!(fmt, "PLAIN refused over an unencrypted connection")
+            }
+            AuthError::TokenWithoutTls => {
+                write!(fmt, "OAuth token refused over an unencrypted connection")
+            }
+            AuthError::ScramKeysMismatch => {
+                write!(
+                    fmt,
+                    "the SCRAM keys don't match the server salt and iterations"
+                )
+            }
+            AuthError::ServerProof => write!(fmt, "the server didn't prove it knows the password"),
+            AuthError::AnonymousUnavailable => {
+                write!(fmt, "the server doesn't offer anonymous login")
+            }
+            AuthError::NoToken => write!(fmt, "no OAuth token to authenticate with"),
+        }
+    }
+}
+
+/// In-band registration (XEP-0077) error
+#[derive(Debug)]
+pub enum RegisterError {
+    /// The server doesn't offer in-band registration
+    Unsupported,
+    /// The password would be sent over a connection which isn't
+    /// encrypted
+    PasswordWithoutTls,
+    /// The username is already taken
+    Conflict,
+    /// Required information is missing, or got refused, such as a
+    /// password too weak
+    NotAcceptable,
+    /// The server doesn't allow the request, such as when registering
+    /// too many accounts
+    NotAllowed,
+    /// Another error answered by the server
+    Stanza(StanzaError),
+}
+
+impl StdError for RegisterError {}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::Unsupported => write!(fmt, "in-band registration not supported"),
+            RegisterError::PasswordWithoutTls => {
+                write!(fmt, "password refused over an unencrypted connection")
+            }
+            RegisterError::Conflict => write!(fmt, "username already taken"),
+            RegisterError::NotAcceptable => write!(fmt, "missing or refused information"),
+            RegisterError::NotAllowed => write!(fmt, "request not allowed"),
+            RegisterError::Stanza(e) => write!(fmt, "error response: {:?}", e.defined_condition),
+        }
+    }
+}
+
+impl From<StanzaError> for RegisterError {
+    fn from(e: StanzaError) -> Self {
+        match e.defined_condition {
+            StanzaDefinedCondition::Conflict => RegisterError::Conflict,
+            StanzaDefinedCondition::NotAcceptable => RegisterError::NotAcceptable,
+            StanzaDefinedCondition::NotAllowed => RegisterError::NotAllowed,
+            _ => RegisterError::Stanza(e),
+        }
+    }
+}
+
+/// TLS error, telling which check failed
+#[derive(Debug)]
+pub enum TlsError {
+    /// Error from the TLS implementation
+    Backend(TlsBackendError),
+    /// The root certificate at this index of the configuration couldn't
+    /// be parsed
+    InvalidRootCertificate(usize),
+    /// The configured client certificate or its key couldn't be used
+    InvalidClientCertificate(TlsBackendError),
+    /// The server certificate chain didn't verify against the trusted
+    /// roots and the domain
+    ///
+    /// Only told apart with `tls-rust`, native-tls reports these
+    /// failures as `Backend`.
+    CertificateVerification(TlsBackendError),
+    /// The server didn't present any certificate to check the pins
+    /// against
+    NoPeerCertificate,
+    /// No certificate presented by the server matched the configured
+    /// pins
+    PinMismatch,
+    /// The configured minimum TLS version isn't available with this
+    /// TLS implementation
+    UnsupportedVersion,
+    /// `TlsPolicy::InsecurePlaintext` was asked for on a connection
+    /// which doesn't stay on this host
+    PlaintextNotLoopback,
+}
+
+impl StdError for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Backend(e) => write!(fmt, "{}", e),
+            TlsError::InvalidRootCertificate(index) => {
+                write!(fmt, "invalid root certificate at index {}", index)
+            }
+            TlsError::InvalidClientCertificate(e) => {
+                write!(fmt, "invalid client certificate: {}", e)
+            }
+            TlsError::CertificateVerification(e) => {
+                write!(fmt, "server certificate verification failed: {}", e)
+            }
+            TlsError::NoPeerCertificate => write!(fmt, "no server certificate to check pins"),
+            TlsError::PinMismatch => write!(fmt, "server certificate doesn't match any pin"),
+            TlsError::UnsupportedVersion => {
+                write!(
+                    fmt,
+                    "minimum TLS version not supported by the TLS implementation"
+                )
+            }
+            TlsError::PlaintextNotLoopback => {
+                write!(fmt, "plaintext is only allowed to loopback addresses")
+            }
         }
     }
 }
 
+/// Limit of the `CodecLimits` an incoming stanza exceeded, along with
+/// its configured value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    /// Bytes of a single stanza
+    StanzaSize(usize),
+    /// Nesting of elements in a stanza
+    Depth(usize),
+    /// Attributes of a single element, namespace declarations included
+    Attributes(usize),
+    /// Bytes of a single text node
+    TextLength(usize),
+}
+
+impl PolicyError {
+    /// The `<policy-violation/>` stream error to close the stream with
+    pub fn to_stream_error(&self) -> StreamError {
+        let mut error = StreamError::new(StreamDefinedCondition::PolicyViolation);
+        error.texts.insert(String::from("en"), self.to_string());
+        error
+    }
+}
+
+impl StdError for PolicyError {}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::StanzaSize(max) => write!(fmt, "stanza larger than {} bytes", max),
+            PolicyError::Depth(max) => write!(fmt, "elements nested deeper than {}", max),
+            PolicyError::Attributes(max) => write!(fmt, "more than {} attributes", max),
+            PolicyError::TextLength(max) => write!(fmt, "text longer than {} bytes", max),
+        }
+    }
+}
+
+/// Step of a connection, to tell which one timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPhase {
+    /// Direct TLS or STARTTLS
+    Tls,
+    /// Authentication
+    Auth,
+    /// Resource binding and Stream Management negotiation
+    Bind,
+    /// In-band registration requests
+    Register,
+}
+
+impl fmt::Display for ConnectionPhase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionPhase::Tls => write!(fmt, "TLS negotiation"),
+            ConnectionPhase::Auth => write!(fmt, "authentication"),
+            ConnectionPhase::Bind => write!(fmt, "resource binding"),
+            ConnectionPhase::Register => write!(fmt, "in-band registration"),
+        }
+    }
+}
+
+/// Failure of a request sent with `send_iq()`
+#[derive(Debug)]
+pub enum IqError {
+    /// The request couldn't be sent
+    Send(Error),
+    /// The recipient answered with an error
+    Stanza(StanzaError),
+    /// The response couldn't be parsed
+    InvalidResponse(ParsersError),
+    /// No answer came in time
+    Timeout,
+    /// The connection ended before the answer came
+    Disconnected,
+    /// The answer didn't follow the protocol
+    Protocol(ProtocolError),
+}
+
+impl StdError for IqError {}
+
+impl fmt::Display for IqError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IqError::Send(e) => write!(fmt, "couldn't send the request: {}", e),
+            IqError::Stanza(e) => write!(fmt, "error response: {:?}", e.defined_condition),
+            IqError::InvalidResponse(e) => write!(fmt, "invalid response: {}", e),
+            IqError::Timeout => write!(fmt, "no response in time"),
+            IqError::Disconnected => write!(fmt, "disconnected before the response"),
+            IqError::Protocol(e) => write!(fmt, "protocol error: {}", e),
+        }
+    }
+}
+
+impl From<StanzaError> for IqError {
+    fn from(e: StanzaError) -> Self {
+        IqError::Stanza(e)
+    }
+}
+
 /// Error establishing connection
 #[derive(Debug)]
 pub enum ConnecterError {
-    /// All attempts failed, no error available
-    AllFailed,
+    /// All connection attempts failed, with the error of each of them
+    AllFailed(Vec<(SocketAddr, IoError)>),
+    /// No connection could be established in time, with the error of
+    /// each attempt that failed in the meantime
+    Timeout(Vec<(SocketAddr, IoError)>),
     /// DNS protocol error
     Dns(ProtoError),
     /// DNS resolution error
     Resolve(ResolveError),
+    /// All connection attempts through the proxy failed, with the
+    /// target and error of each of them
+    Proxy(Vec<(String, ProxyError)>),
+    /// The SRV records of the domain say that it offers no XMPP
+    /// service, with a target of "."
+    Unavailable,
 }
 
 impl StdError for ConnecterError {}
 
 impl std::fmt::Display for ConnecterError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(fmt, "{:?}", self)
+        let (message, attempts) = match self {
+            ConnecterError::AllFailed(attempts) => ("all connection attempts failed", attempts),
+            ConnecterError::Timeout(attempts) => ("connection timed out", attempts),
+            ConnecterError::Proxy(attempts) => {
+                write!(fmt, "all connection attempts through the proxy failed")?;
+                for (target, e) in attempts {
+                    write!(fmt, "; {}: {}", target, e)?;
+                }
+                return Ok(());
+            }
+            ConnecterError::Unavailable => return write!(fmt, "the domain offers no XMPP service"),
+            _ => return write!(fmt, "{:?}", self),
+        };
+        write!(fmt, "{}", message)?;
+        if attempts.is_empty() {
+            return write!(fmt, " (no address to connect to)");
+        }
+        for (addr, e) in attempts {
+            write!(fmt, "; {}: {}", addr, e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Failure to connect through a proxy
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The connection to the proxy failed
+    Io(IoError),
+    /// The proxy accepts none of the authentication methods we have,
+    /// or rejected the credentials
+    Auth,
+    /// The proxy couldn't connect to the target, for this reason
+    Refused(String),
+    /// The proxy didn't answer as a SOCKS5 or HTTP proxy would
+    InvalidResponse,
+}
+
+impl StdError for ProxyError {}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::Io(e) => write!(fmt, "{}", e),
+            ProxyError::Auth => write!(fmt, "proxy authentication failed"),
+            ProxyError::Refused(reason) => write!(fmt, "refused by the proxy: {}", reason),
+            ProxyError::InvalidResponse => write!(fmt, "invalid response from the proxy"),
+        }
+    }
+}
+
+impl From<IoError> for ProxyError {
+    fn from(e: IoError) -> Self {
+        ProxyError::Io(e)
+    }
+}
+
+/// BOSH transport error
+#[cfg(feature = "bosh")]
+#[derive(Debug)]
+pub enum BoshError {
+    /// The endpoint URL couldn't be parsed
+    InvalidUrl(InvalidUri),
+    /// The endpoint URL is neither `http:` nor `https:`
+    UnsupportedScheme,
+    /// HTTP error on the first connection
+    Http(hyper::Error),
+}
+
+#[cfg(feature = "bosh")]
+impl StdError for BoshError {}
+
+#[cfg(feature = "bosh")]
+impl fmt::Display for BoshError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoshError::InvalidUrl(e) => write!(fmt, "invalid endpoint URL: {}", e),
+            BoshError::UnsupportedScheme => write!(fmt, "endpoint URL isn't http: or https:"),
+            BoshError::Http(e) => write!(fmt, "HTTP error: {}", e),
+        }
     }
 }
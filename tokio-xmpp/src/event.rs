@@ -1,4 +1,5 @@
 use super::Error;
+use std::time::Duration;
 use xmpp_parsers::{Element, Jid};
 
 /// High-level event on the Stream implemented by Client and Component
@@ -14,11 +15,29 @@ pub enum Event {
         bound_jid: Jid,
         /// Was this session resumed?
         ///
-        /// Not yet implemented for the Client
+        /// A resumed session (XEP-0198) keeps the previous presence,
+        /// roster state and bound JID, and the stanzas the server
+        /// missed have been sent again.
         resumed: bool,
     },
     /// Stream end
     Disconnected(Error),
+    /// A new connection will be attempted after `delay`
+    Reconnecting {
+        /// Number of the attempt, counting from 1 since the last
+        /// successful connection
+        attempt: u32,
+        /// Time until the attempt
+        delay: Duration,
+    },
+    /// The server redirected us with `<see-other-host/>`, and a
+    /// connection to this host is being attempted
+    Redirected {
+        /// Host name or IP address
+        host: String,
+        /// TCP port
+        port: u16,
+    },
     /// Received stanza/nonza
     Stanza(Element),
 }
@@ -1,63 +1,609 @@
-use crate::{ConnecterError, Error};
+//! Connection establishment racing IPv6 and IPv4 as per RFC 8305
+//! (Happy Eyeballs v2), on hosts found in SRV records ordered as per
+//! RFC 2782, directly or through a proxy.
+
+use crate::proxy::ProxyConfig;
+use crate::resolver::Resolver;
+use crate::{ConnecterError, Error, ProxyError};
+use futures::future::{join_all, Future, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
 use idna;
-use std::net::SocketAddr;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::Error as IoError;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use trust_dns_resolver::{IntoName, TokioAsyncResolver};
+use tokio::time::{sleep_until, timeout_at, Instant};
+use trust_dns_resolver::error::ResolveError;
 
-pub async fn connect_to_host(domain: &str, port: u16) -> Result<TcpStream, Error> {
-    let ascii_domain = idna::domain_to_ascii(&domain).map_err(|_| Error::Idna)?;
+/// Delay before starting the next connection attempt while the
+/// previous ones are still pending (RFC 8305, section 5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
-    if let Ok(ip) = ascii_domain.parse() {
-        return Ok(TcpStream::connect(&SocketAddr::new(ip, port)).await?);
-    }
+/// Time to wait for AAAA records when A records came first
+/// (RFC 8305, section 3)
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
 
-    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(ConnecterError::Resolve)?;
+/// Time given to establish a connection unless configured otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let ips = resolver
-        .lookup_ip(ascii_domain)
-        .await
-        .map_err(ConnecterError::Resolve)?;
-    for ip in ips.iter() {
-        match TcpStream::connect(&SocketAddr::new(ip, port)).await {
-            Ok(stream) => return Ok(stream),
-            Err(_) => {}
-        }
+/// Where a connection got established, so as to connect again to the
+/// very same server, with `connect_to_host()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    /// IP address connected to, or target given to a proxy resolving
+    /// host names itself
+    pub host: String,
+    /// Port connected to
+    pub port: u16,
+}
+
+/// Connects to `domain` on `port`, looked up with `resolver`, through
+/// `proxy` if set, giving up after `timeout`.
+pub async fn connect_to_host(
+    domain: &str,
+    port: u16,
+    timeout: Duration,
+    resolver: &Arc<dyn Resolver>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<TcpStream, Error> {
+    let (stream, _) = connect_to_host_with_endpoint(domain, port, timeout, resolver, proxy).await?;
+    Ok(stream)
+}
+
+/// Same as `connect_to_host()`, also returning where the connection
+/// got established.
+pub async fn connect_to_host_with_endpoint(
+    domain: &str,
+    port: u16,
+    timeout: Duration,
+    resolver: &Arc<dyn Resolver>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<(TcpStream, Endpoint), Error> {
+    let ascii_domain = if domain.parse::<IpAddr>().is_ok() {
+        domain.to_owned()
+    } else {
+        idna::domain_to_ascii(&domain).map_err(|_| Error::Idna)?
+    };
+
+    let mut connecter = Connecter::new(timeout, resolver, proxy);
+    match connecter.host(&ascii_domain, port).await? {
+        Some(stream) => Ok(stream),
+        None => Err(connecter.into_error()),
     }
-    Err(Error::Disconnected)
 }
 
+/// Resolves the SRV records of all `services` for `domain` with
+/// `resolver`, merges them by priority and weight, and connects to the
+/// first reachable target, giving up after `timeout`.
+///
+/// Each service comes with whether its targets expect Direct TLS
+/// (XEP-0368), which is returned along with the connection and where
+/// it got established. When no
+/// record is found, the domain itself is tried on the port of
+/// `fallback`, with Direct TLS if it says so. When the only records
+/// found have a target of ".", the service isn't available (RFC 2782)
+/// and nothing is tried.
+///
+/// SRV records are always looked up locally, proxies having no way to
+/// do it. When the proxy resolves host names, it is given the targets
+/// of the records, or the domain itself if the lookup failed.
 pub async fn connect_with_srv(
     domain: &str,
-    srv: &str,
-    fallback_port: u16,
-) -> Result<TcpStream, Error> {
+    services: &[(&str, bool)],
+    fallback: (u16, bool),
+    timeout: Duration,
+    resolver: &Arc<dyn Resolver>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<(TcpStream, bool, Endpoint), Error> {
     let ascii_domain = idna::domain_to_ascii(&domain).map_err(|_| Error::Idna)?;
 
-    if let Ok(ip) = ascii_domain.parse() {
-        return Ok(TcpStream::connect(&SocketAddr::new(ip, fallback_port)).await?);
+    let mut connecter = Connecter::new(timeout, resolver, proxy);
+    if ascii_domain.parse::<IpAddr>().is_ok() {
+        return connecter.fallback(&ascii_domain, fallback).await;
+    }
+
+    let mut lookups = Vec::new();
+    for &(srv, direct_tls) in services {
+        let srv_domain = format!("{}.{}.", srv, ascii_domain);
+        let resolver = resolver.clone();
+        lookups.push(async move { (resolver.srv(&srv_domain).await.ok(), direct_tls) });
+    }
+    let lookups = match timeout_at(connecter.deadline, join_all(lookups)).await {
+        Ok(lookups) => lookups,
+        Err(_) => {
+            connecter.timed_out = true;
+            return Err(connecter.into_error());
+        }
+    };
+
+    let mut records = Vec::new();
+    let mut unavailable = false;
+    for (lookup, direct_tls) in lookups {
+        if let Some(lookup) = lookup {
+            for srv in lookup.records {
+                // A target of "." means that the service isn't
+                // available at all
+                if srv.target == "." {
+                    unavailable = true;
+                } else {
+                    records.push((srv.priority, srv.weight, (srv.target, srv.port, direct_tls)));
+                }
+            }
+        }
+    }
+
+    if records.is_empty() {
+        if unavailable {
+            return Err(ConnecterError::Unavailable.into());
+        }
+        // No SRV records or lookup error, retry with hostname
+        return connecter.fallback(&ascii_domain, fallback).await;
+    }
+
+    let targets = order_srv(records, &mut rand::thread_rng());
+    for (host, port, direct_tls) in targets {
+        if let Some((stream, endpoint)) = connecter.host(&host, port).await? {
+            return Ok((stream, direct_tls, endpoint));
+        }
+        if connecter.timed_out {
+            break;
+        }
+    }
+    Err(connecter.into_error())
+}
+
+/// Orders SRV targets as described in RFC 2782: by ascending
+/// priority, and by weighted random selection within a priority.
+///
+/// Records are given as `(priority, weight, target)`.
+fn order_srv<T, R: Rng>(mut records: Vec<(u16, u16, T)>, rng: &mut R) -> Vec<T> {
+    // Those with a weight of 0 go first within their priority, so
+    // that they only get a small chance to be selected early.
+    records.sort_by_key(|&(priority, weight, _)| (priority, weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].0;
+        let end = records
+            .iter()
+            .position(|&(p, _, _)| p != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<_> = records.drain(..end).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|&(_, weight, _)| u32::from(weight)).sum();
+            let selected = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let index = group
+                .iter()
+                .position(|&(_, weight, _)| {
+                    sum += u32::from(weight);
+                    sum >= selected
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index).2);
+        }
+    }
+    ordered
+}
+
+/// State shared by the connection attempts to all hosts of a domain
+struct Connecter {
+    resolver: Arc<dyn Resolver>,
+    proxy: Option<ProxyConfig>,
+    deadline: Instant,
+    timed_out: bool,
+    failed: Vec<(SocketAddr, IoError)>,
+    proxy_failed: Vec<(String, ProxyError)>,
+    resolve_error: Option<ResolveError>,
+}
+
+impl Connecter {
+    fn new(timeout: Duration, resolver: &Arc<dyn Resolver>, proxy: Option<&ProxyConfig>) -> Self {
+        Connecter {
+            resolver: resolver.clone(),
+            proxy: proxy.cloned(),
+            deadline: Instant::now() + timeout,
+            timed_out: false,
+            failed: Vec::new(),
+            proxy_failed: Vec::new(),
+            resolve_error: None,
+        }
+    }
+
+    /// Connects to the domain itself, on the port of `fallback` and
+    /// with Direct TLS if it says so, when there are no SRV records to
+    /// use
+    async fn fallback(
+        mut self,
+        host: &str,
+        (port, direct_tls): (u16, bool),
+    ) -> Result<(TcpStream, bool, Endpoint), Error> {
+        match self.host(host, port).await? {
+            Some((stream, endpoint)) => Ok((stream, direct_tls, endpoint)),
+            None => Err(self.into_error()),
+        }
+    }
+
+    /// Connects to `host`, an ASCII domain name or IP address, on
+    /// `port`.
+    ///
+    /// Returns `None` if every address failed or the deadline passed,
+    /// the details of which are kept for `into_error()`.
+    async fn host(
+        &mut self,
+        host: &str,
+        port: u16,
+    ) -> Result<Option<(TcpStream, Endpoint)>, Error> {
+        if let Some(proxy) = self.proxy.clone() {
+            return self.proxied(&proxy, host, port).await;
+        }
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let (v6, v4) = if ip.is_ipv6() {
+                (vec![ip], vec![])
+            } else {
+                (vec![], vec![ip])
+            };
+            let v6 = async move { Ok::<_, ResolveError>(v6) };
+            let v4 = async move { Ok::<_, ResolveError>(v4) };
+            return Ok(self.race(v6, v4, port).await);
+        }
+
+        let resolver = self.resolver.clone();
+        let v6 = async {
+            let answer = resolver.ipv6(host).await?;
+            Ok::<Vec<IpAddr>, ResolveError>(answer.records.into_iter().map(IpAddr::V6).collect())
+        };
+        let v4 = async {
+            let answer = resolver.ipv4(host).await?;
+            Ok::<Vec<IpAddr>, ResolveError>(answer.records.into_iter().map(IpAddr::V4).collect())
+        };
+        Ok(self.race(v6, v4, port).await)
     }
 
-    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(ConnecterError::Resolve)?;
+    /// Connects to `host` on `port` through `proxy`, trying each of
+    /// its addresses in turn unless the proxy resolves it.
+    async fn proxied(
+        &mut self,
+        proxy: &ProxyConfig,
+        host: &str,
+        port: u16,
+    ) -> Result<Option<(TcpStream, Endpoint)>, Error> {
+        let targets = if proxy.resolves_remotely() || host.parse::<IpAddr>().is_ok() {
+            vec![host.to_owned()]
+        } else {
+            let resolver = self.resolver.clone();
+            let lookup = async {
+                let v6 = resolver.ipv6(host).await?;
+                let v4 = resolver.ipv4(host).await?;
+                let v6 = v6.records.into_iter().map(|ip| ip.to_string());
+                let v4 = v4.records.into_iter().map(|ip| ip.to_string());
+                Ok::<Vec<String>, ResolveError>(v6.chain(v4).collect())
+            };
+            match timeout_at(self.deadline, lookup).await {
+                Ok(Ok(targets)) => targets,
+                Ok(Err(e)) => {
+                    self.resolve_error = Some(e);
+                    return Ok(None);
+                }
+                Err(_) => {
+                    self.timed_out = true;
+                    return Ok(None);
+                }
+            }
+        };
 
-    let srv_domain = format!("{}.{}.", srv, ascii_domain)
-        .into_name()
-        .map_err(ConnecterError::Dns)?;
-    let srv_records = resolver.srv_lookup(srv_domain).await.ok();
+        for target in targets {
+            match timeout_at(self.deadline, proxy.connect(&target, port)).await {
+                Ok(Ok(stream)) => {
+                    let endpoint = Endpoint { host: target, port };
+                    return Ok(Some((stream, endpoint)));
+                }
+                Ok(Err(e)) => self
+                    .proxy_failed
+                    .push((format!("{} port {}", target, port), e)),
+                Err(_) => {
+                    self.timed_out = true;
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Races connection attempts to the addresses resolved by `v6`
+    /// and `v4`, starting as soon as the first ones are known and
+    /// alternating between address families.
+    async fn race<F6, F4>(&mut self, v6: F6, v4: F4, port: u16) -> Option<(TcpStream, Endpoint)>
+    where
+        F6: Future<Output = Result<Vec<IpAddr>, ResolveError>>,
+        F4: Future<Output = Result<Vec<IpAddr>, ResolveError>>,
+    {
+        let v6 = v6.fuse();
+        let v4 = v4.fuse();
+        tokio::pin!(v6, v4);
+        let mut v6_pending = true;
+        let mut v4_pending = true;
+        let mut v6_addrs = VecDeque::new();
+        let mut v4_addrs = VecDeque::new();
+        let mut prefer_v6 = true;
+
+        let mut attempts = FuturesUnordered::new();
+        let mut last_attempt: Option<Instant> = None;
+        let next_attempt = sleep_until(self.deadline);
+        tokio::pin!(next_attempt);
+        let mut next_attempt_armed = false;
+        let deadline = sleep_until(self.deadline);
+        tokio::pin!(deadline);
+
+        loop {
+            if !v6_pending
+                && !v4_pending
+                && v6_addrs.is_empty()
+                && v4_addrs.is_empty()
+                && attempts.is_empty()
+            {
+                return None;
+            }
 
-    match srv_records {
-        Some(lookup) => {
-            // TODO: sort lookup records by priority/weight
-            for srv in lookup.iter() {
-                match connect_to_host(&srv.target().to_ascii(), srv.port()).await {
-                    Ok(stream) => return Ok(stream),
-                    Err(_) => {}
+            // When the next attempt may start without waiting for the
+            // previous one to fail
+            let earliest = match last_attempt {
+                Some(last) => (last + CONNECTION_ATTEMPT_DELAY).max(Instant::now()),
+                None => Instant::now(),
+            };
+
+            tokio::select! {
+                result = &mut v6, if v6_pending => {
+                    v6_pending = false;
+                    match result {
+                        Ok(addrs) => v6_addrs.extend(addrs),
+                        Err(e) => self.resolve_error = Some(e),
+                    }
+                    next_attempt.as_mut().reset(earliest);
+                    next_attempt_armed = true;
+                }
+                result = &mut v4, if v4_pending => {
+                    v4_pending = false;
+                    match result {
+                        Ok(addrs) => v4_addrs.extend(addrs),
+                        Err(e) => self.resolve_error = Some(e),
+                    }
+                    if !next_attempt_armed {
+                        let start = if v6_pending && last_attempt.is_none() {
+                            // Give IPv6 a chance to come first
+                            Instant::now() + RESOLUTION_DELAY
+                        } else {
+                            earliest
+                        };
+                        next_attempt.as_mut().reset(start);
+                        next_attempt_armed = true;
+                    }
+                }
+                () = &mut next_attempt, if next_attempt_armed => {
+                    next_attempt_armed = false;
+                    let addr = if prefer_v6 {
+                        v6_addrs.pop_front().or_else(|| v4_addrs.pop_front())
+                    } else {
+                        v4_addrs.pop_front().or_else(|| v6_addrs.pop_front())
+                    };
+                    if let Some(ip) = addr {
+                        prefer_v6 = !ip.is_ipv6();
+                        let addr = SocketAddr::new(ip, port);
+                        attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+                        let now = Instant::now();
+                        last_attempt = Some(now);
+                        next_attempt.as_mut().reset(now + CONNECTION_ATTEMPT_DELAY);
+                        next_attempt_armed = true;
+                    }
+                }
+                Some((addr, result)) = attempts.next(), if !attempts.is_empty() => {
+                    match result {
+                        Ok(stream) => {
+                            let endpoint = Endpoint {
+                                host: addr.ip().to_string(),
+                                port,
+                            };
+                            return Some((stream, endpoint));
+                        }
+                        Err(e) => {
+                            self.failed.push((addr, e));
+                            // No need to wait any longer for the next one
+                            next_attempt.as_mut().reset(Instant::now());
+                            next_attempt_armed = true;
+                        }
+                    }
+                }
+                () = &mut deadline => {
+                    self.timed_out = true;
+                    return None;
                 }
             }
-            Err(Error::Disconnected)
         }
-        None => {
-            // SRV lookup error, retry with hostname
-            connect_to_host(domain, fallback_port).await
+    }
+
+    /// Error summing up why no connection could be established
+    fn into_error(self) -> Error {
+        let error = if self.timed_out {
+            ConnecterError::Timeout(self.failed)
+        } else if !self.proxy_failed.is_empty() {
+            ConnecterError::Proxy(self.proxy_failed)
+        } else if self.failed.is_empty() {
+            match self.resolve_error {
+                Some(e) => ConnecterError::Resolve(e),
+                None => ConnecterError::AllFailed(self.failed),
+            }
+        } else {
+            ConnecterError::AllFailed(self.failed)
+        };
+        error.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::{default_resolver, SrvRecord, StaticResolver};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_srv_priority() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let records = vec![(10, 0, "c"), (0, 5, "a"), (5, 1, "b"), (10, 0, "d")];
+        let ordered = order_srv(records, &mut rng);
+        assert_eq!(&ordered[..2], &["a", "b"]);
+        assert_eq!(ordered.len(), 4);
+    }
+
+    #[test]
+    fn test_srv_weight() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let records = vec![(0, 0, "zero"), (0, 1, "light"), (0, 98, "heavy")];
+            if order_srv(records, &mut rng)[0] == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert!(heavy_first > 900);
+    }
+
+    #[tokio::test]
+    async fn test_connect_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let resolver = default_resolver();
+        let timeout = Duration::from_secs(5);
+        connect_to_host("127.0.0.1", port, timeout, &resolver, None)
+            .await
+            .unwrap();
+
+        drop(listener);
+        match connect_to_host("127.0.0.1", port, timeout, &resolver, None).await {
+            Err(Error::Connection(ConnecterError::AllFailed(failed))) => {
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0.port(), port);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_srv() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let unavailable = SrvRecord {
+            priority: 0,
+            weight: 0,
+            port: 5223,
+            target: String::from("."),
+        };
+        let record = SrvRecord {
+            priority: 0,
+            weight: 0,
+            port,
+            target: String::from("xmpp.capulet.example."),
+        };
+        let resolver: Arc<dyn Resolver> = Arc::new(
+            StaticResolver::new()
+                .with_srv(
+                    "_xmpps-client._tcp.capulet.example",
+                    vec![unavailable.clone()],
+                )
+                .with_srv("_xmpp-client._tcp.capulet.example", vec![record])
+                .with_ipv4("xmpp.capulet.example", vec!["127.0.0.1".parse().unwrap()]),
+        );
+        let services = [("_xmpp-client._tcp", false), ("_xmpps-client._tcp", true)];
+        let timeout = Duration::from_secs(5);
+        let (_, direct_tls, endpoint) = connect_with_srv(
+            "capulet.example",
+            &services,
+            (5222, false),
+            timeout,
+            &resolver,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!direct_tls);
+        // The address of the target, not its name
+        let expected = Endpoint {
+            host: String::from("127.0.0.1"),
+            port,
+        };
+        assert_eq!(endpoint, expected);
+
+        // Neither SRV nor address records
+        match connect_with_srv(
+            "montague.example",
+            &services,
+            (5222, false),
+            timeout,
+            &resolver,
+            None,
+        )
+        .await
+        {
+            Err(Error::Connection(ConnecterError::AllFailed(failed))) => {
+                assert!(failed.is_empty())
+            }
+            _ => panic!(),
+        }
+
+        // The domain says it has no XMPP service
+        let resolver: Arc<dyn Resolver> = Arc::new(
+            StaticResolver::new()
+                .with_srv(
+                    "_xmpps-client._tcp.montague.example",
+                    vec![unavailable.clone()],
+                )
+                .with_srv("_xmpp-client._tcp.montague.example", vec![unavailable])
+                .with_ipv4("montague.example", vec!["127.0.0.1".parse().unwrap()]),
+        );
+        match connect_with_srv(
+            "montague.example",
+            &services,
+            (port, false),
+            timeout,
+            &resolver,
+            None,
+        )
+        .await
+        {
+            Err(Error::Connection(ConnecterError::Unavailable)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyConfig::Http {
+            host: String::from("127.0.0.1"),
+            port: listener.local_addr().unwrap().port(),
+            credentials: None,
+        };
+        drop(listener);
+
+        // The proxy is given the domain, without resolving it first
+        let timeout = Duration::from_secs(5);
+        let resolver = default_resolver();
+        match connect_to_host("capulet.example", 5222, timeout, &resolver, Some(&proxy)).await {
+            Err(Error::Connection(ConnecterError::Proxy(failed))) => {
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, "capulet.example port 5222");
+                match failed[0].1 {
+                    ProxyError::Io(_) => (),
+                    _ => panic!(),
+                }
+            }
+            _ => panic!(),
         }
     }
 }
@@ -0,0 +1,282 @@
+//! Matching of responses to the requests sent with `send_iq()`
+
+use futures::Future;
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{BareJid, Element, Error as ParsersError, Jid};
+
+use crate::{Error, IqError, ProtocolError};
+
+/// Time given to the recipient of a request to answer, unless
+/// configured otherwise
+pub const DEFAULT_IQ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Payload of the result, or why there is none
+pub type IqResponse = Result<Option<Element>, IqError>;
+
+/// Parses the payload of a result as the response to a request
+pub fn parse_response<R: TryFrom<Element, Error = ParsersError>>(
+    payload: Option<Element>,
+) -> Result<Option<R>, IqError> {
+    payload
+        .map(R::try_from)
+        .transpose()
+        .map_err(IqError::InvalidResponse)
+}
+
+/// A request waiting for its response
+struct Pending {
+    /// Recipient of the request, which has to be the sender of the
+    /// response
+    to: Option<Jid>,
+    sender: oneshot::Sender<IqResponse>,
+}
+
+/// Requests waiting for their response
+pub struct IqTracker {
+    timeout: Duration,
+    /// Random part of the ids, so that other entities can't guess them
+    prefix: u64,
+    /// Number of requests sent, making the ids unique
+    count: u64,
+    pending: HashMap<String, Pending>,
+}
+
+impl IqTracker {
+    /// Tracks requests, giving each `timeout` to be answered
+    pub fn new(timeout: Duration) -> Self {
+        IqTracker {
+            timeout,
+            prefix: rand::random(),
+            count: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Gives `iq` a unique id, and returns the future of its response
+    pub fn request(&mut self, iq: &mut Iq) -> impl Future<Output = IqResponse> {
+        // Forget the requests whose future got dropped or timed out
+        self.pending
+            .retain(|_, pending| !pending.sender.is_closed());
+
+        self.count += 1;
+        iq.id = format!("{:016x}-{}", self.prefix, self.count);
+        let (sender, receiver) = oneshot::channel();
+        let pending = Pending {
+            to: iq.to.clone(),
+            sender,
+        };
+        self.pending.insert(iq.id.clone(), pending);
+
+        let duration = self.timeout;
+        async move {
+            match timeout(duration, receiver).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(_)) => Err(IqError::Disconnected),
+                Err(_) => Err(IqError::Timeout),
+            }
+        }
+    }
+
+    /// The request with this id couldn't be sent
+    pub fn failed(&mut self, id: &str, error: Error) {
+        if let Some(pending) = self.pending.remove(id) {
+            let _ = pending.sender.send(Err(IqError::Send(error)));
+        }
+    }
+
+    /// Hands a response to its request, with `own` being the JID this
+    /// connection is bound to
+    ///
+    /// Returns the stanza back if it isn't the response to any pending
+    /// request, including when it comes from another entity than the
+    /// one the request was sent to.
+    pub fn response(&mut self, stanza: Element, own: &Jid) -> Option<Element> {
+        if stanza.name() != "iq" {
+            return Some(stanza);
+        }
+        match stanza.attr("type") {
+            Some("result") | Some("error") => (),
+            _ => return Some(stanza),
+        }
+        let from = match stanza.attr("from").map(str::parse::<Jid>) {
+            Some(Ok(from)) => Some(from),
+            Some(Err(_)) => return Some(stanza),
+            None => None,
+        };
+        let pending = match stanza.attr("id").and_then(|id| self.pending.get(id)) {
+            Some(pending) => pending,
+            None => return Some(stanza),
+        };
+        if !comes_from(&pending.to, &from, own) {
+            warn!(
+                "Ignoring a response from {:?} to a request sent to {:?}",
+                from, pending.to
+            );
+            return Some(stanza);
+        }
+
+        let pending = self.pending.remove(stanza.attr("id").unwrap()).unwrap();
+        let response = match Iq::try_from(stanza) {
+            Ok(Iq {
+                payload: IqType::Result(payload),
+                ..
+            }) => Ok(payload),
+            Ok(Iq {
+                payload: IqType::Error(error),
+                ..
+            }) => Err(IqError::Stanza(error)),
+            // Checked above, but the parser has the last word
+            Ok(_) => Err(IqError::Protocol(ProtocolError::InvalidIqResponse)),
+            Err(e) => Err(IqError::InvalidResponse(e)),
+        };
+        let _ = pending.sender.send(response);
+        None
+    }
+
+    /// Fails all pending requests, as their response won't come once
+    /// the connection is gone for good, or on a new session which
+    /// didn't resume the previous one
+    pub fn disconnected(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Whether a response from `from` can answer a request sent to `to`
+///
+/// The server answers on behalf of our own account, with or without
+/// a from.
+fn comes_from(to: &Option<Jid>, from: &Option<Jid>, own: &Jid) -> bool {
+    if from == to {
+        return true;
+    }
+    let own_bare = Jid::Bare(BareJid::from(own.clone()));
+    let to_account = match to {
+        None => true,
+        Some(to) => *to == own_bare,
+    };
+    if !to_account {
+        return false;
+    }
+    match from {
+        None => true,
+        Some(from) => *from == own_bare || from == own,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::ping::Ping;
+    use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+
+    fn jid(jid: &str) -> Jid {
+        jid.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_result() {
+        let own = jid("juliet@capulet.example/balcony");
+        let mut tracker = IqTracker::new(DEFAULT_IQ_TIMEOUT);
+        let mut first = Iq::from_get("", Ping).with_to(jid("capulet.example"));
+        let mut second = first.clone();
+        let first_response = tracker.request(&mut first);
+        let second_response = tracker.request(&mut second);
+        assert_ne!(first.id, second.id);
+
+        // Not from the server we asked
+        let spoofed: Element = Iq::empty_result(jid("montague.example"), first.id.clone())
+            .with_from(jid("montague.example"))
+            .into();
+        assert!(tracker.response(spoofed, &own).is_some());
+
+        let result = Iq::empty_result(own.clone(), first.id.clone())
+            .with_from(jid("capulet.example"))
+            .into();
+        assert!(tracker.response(result, &own).is_none());
+        assert_eq!(first_response.await.unwrap(), None);
+
+        let error = StanzaError::new(
+            ErrorType::Cancel,
+            DefinedCondition::ServiceUnavailable,
+            "en",
+            "No ping here",
+        );
+        let error = Iq::from_error(second.id.clone(), error)
+            .with_from(jid("capulet.example"))
+            .into();
+        assert!(tracker.response(error, &own).is_none());
+        match second_response.await {
+            Err(IqError::Stanza(error)) => {
+                assert_eq!(
+                    error.defined_condition,
+                    DefinedCondition::ServiceUnavailable
+                )
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_own_account() {
+        let own = jid("juliet@capulet.example/balcony");
+        let mut tracker = IqTracker::new(DEFAULT_IQ_TIMEOUT);
+        let mut iq = Iq::from_get("", Ping);
+        let response = tracker.request(&mut iq);
+        let result = Iq::empty_result(own.clone(), iq.id.clone())
+            .with_from(jid("juliet@capulet.example"))
+            .into();
+        assert!(tracker.response(result, &own).is_none());
+        assert_eq!(response.await.unwrap(), None);
+
+        let mut iq = Iq::from_get("", Ping);
+        let response = tracker.request(&mut iq);
+        let result = Iq::empty_result(own.clone(), iq.id.clone()).into();
+        assert!(tracker.response(result, &own).is_none());
+        assert_eq!(response.await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        use xmpp_parsers::iq::{IqGetPayload, NoPayload};
+        use xmpp_parsers::version::VersionQuery;
+
+        let elem: Element =
+            "<query xmlns='jabber:iq:version'><name>xmpp-rs</name><version>0.3.0</version></query>"
+                .parse()
+                .unwrap();
+        let result: Option<<VersionQuery as IqGetPayload>::Response> =
+            parse_response(Some(elem.clone())).unwrap();
+        assert_eq!(result.unwrap().name, "xmpp-rs");
+
+        assert!(parse_response::<<Ping as IqGetPayload>::Response>(None)
+            .unwrap()
+            .is_none());
+        match parse_response::<NoPayload>(Some(elem)) {
+            Err(IqError::InvalidResponse(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_and_disconnect() {
+        let mut tracker = IqTracker::new(Duration::from_secs(10));
+        let mut iq = Iq::from_get("", Ping).with_to(jid("capulet.example"));
+        match tracker.request(&mut iq).await {
+            Err(IqError::Timeout) => (),
+            _ => panic!(),
+        }
+
+        let response = tracker.request(&mut iq);
+        tracker.disconnected();
+        match response.await {
+            Err(IqError::Disconnected) => (),
+            _ => panic!(),
+        }
+    }
+}
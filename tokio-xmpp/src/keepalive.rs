@@ -0,0 +1,247 @@
+//! Keepalive and dead peer detection on idle connections
+
+use futures::task::Poll;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::Context;
+use std::time::Duration;
+use tokio::time::{sleep, Instant, Sleep};
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::ping::Ping;
+use xmpp_parsers::{Element, Jid};
+
+use crate::xmpp_codec::Packet;
+use crate::Error;
+
+/// What to send to the server on an idle connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeepaliveProbe {
+    /// A single space between stanzas, which keeps NAT mappings and
+    /// proxies alive but which the server doesn't answer.
+    ///
+    /// It doesn't detect dead peers: writing to a connection which
+    /// silently died succeeds until the TCP stack gives up on it.
+    Whitespace,
+    /// An XEP-0199 ping, which the server answers, the connection
+    /// being considered dead without an answer in time
+    Ping,
+}
+
+/// Keepalive configuration
+#[derive(Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// Time without any traffic after which to send a probe
+    pub interval: Duration,
+    /// Time given to the server to send anything after a ping, after
+    /// which the connection is considered dead; unused with
+    /// `KeepaliveProbe::Whitespace`
+    pub timeout: Duration,
+    /// What to send
+    pub probe: KeepaliveProbe,
+}
+
+impl Default for KeepaliveConfig {
+    /// Pings after a minute of inactivity, waiting 30 seconds for
+    /// the answer
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+            probe: KeepaliveProbe::Ping,
+        }
+    }
+}
+
+/// Keepalive state of a connection
+pub struct Keepalive {
+    config: KeepaliveConfig,
+    /// Recipient of the pings
+    server: Jid,
+    /// Sender of the pings, for components
+    from: Option<Jid>,
+    timer: Pin<Box<Sleep>>,
+    /// Random part of the ping ids, so that they can't be guessed
+    prefix: u64,
+    /// Number of pings sent, to give them unique ids
+    pings: u64,
+    /// Id of the last ping, whose answer is kept from the application
+    pending: Option<String>,
+    /// Whether nothing was received since the last ping
+    awaiting: bool,
+}
+
+impl Keepalive {
+    /// Starts counting idle time on a new connection to `server`
+    pub fn new(config: KeepaliveConfig, server: Jid) -> Self {
+        let timer = Box::pin(sleep(config.interval));
+        Keepalive {
+            config,
+            server,
+            from: None,
+            timer,
+            prefix: rand::random(),
+            pings: 0,
+            pending: None,
+            awaiting: false,
+        }
+    }
+
+    /// Sends the pings from this address, as components have to
+    pub fn with_from(mut self, from: Jid) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    fn reset(&mut self, duration: Duration) {
+        self.timer.as_mut().reset(Instant::now() + duration);
+    }
+
+    /// Restarts the timer from `poll()`, polling it again so that the
+    /// task gets woken up when it expires
+    fn reset_and_register(&mut self, duration: Duration, cx: &mut Context) {
+        self.reset(duration);
+        let _ = self.timer.as_mut().poll(cx);
+    }
+
+    /// Something was received from the server: the connection is
+    /// alive
+    pub fn received(&mut self) {
+        self.awaiting = false;
+        self.reset(self.config.interval);
+    }
+
+    /// Something was sent to the server, no need to probe for a while
+    pub fn sent(&mut self) {
+        if !self.awaiting {
+            self.reset(self.config.interval);
+        }
+    }
+
+    /// Is this the answer to our ping, to be kept from the
+    /// application?
+    ///
+    /// It has to be a result or an error with the id of the ping,
+    /// coming from the pinged server or from no one.
+    pub fn is_pong(&mut self, stanza: &Element) -> bool {
+        let id = match self.pending {
+            Some(ref id) => id,
+            None => return false,
+        };
+        if stanza.name() != "iq" || stanza.attr("id") != Some(id) {
+            return false;
+        }
+        match stanza.attr("type") {
+            Some("result") | Some("error") => (),
+            _ => return false,
+        }
+        let from_server = match stanza.attr("from") {
+            None => true,
+            Some(from) => Jid::from_str(from).map_or(false, |from| from == self.server),
+        };
+        if from_server {
+            self.pending = None;
+        }
+        from_server
+    }
+
+    /// Polls the idle timer, returning the probe to send once it
+    /// expires, or `Error::KeepaliveTimeout` if the server didn't
+    /// answer the previous ping in time.
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<Result<Packet, Error>> {
+        if self.timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if self.awaiting {
+            return Poll::Ready(Err(Error::KeepaliveTimeout));
+        }
+        let probe = match self.config.probe {
+            KeepaliveProbe::Whitespace => {
+                self.reset_and_register(self.config.interval, cx);
+                Packet::Text(String::from(" "))
+            }
+            KeepaliveProbe::Ping => {
+                self.pings = self.pings.wrapping_add(1);
+                let id = format!("{:016x}-keepalive-{}", self.prefix, self.pings);
+                let mut iq = Iq::from_get(id.clone(), Ping).with_to(self.server.clone());
+                iq.from = self.from.clone();
+                self.pending = Some(id);
+                self.awaiting = true;
+                self.reset_and_register(self.config.timeout, cx);
+                Packet::Stanza(iq.into())
+            }
+        };
+        Poll::Ready(Ok(probe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+
+    fn config(probe: KeepaliveProbe) -> KeepaliveConfig {
+        KeepaliveConfig {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            probe,
+        }
+    }
+
+    async fn next(keepalive: &mut Keepalive) -> Result<Packet, Error> {
+        poll_fn(|cx| keepalive.poll(cx)).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping() {
+        let server = Jid::Bare("example.org".parse().unwrap());
+        let mut keepalive = Keepalive::new(config(KeepaliveProbe::Ping), server);
+        let start = Instant::now();
+        let ping = match next(&mut keepalive).await.unwrap() {
+            Packet::Stanza(ping) => ping,
+            _ => panic!(),
+        };
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        assert_eq!(ping.attr("to"), Some("example.org"));
+        let id = ping.attr("id").unwrap();
+
+        // Neither a request nor an answer from someone else
+        let request = Iq::from_get(id, Ping).with_from(Jid::Bare("example.org".parse().unwrap()));
+        assert!(!keepalive.is_pong(&request.into()));
+        let spoofed = Iq::empty_result(Jid::Bare("example.org".parse().unwrap()), id)
+            .with_from(Jid::Bare("romeo@montague.example".parse().unwrap()));
+        assert!(!keepalive.is_pong(&spoofed.into()));
+
+        let pong = Iq::empty_result(Jid::Bare("example.org".parse().unwrap()), id)
+            .with_from(Jid::Bare("example.org".parse().unwrap()));
+        assert!(keepalive.is_pong(&pong.into()));
+        keepalive.received();
+        next(&mut keepalive).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(120));
+
+        // Sending doesn't count as an answer
+        keepalive.sent();
+        match next(&mut keepalive).await {
+            Err(Error::KeepaliveTimeout) => (),
+            _ => panic!(),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(130));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_whitespace() {
+        let server = Jid::Bare("example.org".parse().unwrap());
+        let mut keepalive = Keepalive::new(config(KeepaliveProbe::Whitespace), server);
+        let start = Instant::now();
+        for i in 1..3 {
+            match next(&mut keepalive).await.unwrap() {
+                Packet::Text(text) => assert_eq!(text, " "),
+                _ => panic!(),
+            }
+            assert_eq!(start.elapsed(), Duration::from_secs(60 * i));
+        }
+        keepalive.sent();
+        next(&mut keepalive).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(180));
+    }
+}
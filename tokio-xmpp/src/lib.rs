@@ -2,19 +2,52 @@
 
 #![deny(unsafe_code, missing_docs, bare_trait_objects)]
 
+#[cfg(feature = "bosh")]
+mod bosh;
 mod starttls;
 mod stream_start;
+mod tls;
+pub use tls::{CertificatePin, ClientCertificate, TlsConfig, TlsPolicy, TlsVersion};
 mod xmpp_codec;
-pub use crate::xmpp_codec::Packet;
+pub use crate::xmpp_codec::{CodecLimits, Packet, XMPPCodec};
 mod event;
 pub use event::Event;
 mod client;
 mod happy_eyeballs;
+mod iq_tracker;
+mod keepalive;
+pub use keepalive::{KeepaliveConfig, KeepaliveProbe};
+mod observer;
+pub use observer::{redact, Direction, PacketObserver, XmlConsole};
+mod proxy;
+pub use proxy::{ProxyConfig, ProxyCredentials};
+mod resolver;
+pub use resolver::{Answer, CachingResolver, Resolver, SrvRecord, StaticResolver, SystemResolver};
 pub mod stream_features;
+#[cfg(feature = "websocket")]
+mod websocket;
 pub mod xmpp_stream;
-pub use client::{async_client::Client as AsyncClient, simple_client::Client as SimpleClient};
+#[cfg(unix)]
+pub use client::connect::UnixConnector;
+pub use client::{
+    async_client::{
+        Client as AsyncClient, Config as AsyncConfig, ServerConfig as AsyncServerConfig,
+    },
+    connect::{Connection, ServerConnector, SrvConnector, TcpConnector},
+    oauth::{OAuthCredentials, OAuthRefresher, OAuthToken},
+    reconnect::{ReconnectPolicy, Timeouts},
+    register::Registration,
+    sasl2::{Config as Sasl2Config, FastToken, FastTokenHook},
+    scram::{ScramHash, ScramKeys, ScramKeysHook},
+    simple_client::Client as SimpleClient,
+};
 mod component;
-pub use crate::component::Component;
+pub use crate::component::{Component, Config as ComponentConfig};
 mod error;
-pub use crate::error::{AuthError, ConnecterError, Error, ParseError, ProtocolError};
-pub use starttls::starttls;
+#[cfg(feature = "bosh")]
+pub use crate::error::BoshError;
+pub use crate::error::{
+    AuthError, ConnecterError, ConnectionPhase, Error, IqError, ParseError, PolicyError,
+    ProtocolError, ProxyError, RegisterError, TlsError,
+};
+pub use starttls::{direct_tls, starttls};
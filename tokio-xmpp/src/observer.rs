@@ -0,0 +1,326 @@
+//! Observation of the packets going over a stream, such as for an XML
+//! console, with credentials redacted
+
+use log::warn;
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use xmpp_parsers::{ns, Element};
+
+use crate::xmpp_codec::{escape, Packet};
+
+/// What replaces secrets in the packets given to observers
+const REDACTED: &str = "[redacted]";
+
+/// Whether a packet was received from or sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Decoded from the stream
+    Received,
+    /// Encoded into the stream
+    Sent,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Received => write!(fmt, "received"),
+            Direction::Sent => write!(fmt, "sent"),
+        }
+    }
+}
+
+/// Gets every packet going over a stream, as given to
+/// `XMPPCodec::with_observer()`
+///
+/// Packets are observed right as they are decoded or encoded, so this
+/// should return quickly.
+pub trait PacketObserver: Send + Sync {
+    /// Called with each packet and when it went over the stream, once
+    /// redacted as by `redact()`
+    fn observe(&self, direction: Direction, timestamp: SystemTime, packet: &Packet);
+}
+
+/// Replaces the credentials in `packet`: SASL and SASL2 payloads,
+/// FAST tokens, component handshakes, and the passwords of in-band
+/// registration
+pub fn redact(packet: &Packet) -> Cow<Packet> {
+    match packet {
+        Packet::Stanza(stanza) if may_hold_secrets(stanza) => {
+            let mut stanza = stanza.clone();
+            redact_element(&mut stanza);
+            Cow::Owned(Packet::Stanza(stanza))
+        }
+        _ => Cow::Borrowed(packet),
+    }
+}
+
+/// Nonzas carrying credentials, or elements which do
+fn may_hold_secrets(stanza: &Element) -> bool {
+    stanza.has_ns(ns::SASL)
+        || stanza.has_ns(ns::SASL2)
+        || stanza.is("handshake", ns::COMPONENT_ACCEPT)
+        || (stanza.name() == "iq" && stanza.has_child("query", ns::REGISTER))
+}
+
+fn redact_texts(elem: &mut Element) {
+    for text in elem.texts_mut() {
+        if !text.trim().is_empty() {
+            *text = REDACTED.to_owned();
+        }
+    }
+}
+
+fn redact_element(elem: &mut Element) {
+    let secret_text = ["auth", "response", "success"]
+        .iter()
+        .any(|name| elem.is(name, ns::SASL))
+        || ["initial-response", "response", "additional-data"]
+            .iter()
+            .any(|name| elem.is(name, ns::SASL2))
+        || elem.is("handshake", ns::COMPONENT_ACCEPT)
+        || elem.is("password", ns::REGISTER);
+    if secret_text {
+        redact_texts(elem);
+    }
+    // Password fields of registration forms
+    if elem.is("field", ns::DATA_FORMS)
+        && (elem.attr("var") == Some("password") || elem.attr("type") == Some("text-private"))
+    {
+        for value in elem.children_mut() {
+            if value.is("value", ns::DATA_FORMS) {
+                redact_texts(value);
+            }
+        }
+    }
+    if elem.is("token", ns::FAST) && elem.attr("token").is_some() {
+        elem.set_attr("token", REDACTED);
+    }
+    for child in elem.children_mut() {
+        redact_element(child);
+    }
+}
+
+/// Serializes `packet` as it would appear on the stream
+pub(crate) fn to_xml(packet: &Packet) -> String {
+    match packet {
+        Packet::StreamStart(attrs) => {
+            let mut xml = String::from("<stream:stream");
+            for (name, value) in attrs {
+                xml.push_str(&format!(" {}=\"{}\"", escape(name), escape(value)));
+            }
+            xml.push('>');
+            xml
+        }
+        Packet::Stanza(stanza) => String::from(stanza),
+        Packet::Text(text) => escape(text),
+        Packet::StreamEnd => String::from("</stream:stream>"),
+    }
+}
+
+/// Formats `time` as in RFC 3339, in UTC and to the millisecond
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Gregorian date of the day `days` after 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Counting from 0000-03-01, in 400 year eras, so that leap days
+    // come last
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Observer writing the packets to a file, each one preceded by a
+/// comment with its time and direction
+///
+/// Writes happen right away and block, this is meant for debugging.
+pub struct XmlConsole {
+    file: Mutex<File>,
+}
+
+impl XmlConsole {
+    /// Appends to the file at `path`, creating it if needed
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(XmlConsole {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl PacketObserver for XmlConsole {
+    fn observe(&self, direction: Direction, timestamp: SystemTime, packet: &Packet) {
+        let entry = format!(
+            "<!-- {} {} -->\n{}\n",
+            format_time(timestamp),
+            direction,
+            to_xml(packet)
+        );
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(entry.as_bytes()) {
+            warn!("Couldn't write to the XML console: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xmpp_codec::XMPPCodec;
+    use bytes::BytesMut;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    /// Redacts `xml`, and checks that it gives `expected`
+    fn assert_redacted(xml: &str, expected: &str) {
+        let packet = Packet::Stanza(xml.parse().unwrap());
+        let expected = Packet::Stanza(expected.parse().unwrap());
+        match redact(&packet) {
+            Cow::Owned(packet) => assert_eq!(packet, expected),
+            Cow::Borrowed(_) => panic!("nothing redacted in {}", xml),
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_redacted(
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>AGp1bGlldABzZWNyZXQ=</auth>",
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>[redacted]</auth>",
+        );
+        assert_redacted(
+            "<authenticate xmlns='urn:xmpp:sasl:2' mechanism='HT-SHA-256-NONE'><initial-response>anVsaWV0</initial-response><user-agent id='d4565fa7'/></authenticate>",
+            "<authenticate xmlns='urn:xmpp:sasl:2' mechanism='HT-SHA-256-NONE'><initial-response>[redacted]</initial-response><user-agent id='d4565fa7'/></authenticate>",
+        );
+        assert_redacted(
+            "<success xmlns='urn:xmpp:sasl:2'><authorization-identifier>juliet@capulet.example</authorization-identifier><token xmlns='urn:xmpp:fast:0' expiry='2026-10-18T00:00:00Z' token='s3cr3t'/></success>",
+            "<success xmlns='urn:xmpp:sasl:2'><authorization-identifier>juliet@capulet.example</authorization-identifier><token xmlns='urn:xmpp:fast:0' expiry='2026-10-18T00:00:00Z' token='[redacted]'/></success>",
+        );
+        assert_redacted(
+            "<handshake xmlns='jabber:component:accept'>aaee83c26aeeafcbabeabfcbcd50df997e0a2a1e</handshake>",
+            "<handshake xmlns='jabber:component:accept'>[redacted]</handshake>",
+        );
+        assert_redacted(
+            "<iq xmlns='jabber:client' type='set' id='reg2'><query xmlns='jabber:iq:register'><username>juliet</username><password>s3cr3t</password></query></iq>",
+            "<iq xmlns='jabber:client' type='set' id='reg2'><query xmlns='jabber:iq:register'><username>juliet</username><password>[redacted]</password></query></iq>",
+        );
+        assert_redacted(
+            "<iq xmlns='jabber:client' type='set' id='reg3'><query xmlns='jabber:iq:register'><x xmlns='jabber:x:data' type='submit'><field var='username'><value>juliet</value></field><field var='password'><value>s3cr3t</value></field><field var='pin' type='text-private'><value>1234</value></field></x></query></iq>",
+            "<iq xmlns='jabber:client' type='set' id='reg3'><query xmlns='jabber:iq:register'><x xmlns='jabber:x:data' type='submit'><field var='username'><value>juliet</value></field><field var='password'><value>[redacted]</value></field><field var='pin' type='text-private'><value>[redacted]</value></field></x></query></iq>",
+        );
+
+        let message = Packet::Stanza(
+            "<message xmlns='jabber:client'><body>secret</body></message>"
+                .parse()
+                .unwrap(),
+        );
+        match redact(&message) {
+            Cow::Borrowed(packet) => assert_eq!(packet, &message),
+            Cow::Owned(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(951_825_599_250);
+        assert_eq!(format_time(time), "2000-02-29T11:59:59.250Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        assert_eq!(format_time(time), "2024-01-01T00:00:00.000Z");
+    }
+
+    /// Keeps what it observes
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(Direction, Packet)>>);
+
+    impl PacketObserver for Recorder {
+        fn observe(&self, direction: Direction, _: SystemTime, packet: &Packet) {
+            self.0.lock().unwrap().push((direction, packet.clone()));
+        }
+    }
+
+    #[test]
+    fn test_codec_observer() {
+        let recorder = Arc::new(Recorder::default());
+        let mut codec = XMPPCodec::new().with_observer(Some(recorder.clone()));
+
+        let mut buf = BytesMut::from("<stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:client'><success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>dj1ybUY5cHFWOFM3c3VBb1pXamE0ZEpSa0ZzS1E9</success>");
+        while codec.decode(&mut buf).unwrap().is_some() {}
+        let auth: Element =
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>AGp1bGlldABzZWNyZXQ=</auth>"
+                .parse()
+                .unwrap();
+        let mut dst = BytesMut::new();
+        codec.encode(Packet::Stanza(auth), &mut dst).unwrap();
+        // The stream itself is left untouched
+        assert!(dst.ends_with(b">AGp1bGlldABzZWNyZXQ=</auth>"));
+
+        let observed = recorder.0.lock().unwrap();
+        assert_eq!(observed.len(), 3);
+        match observed[0] {
+            (Direction::Received, Packet::StreamStart(ref attrs)) => {
+                assert_eq!(attrs["xmlns"], "jabber:client")
+            }
+            _ => panic!(),
+        }
+        match observed[1] {
+            (Direction::Received, Packet::Stanza(ref stanza)) => {
+                assert_eq!(stanza.text(), "[redacted]")
+            }
+            _ => panic!(),
+        }
+        match observed[2] {
+            (Direction::Sent, Packet::Stanza(ref stanza)) => {
+                assert_eq!(stanza.text(), "[redacted]")
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_xml_console() {
+        let path = std::env::temp_dir().join(format!("xml-console-{}.log", rand::random::<u64>()));
+        let console = XmlConsole::create(&path).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        console.observe(Direction::Sent, time, &Packet::Text(String::from(" ")));
+        console.observe(Direction::Received, time, &Packet::StreamEnd);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            log,
+            "<!-- 2024-01-01T00:00:00.000Z sent -->\n \n\
+             <!-- 2024-01-01T00:00:00.000Z received -->\n</stream:stream>\n"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! DNS resolution of the servers to connect to, through the system
+//! resolver, a fixed table of records, or a cache in front of either
+
+use futures::future::BoxFuture;
+use futures::Future;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// How long the absence of records is remembered, as the system
+/// resolver doesn't tell
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+/// SRV record (RFC 2782)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower values are tried first
+    pub priority: u16,
+    /// Relative chance of being tried first among records of the same
+    /// priority
+    pub weight: u16,
+    /// Port of the service on the target
+    pub port: u16,
+    /// Host of the service, `.` meaning it isn't available for this
+    /// domain
+    pub target: String,
+}
+
+/// Records found for a name, along with how long they may be cached
+///
+/// No records at all is a valid answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Answer<T> {
+    /// Records found
+    pub records: Vec<T>,
+    /// Time to live of the records
+    pub ttl: Duration,
+}
+
+/// DNS resolver used to find the server of a domain and its addresses
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// SRV records of `name`, such as `_xmpp-client._tcp.example.org.`
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>>;
+
+    /// AAAA records of `host`
+    fn ipv6<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>>;
+
+    /// A records of `host`
+    fn ipv4<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>>;
+}
+
+/// The resolver connections use unless configured otherwise: the
+/// system one, behind a cache
+pub(crate) fn default_resolver() -> Arc<dyn Resolver> {
+    Arc::new(CachingResolver::new(SystemResolver::new()))
+}
+
+/// Resolver configured like the system one, through trust-dns
+#[derive(Default)]
+pub struct SystemResolver {
+    /// Only set up when first needed
+    resolver: Mutex<Option<TokioAsyncResolver>>,
+}
+
+impl SystemResolver {
+    /// Reads the system configuration on the first lookup
+    pub fn new() -> Self {
+        SystemResolver::default()
+    }
+
+    fn resolver(&self) -> Result<TokioAsyncResolver, ResolveError> {
+        let mut resolver = self.resolver.lock().unwrap();
+        if let Some(ref resolver) = *resolver {
+            return Ok(resolver.clone());
+        }
+        let system = TokioAsyncResolver::tokio_from_system_conf()?;
+        *resolver = Some(system.clone());
+        Ok(system)
+    }
+}
+
+impl fmt::Debug for SystemResolver {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SystemResolver").finish()
+    }
+}
+
+/// Turns what trust-dns found into an answer, "no records" included
+fn answer<T>(
+    lookup: Result<(Vec<T>, std::time::Instant), ResolveError>,
+) -> Result<Answer<T>, ResolveError> {
+    match lookup {
+        Ok((records, valid_until)) => Ok(Answer {
+            records,
+            ttl: valid_until.saturating_duration_since(std::time::Instant::now()),
+        }),
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(Answer {
+                records: Vec::new(),
+                ttl: NEGATIVE_TTL,
+            }),
+            _ => Err(e),
+        },
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>> {
+        Box::pin(async move {
+            let lookup = self.resolver()?.srv_lookup(name).await.map(|lookup| {
+                let records = lookup
+                    .iter()
+                    .map(|srv| SrvRecord {
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                        port: srv.port(),
+                        target: srv.target().to_ascii(),
+                    })
+                    .collect();
+                (records, lookup.valid_until())
+            });
+            answer(lookup)
+        })
+    }
+
+    fn ipv6<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>> {
+        Box::pin(async move {
+            let lookup = self.resolver()?.ipv6_lookup(host).await.map(|lookup| {
+                let records = lookup.iter().cloned().collect();
+                (records, lookup.valid_until())
+            });
+            answer(lookup)
+        })
+    }
+
+    fn ipv4<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>> {
+        Box::pin(async move {
+            let lookup = self.resolver()?.ipv4_lookup(host).await.map(|lookup| {
+                let records = lookup.iter().cloned().collect();
+                (records, lookup.valid_until())
+            });
+            answer(lookup)
+        })
+    }
+}
+
+/// Names are case-insensitive, and looked up as fully qualified
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Resolver answering from a fixed table of records, such as for tests
+///
+/// Names missing from the table have no records. Answers come with a
+/// TTL of zero unless set otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    srv: HashMap<String, Vec<SrvRecord>>,
+    ipv6: HashMap<String, Vec<Ipv6Addr>>,
+    ipv4: HashMap<String, Vec<Ipv4Addr>>,
+    ttl: Duration,
+}
+
+impl StaticResolver {
+    /// Empty table
+    pub fn new() -> Self {
+        StaticResolver::default()
+    }
+
+    /// Adds the SRV records of `name`
+    pub fn with_srv(mut self, name: &str, records: Vec<SrvRecord>) -> Self {
+        self.srv.insert(normalize(name), records);
+        self
+    }
+
+    /// Adds the AAAA records of `host`
+    pub fn with_ipv6(mut self, host: &str, addrs: Vec<Ipv6Addr>) -> Self {
+        self.ipv6.insert(normalize(host), addrs);
+        self
+    }
+
+    /// Adds the A records of `host`
+    pub fn with_ipv4(mut self, host: &str, addrs: Vec<Ipv4Addr>) -> Self {
+        self.ipv4.insert(normalize(host), addrs);
+        self
+    }
+
+    /// Sets the TTL of all answers
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn answer<T: Clone>(&self, table: &HashMap<String, Vec<T>>, name: &str) -> Answer<T> {
+        Answer {
+            records: table.get(&normalize(name)).cloned().unwrap_or_default(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>> {
+        let answer = self.answer(&self.srv, name);
+        Box::pin(async move { Ok(answer) })
+    }
+
+    fn ipv6<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>> {
+        let answer = self.answer(&self.ipv6, host);
+        Box::pin(async move { Ok(answer) })
+    }
+
+    fn ipv4<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>> {
+        let answer = self.answer(&self.ipv4, host);
+        Box::pin(async move { Ok(answer) })
+    }
+}
+
+/// Records of each name along with when they expire
+type Cache<T> = Mutex<HashMap<String, (Instant, Vec<T>)>>;
+
+/// Resolver keeping the answers of another one for as long as their
+/// TTL allows, so that reconnections don't query them again
+///
+/// Errors aren't kept.
+#[derive(Debug, Default)]
+pub struct CachingResolver<R> {
+    inner: R,
+    srv: Cache<SrvRecord>,
+    ipv6: Cache<Ipv6Addr>,
+    ipv4: Cache<Ipv4Addr>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Caches the answers of `inner`
+    pub fn new(inner: R) -> Self {
+        CachingResolver {
+            inner,
+            srv: Mutex::new(HashMap::new()),
+            ipv6: Mutex::new(HashMap::new()),
+            ipv4: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Answers from `cache` if it has unexpired records for `name`, from
+/// the future `query` returns otherwise, keeping its answer
+async fn cached<T: Clone, Q, F>(
+    cache: &Cache<T>,
+    name: &str,
+    query: Q,
+) -> Result<Answer<T>, ResolveError>
+where
+    Q: FnOnce() -> F,
+    F: Future<Output = Result<Answer<T>, ResolveError>>,
+{
+    let key = normalize(name);
+    let now = Instant::now();
+    let hit = cache
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|(expiry, _)| *expiry > now)
+        .map(|(expiry, records)| Answer {
+            records: records.clone(),
+            ttl: *expiry - now,
+        });
+    if let Some(answer) = hit {
+        return Ok(answer);
+    }
+
+    let answer = query().await?;
+    let mut cache = cache.lock().unwrap();
+    if answer.ttl > Duration::from_secs(0) {
+        cache.insert(key, (now + answer.ttl, answer.records.clone()));
+    } else {
+        cache.remove(&key);
+    }
+    Ok(answer)
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>> {
+        Box::pin(cached(&self.srv, name, move || self.inner.srv(name)))
+    }
+
+    fn ipv6<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>> {
+        Box::pin(cached(&self.ipv6, host, move || self.inner.ipv6(host)))
+    }
+
+    fn ipv4<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>> {
+        Box::pin(cached(&self.ipv4, host, move || self.inner.ipv4(host)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the queries that reach a table of records
+    #[derive(Debug)]
+    struct Counting(StaticResolver, AtomicUsize);
+
+    impl Resolver for Counting {
+        fn srv<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<SrvRecord>, ResolveError>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.srv(name)
+        }
+
+        fn ipv6<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<Ipv6Addr>, ResolveError>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.ipv6(host)
+        }
+
+        fn ipv4<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<Ipv4Addr>, ResolveError>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.ipv4(host)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static() {
+        let record = SrvRecord {
+            priority: 0,
+            weight: 5,
+            port: 5222,
+            target: String::from("xmpp.capulet.example."),
+        };
+        let resolver = StaticResolver::new()
+            .with_srv("_xmpp-client._tcp.capulet.example", vec![record.clone()])
+            .with_ipv4("xmpp.capulet.example", vec![Ipv4Addr::new(192, 0, 2, 1)]);
+
+        let answer = resolver.srv("_xmpp-client._tcp.Capulet.example.").await;
+        assert_eq!(answer.unwrap().records, vec![record]);
+        let answer = resolver.ipv4("xmpp.capulet.example.").await;
+        assert_eq!(answer.unwrap().records, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        let answer = resolver.ipv6("xmpp.capulet.example.").await;
+        assert!(answer.unwrap().records.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache() {
+        let table = StaticResolver::new()
+            .with_ipv6("capulet.example", vec![Ipv6Addr::LOCALHOST])
+            .with_ttl(Duration::from_secs(300));
+        let resolver = CachingResolver::new(Counting(table, AtomicUsize::new(0)));
+
+        let answer = resolver.ipv6("capulet.example").await.unwrap();
+        assert_eq!(answer.records, vec![Ipv6Addr::LOCALHOST]);
+        tokio::time::advance(Duration::from_secs(100)).await;
+        let answer = resolver.ipv6("Capulet.example.").await.unwrap();
+        assert_eq!(answer.records, vec![Ipv6Addr::LOCALHOST]);
+        assert_eq!(answer.ttl, Duration::from_secs(200));
+        assert_eq!(resolver.inner.1.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(Duration::from_secs(200)).await;
+        resolver.ipv6("capulet.example").await.unwrap();
+        assert_eq!(resolver.inner.1.load(Ordering::SeqCst), 2);
+
+        // Other record types are cached on their own
+        resolver.ipv4("capulet.example").await.unwrap();
+        assert_eq!(resolver.inner.1.load(Ordering::SeqCst), 3);
+    }
+}
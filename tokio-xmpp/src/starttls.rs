@@ -6,7 +6,10 @@ use {
     std::sync::Arc,
     tokio_rustls::{
         client::TlsStream,
-        rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+        rustls::{
+            version, Certificate, ClientConfig, Error as RustlsError, OwnedTrustAnchor, PrivateKey,
+            ProtocolVersion, RootCertStore, ServerName, SupportedProtocolVersion, DEFAULT_VERSIONS,
+        },
         TlsConnector,
     },
     webpki_roots,
@@ -14,58 +17,199 @@ use {
 
 #[cfg(feature = "tls-native")]
 use {
-    native_tls::TlsConnector as NativeTlsConnector,
+    native_tls::{Certificate, Identity, Protocol, TlsConnector as NativeTlsConnector},
     tokio_native_tls::{TlsConnector, TlsStream},
 };
 
+use sasl::common::ChannelBinding;
 use tokio::io::{AsyncRead, AsyncWrite};
 use xmpp_parsers::{ns, Element};
 
+use crate::tls::{TlsConfig, TlsVersion};
 use crate::xmpp_codec::Packet;
 use crate::xmpp_stream::XMPPStream;
-use crate::{Error, ProtocolError};
+use crate::{Error, ProtocolError, TlsError};
+
+/// ALPN protocol identifier for Direct TLS client connections (XEP-0368)
+const ALPN_XMPP_CLIENT: &str = "xmpp-client";
 
 #[cfg(feature = "tls-native")]
-async fn get_tls_stream<S: AsyncRead + AsyncWrite + Unpin>(
-    xmpp_stream: XMPPStream<S>,
+pub(crate) async fn get_tls_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    domain: &str,
+    alpn: Option<&str>,
+    config: &TlsConfig,
 ) -> Result<TlsStream<S>, Error> {
-    let domain = &xmpp_stream.jid.clone().domain();
-    let stream = xmpp_stream.into_inner();
-    let tls_stream = TlsConnector::from(NativeTlsConnector::builder().build().unwrap())
-        .connect(&domain, stream)
+    let mut builder = NativeTlsConnector::builder();
+    if let Some(alpn) = alpn {
+        builder.request_alpns(&[alpn]);
+    }
+    for (index, der) in config.root_certificates.iter().enumerate() {
+        let certificate =
+            Certificate::from_der(der).map_err(|_| TlsError::InvalidRootCertificate(index))?;
+        builder.add_root_certificate(certificate);
+    }
+    builder.disable_built_in_roots(config.replace_default_roots);
+    match config.min_version {
+        None => (),
+        Some(TlsVersion::Tls12) => {
+            builder.min_protocol_version(Some(Protocol::Tlsv12));
+        }
+        // native-tls doesn't know about TLS 1.3
+        Some(TlsVersion::Tls13) => return Err(TlsError::UnsupportedVersion.into()),
+    }
+    if let Some(ref client_certificate) = config.client_certificate {
+        // native-tls only takes PEM for PKCS#8 keys
+        let chain: String = client_certificate
+            .chain
+            .iter()
+            .map(|der| pem("CERTIFICATE", der))
+            .collect();
+        let key = pem("PRIVATE KEY", &client_certificate.key);
+        let identity = Identity::from_pkcs8(chain.as_bytes(), key.as_bytes())
+            .map_err(TlsError::InvalidClientCertificate)?;
+        builder.identity(identity);
+    }
+    let tls_stream = TlsConnector::from(builder.build()?)
+        .connect(domain, stream)
         .await?;
+
+    // native-tls only gives the server certificate, not the rest of
+    // the chain, so that only pins on it can match.
+    let peer_certificate = match tls_stream.get_ref().peer_certificate()? {
+        Some(certificate) => Some(certificate.to_der()?),
+        None => None,
+    };
+    config.check_pins(peer_certificate.as_deref())?;
     Ok(tls_stream)
 }
 
+/// Encodes a DER object as PEM
+#[cfg(feature = "tls-native")]
+fn pem(label: &str, der: &[u8]) -> String {
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64::encode(der).as_bytes().chunks(64) {
+        // Base64 is ASCII
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
 #[cfg(feature = "tls-rust")]
-async fn get_tls_stream<S: AsyncRead + AsyncWrite + Unpin>(
-    xmpp_stream: XMPPStream<S>,
+pub(crate) async fn get_tls_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    domain: &str,
+    alpn: Option<&str>,
+    config: &TlsConfig,
 ) -> Result<TlsStream<S>, Error> {
-    let domain = &xmpp_stream.jid.clone().domain();
-    let domain = ServerName::try_from(domain.as_str())?;
-    let stream = xmpp_stream.into_inner();
+    let domain = ServerName::try_from(domain)?;
     let mut root_store = RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-    let config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    let tls_stream = TlsConnector::from(Arc::new(config))
+    if !config.replace_default_roots {
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    }
+    for (index, der) in config.root_certificates.iter().enumerate() {
+        root_store
+            .add(&Certificate(der.clone()))
+            .map_err(|_| TlsError::InvalidRootCertificate(index))?;
+    }
+    let versions: &[&SupportedProtocolVersion] = match config.min_version {
+        Some(TlsVersion::Tls13) => &[&version::TLS13],
+        Some(TlsVersion::Tls12) | None => DEFAULT_VERSIONS,
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(TlsError::Backend)?
+        .with_root_certificates(root_store);
+    let mut rustls_config = match config.client_certificate {
+        Some(ref client_certificate) => builder
+            .with_single_cert(
+                client_certificate
+                    .chain
+                    .iter()
+                    .cloned()
+                    .map(Certificate)
+                    .collect(),
+                PrivateKey(client_certificate.key.clone()),
+            )
+            .map_err(TlsError::InvalidClientCertificate)?,
+        None => builder.with_no_client_auth(),
+    };
+    if let Some(alpn) = alpn {
+        rustls_config.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+    }
+    let tls_stream = TlsConnector::from(Arc::new(rustls_config))
         .connect(domain, stream)
-        .await?;
+        .await
+        .map_err(handshake_error)?;
+
+    let chain = tls_stream.get_ref().1.peer_certificates().unwrap_or(&[]);
+    config.check_pins(chain.iter().map(|certificate| certificate.0.as_slice()))?;
     Ok(tls_stream)
 }
 
+/// Tells certificate verification failures apart from other handshake
+/// errors
+#[cfg(feature = "tls-rust")]
+fn handshake_error(e: std::io::Error) -> Error {
+    let rustls_error = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<RustlsError>())
+        .cloned();
+    match rustls_error {
+        Some(
+            e @ (RustlsError::InvalidCertificateData(_)
+            | RustlsError::InvalidCertificateEncoding
+            | RustlsError::InvalidCertificateSignature
+            | RustlsError::InvalidCertificateSignatureType
+            | RustlsError::UnsupportedNameType),
+        ) => TlsError::CertificateVerification(e).into(),
+        Some(e) => TlsError::Backend(e).into(),
+        None => e.into(),
+    }
+}
+
+/// Channel binding data of a TLS connection, for SCRAM-*-PLUS
+///
+/// Only `tls-exporter` (RFC 9266) is available, which requires TLS 1.3:
+/// rustls doesn't offer `tls-unique`.
+#[cfg(feature = "tls-rust")]
+pub(crate) fn channel_binding<S>(tls_stream: &TlsStream<S>) -> Result<ChannelBinding, Error> {
+    let (_, connection) = tls_stream.get_ref();
+    match connection.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => {
+            let mut data = vec![0; 32];
+            connection.export_keying_material(&mut data, b"EXPORTER-Channel-Binding", None)?;
+            Ok(ChannelBinding::TlsExporter(data))
+        }
+        _ => Ok(ChannelBinding::None),
+    }
+}
+
+/// Channel binding data of a TLS connection, for SCRAM-*-PLUS
+///
+/// native-tls exports neither keying material nor the Finished
+/// messages, and its `tls-server-end-point` data isn't supported by the
+/// sasl mechanisms, so there is none.
+#[cfg(feature = "tls-native")]
+pub(crate) fn channel_binding<S>(_tls_stream: &TlsStream<S>) -> Result<ChannelBinding, Error> {
+    Ok(ChannelBinding::None)
+}
+
 /// Performs `<starttls/>` on an XMPPStream and returns a binary
 /// TlsStream.
 pub async fn starttls<S: AsyncRead + AsyncWrite + Unpin>(
     mut xmpp_stream: XMPPStream<S>,
+    config: &TlsConfig,
 ) -> Result<TlsStream<S>, Error> {
     let nonza = Element::builder("starttls", ns::TLS).build();
     let packet = Packet::Stanza(nonza);
@@ -82,5 +226,16 @@ pub async fn starttls<S: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 
-    get_tls_stream(xmpp_stream).await
+    let domain = xmpp_stream.jid.clone().domain();
+    get_tls_stream(xmpp_stream.into_inner(), &domain, None, config).await
+}
+
+/// Performs the TLS handshake of a Direct TLS connection (XEP-0368),
+/// before any stream header, and returns a binary TlsStream.
+pub async fn direct_tls<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    domain: &str,
+    config: &TlsConfig,
+) -> Result<TlsStream<S>, Error> {
+    get_tls_stream(stream, domain, Some(ALPN_XMPP_CLIENT), config).await
 }
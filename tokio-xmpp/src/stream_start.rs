@@ -1,9 +1,11 @@
 use futures::{sink::SinkExt, stream::StreamExt};
+use std::convert::TryFrom;
 use std::marker::Unpin;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::Framed;
-use xmpp_parsers::{ns, Element, Jid};
+use xmpp_parsers::{ns, Jid};
 
+use crate::stream_features::StreamFeatures;
 use crate::xmpp_codec::{Packet, XMPPCodec};
 use crate::xmpp_stream::XMPPStream;
 use crate::{Error, ProtocolError};
@@ -52,9 +54,13 @@ pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
         loop {
             match stream.next().await {
                 Some(Ok(Packet::Stanza(stanza))) if stanza.is("features", ns::STREAM) => {
-                    stream_features = stanza;
+                    stream_features =
+                        StreamFeatures::try_from(stanza).map_err(ProtocolError::Parsers)?;
                     break;
                 }
+                Some(Ok(Packet::Stanza(stanza))) if stanza.is("error", ns::STREAM) => {
+                    return Err(Error::from_stream_error(stanza));
+                }
                 Some(Ok(_)) => {}
                 Some(Err(e)) => return Err(e.into()),
                 None => return Err(Error::Disconnected),
@@ -62,14 +68,7 @@ pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
         }
         XMPPStream::new(jid, stream, ns, stream_id, stream_features)
     } else {
-        // FIXME: huge hack, shouldn’t be an element!
-        XMPPStream::new(
-            jid,
-            stream,
-            ns,
-            stream_id.clone(),
-            Element::builder(stream_id, ns::STREAM).build(),
-        )
+        XMPPStream::new(jid, stream, ns, stream_id, StreamFeatures::default())
     };
     Ok(stream)
 }
@@ -0,0 +1,246 @@
+//! TLS configuration: when to use TLS, trusted roots, certificate
+//! pinning and client certificates
+
+use sha2::{Digest, Sha256};
+
+use crate::error::TlsError;
+
+/// Configuration of the TLS connections to a server
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// Additional trusted root certificates, DER-encoded
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Whether to only trust `root_certificates`, instead of adding
+    /// them to the default store
+    pub replace_default_roots: bool,
+    /// When not empty, the server certificate chain has to match one
+    /// of these pins, on top of the usual verification.
+    ///
+    /// With `tls-native`, which doesn't give access to the rest of the
+    /// chain, only the server certificate itself is checked: pins on
+    /// intermediate or root certificates never match there.
+    pub pins: Vec<CertificatePin>,
+    /// Certificate to present to the server
+    pub client_certificate: Option<ClientCertificate>,
+    /// Oldest TLS version to accept, the TLS implementation's default
+    /// otherwise
+    pub min_version: Option<TlsVersion>,
+}
+
+/// Version of the TLS protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3, only available with `tls-rust`
+    Tls13,
+}
+
+/// Whether and how the connection to the server is encrypted
+///
+/// This applies to TCP connections, WebSocket and BOSH ones being
+/// encrypted depending on their URL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsPolicy {
+    /// TLS is required: Direct TLS (XEP-0368) where SRV records or
+    /// `ServerConfig::Manual` say so, `<starttls/>` otherwise, failing
+    /// if the server doesn't offer it
+    RequireStartTls,
+    /// Only Direct TLS, looking up `_xmpps-client._tcp` SRV records and
+    /// falling back to port 5223
+    DirectTls,
+    /// TLS if available: when the server doesn't offer `<starttls/>`,
+    /// the connection is opened again without TLS
+    Opportunistic,
+    /// No TLS at all, for test servers on this host: connections to
+    /// other addresses, or through a proxy, are refused
+    InsecurePlaintext,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        TlsPolicy::RequireStartTls
+    }
+}
+
+/// Pin on a certificate of the server chain, or only on the server
+/// certificate with `tls-native`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// SHA-256 hash of the DER-encoded SubjectPublicKeyInfo, as used
+    /// by RFC 7469
+    Spki([u8; 32]),
+    /// SHA-256 fingerprint of the DER-encoded certificate
+    Fingerprint([u8; 32]),
+}
+
+/// Client certificate, along with its private key
+#[derive(Clone)]
+pub struct ClientCertificate {
+    /// DER-encoded certificate chain, starting with the client
+    /// certificate
+    pub chain: Vec<Vec<u8>>,
+    /// DER-encoded PKCS#8 private key
+    pub key: Vec<u8>,
+}
+
+impl std::fmt::Debug for ClientCertificate {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Keep the private key out of logs
+        fmt.debug_struct("ClientCertificate")
+            .field("chain", &self.chain)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CertificatePin {
+    fn matches(&self, certificate: &[u8]) -> bool {
+        match self {
+            CertificatePin::Spki(hash) => match spki(certificate) {
+                Some(spki) => Sha256::digest(spki).as_slice() == hash,
+                None => false,
+            },
+            CertificatePin::Fingerprint(hash) => Sha256::digest(certificate).as_slice() == hash,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Checks the pins against the DER-encoded certificates presented
+    /// by the server
+    pub(crate) fn check_pins<'a, I: IntoIterator<Item = &'a [u8]>>(
+        &self,
+        chain: I,
+    ) -> Result<(), TlsError> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        let mut empty = true;
+        for certificate in chain {
+            empty = false;
+            if self.pins.iter().any(|pin| pin.matches(certificate)) {
+                return Ok(());
+            }
+        }
+        if empty {
+            Err(TlsError::NoPeerCertificate)
+        } else {
+            Err(TlsError::PinMismatch)
+        }
+    }
+}
+
+/// Reads the DER element at the start of `data`, returning its tag,
+/// its whole encoding, its contents and what follows it
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        // Long form, on at most four bytes
+        let count = (len & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, after) = rest.split_at(count);
+        rest = after;
+        bytes.iter().fold(0, |len, &byte| len << 8 | byte as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = data.len() - rest.len();
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, &data[..header + len], contents, rest))
+}
+
+/// Finds the SubjectPublicKeyInfo of a DER-encoded X.509 certificate
+fn spki(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, _, certificate, _) = der_element(certificate)?;
+    let (_, _, mut tbs, _) = der_element(certificate)?;
+    // Explicitly tagged version, absent for v1 certificates
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.3;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.3;
+    }
+    match der_element(tbs)? {
+        (0x30, spki, _, _) => Some(spki),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut data = vec![tag];
+        if contents.len() < 0x80 {
+            data.push(contents.len() as u8);
+        } else {
+            data.push(0x82);
+            data.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        }
+        data.extend_from_slice(contents);
+        data
+    }
+
+    /// Minimal certificate structure, with the given SPKI
+    fn certificate(spki: &[u8]) -> Vec<u8> {
+        let tbs = [
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(0x30, &[]),
+            der(0x30, b"issuer"),
+            der(0x30, b"validity"),
+            der(0x30, b"subject"),
+            spki.to_vec(),
+            der(0xa3, &[0; 200]),
+        ]
+        .concat();
+        let certificate = [der(0x30, &tbs), der(0x30, &[]), der(0x03, &[0])].concat();
+        der(0x30, &certificate)
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn test_spki() {
+        let key = der(0x30, &[0x42; 150]);
+        let certificate = certificate(&key);
+        assert_eq!(spki(&certificate), Some(key.as_slice()));
+        assert_eq!(spki(&certificate[..40]), None);
+    }
+
+    #[test]
+    fn test_pins() {
+        let key = der(0x30, &[0x42; 10]);
+        let certificate = certificate(&key);
+        let chain = [certificate.as_slice()];
+
+        let mut config = TlsConfig::default();
+        assert!(config.check_pins(chain.iter().copied()).is_ok());
+
+        config.pins = vec![CertificatePin::Spki([0; 32])];
+        match config.check_pins(chain.iter().copied()) {
+            Err(TlsError::PinMismatch) => (),
+            _ => panic!(),
+        }
+        match config.check_pins(std::iter::empty()) {
+            Err(TlsError::NoPeerCertificate) => (),
+            _ => panic!(),
+        }
+
+        config.pins.push(CertificatePin::Spki(sha256(&key)));
+        assert!(config.check_pins(chain.iter().copied()).is_ok());
+
+        config.pins = vec![CertificatePin::Fingerprint(sha256(&certificate))];
+        assert!(config.check_pins(chain.iter().copied()).is_ok());
+    }
+}
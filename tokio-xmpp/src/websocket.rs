@@ -0,0 +1,354 @@
+//! WebSocket transport (RFC 7395)
+//!
+//! The framed `<open/>` and `<close/>` elements and the one element
+//! per message of WebSocket are translated from and to the XML byte
+//! stream that the rest of the crate speaks, so that the usual
+//! `XMPPStream` machinery works on top of it.
+
+use bytes::BytesMut;
+use futures::{ready, Sink, Stream};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+use tokio_tungstenite::{client_async, WebSocketStream as Inner};
+use xmpp_parsers::websocket::{Close, Open};
+use xmpp_parsers::{ns, BareJid, Element};
+
+use crate::error::to_io_error;
+use crate::happy_eyeballs::connect_to_host;
+use crate::proxy::ProxyConfig;
+use crate::resolver::Resolver;
+use crate::starttls::get_tls_stream;
+use crate::tls::TlsConfig;
+use crate::xmpp_codec::{escape, Packet, PacketSplitter};
+use crate::xmpp_stream::AsyncReadAndWrite;
+use crate::{Error, ProtocolError};
+
+/// WebSocket subprotocol for XMPP
+const SUBPROTOCOL: &str = "xmpp";
+
+/// Opens a WebSocket connection to `url`, over TLS for `wss:` URLs,
+/// and agrees on the `xmpp` subprotocol with the server.
+///
+/// Establishing the TCP connection, with the host looked up by
+/// `resolver` and through `proxy` if set, is given up after `timeout`.
+pub async fn connect(
+    url: &str,
+    timeout: Duration,
+    resolver: &Arc<dyn Resolver>,
+    proxy: Option<&ProxyConfig>,
+    tls: &TlsConfig,
+) -> Result<WebSocketStream, Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+
+    let uri = request.uri();
+    let secure = match uri.scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        _ => return Err(WebSocketError::Url(UrlError::UnsupportedUrlScheme).into()),
+    };
+    let host = uri
+        .host()
+        .ok_or(WebSocketError::Url(UrlError::NoHostName))?
+        // IPv6 literals come within brackets
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp_stream = connect_to_host(&host, port, timeout, resolver, proxy).await?;
+    let stream: Box<dyn AsyncReadAndWrite> = if secure {
+        Box::new(get_tls_stream(tcp_stream, &host, Some("http/1.1"), tls).await?)
+    } else {
+        Box::new(tcp_stream)
+    };
+
+    let (inner, response) = client_async(request, stream).await?;
+    match response.headers().get("Sec-WebSocket-Protocol") {
+        Some(protocol) if protocol == SUBPROTOCOL => Ok(WebSocketStream::new(inner)),
+        _ => Err(ProtocolError::NoWebSocketSubprotocol.into()),
+    }
+}
+
+/// XML byte stream carried over a WebSocket connection
+pub struct WebSocketStream {
+    inner: Inner<Box<dyn AsyncReadAndWrite>>,
+    /// Translated incoming data not read yet
+    read_buf: BytesMut,
+    /// Splits outgoing data into elements
+    splitter: PacketSplitter,
+    /// Messages not accepted by the WebSocket yet
+    pending: VecDeque<Message>,
+}
+
+impl WebSocketStream {
+    fn new(inner: Inner<Box<dyn AsyncReadAndWrite>>) -> Self {
+        WebSocketStream {
+            inner,
+            read_buf: BytesMut::new(),
+            splitter: PacketSplitter::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Hands the pending messages over to the WebSocket
+    fn poll_send_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+            let message = self.pending.pop_front().unwrap();
+            Pin::new(&mut self.inner)
+                .start_send(message)
+                .map_err(to_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Translates a message from the server into stream data, the
+/// framing elements becoming the stream header and footer.
+fn incoming(text: String) -> Result<String, Error> {
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with("<open") && !trimmed.starts_with("<close") {
+        return Ok(text);
+    }
+
+    let elem = Element::from_str(trimmed)?;
+    if let Ok(open) = Open::try_from(elem.clone()) {
+        let mut header = format!(
+            "<stream:stream xmlns='{}' xmlns:stream='{}'",
+            ns::JABBER_CLIENT,
+            ns::STREAM
+        );
+        let attrs = [
+            ("id", open.id),
+            ("from", open.from.map(|from| from.to_string())),
+            ("version", open.version),
+            ("xml:lang", open.xml_lang),
+        ];
+        for (name, value) in attrs.iter() {
+            if let Some(value) = value {
+                write!(header, " {}='{}'", name, escape(value)).unwrap();
+            }
+        }
+        header.push('>');
+        Ok(header)
+    } else if Close::try_from(elem).is_ok() {
+        Ok(String::from("</stream:stream>"))
+    } else {
+        Ok(text)
+    }
+}
+
+/// Translates a packet to the server into a message, the stream
+/// header and footer becoming the framing elements.
+fn outgoing(packet: Packet) -> Result<Option<Message>, Error> {
+    let elem = match packet {
+        Packet::StreamStart(attrs) => Open {
+            from: None,
+            to: attrs.get("to").and_then(|to| BareJid::from_str(to).ok()),
+            id: None,
+            version: attrs.get("version").cloned(),
+            xml_lang: attrs.get("xml:lang").cloned(),
+        }
+        .into(),
+        Packet::Stanza(stanza) => stanza,
+        // Whitespace isn't allowed between messages
+        Packet::Text(_) => return Ok(None),
+        Packet::StreamEnd => Close::new().into(),
+    };
+    let mut data = Vec::new();
+    elem.write_to(&mut data)?;
+    let text = String::from_utf8(data).map_err(to_io_error)?;
+    Ok(Some(Message::Text(text)))
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_buf.is_empty() {
+            let text = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Binary(data))) => String::from_utf8(data).map_err(to_io_error)?,
+                // End of file
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Control frames, answered by the WebSocket itself
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            };
+            let data = incoming(text).map_err(to_io_error)?;
+            this.read_buf.extend_from_slice(data.as_bytes());
+        }
+
+        let len = buf.remaining().min(this.read_buf.len());
+        buf.put_slice(&this.read_buf.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+
+        for packet in this.splitter.split(buf).map_err(to_io_error)? {
+            if let Some(message) = outgoing(packet).map_err(to_io_error)? {
+                this.pending.push_back(message);
+            }
+        }
+
+        // The data is buffered either way, the rest is up to the next
+        // write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsyncClient, AsyncConfig, AsyncServerConfig, Event};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::accept_hdr_async;
+    use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+    use xmpp_parsers::iq::Iq;
+    use xmpp_parsers::Jid;
+
+    type ServerStream = Inner<TcpStream>;
+
+    async fn send(ws: &mut ServerStream, text: &str) {
+        ws.send(Message::Text(text.to_owned())).await.unwrap();
+    }
+
+    async fn recv(ws: &mut ServerStream) -> Element {
+        loop {
+            if let Message::Text(text) = ws.next().await.unwrap().unwrap() {
+                return text.parse().unwrap();
+            }
+        }
+    }
+
+    async fn open(ws: &mut ServerStream, id: &str, features: &str) {
+        let open = Open::try_from(recv(ws).await).unwrap();
+        assert_eq!(open.to, Some(BareJid::from_str("example.org").unwrap()));
+        assert!(open.is_version("1.0"));
+        send(ws, &format!("<open xmlns='urn:ietf:params:xml:ns:xmpp-framing' from='example.org' id='{}' version='1.0'/>", id)).await;
+        send(ws, &format!("<stream:features xmlns:stream='http://etherx.jabber.org/streams'>{}</stream:features>", features)).await;
+    }
+
+    async fn serve(listener: TcpListener) {
+        let (tcp_stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_hdr_async(tcp_stream, |_: &Request, mut response: Response| {
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("xmpp"));
+            Ok::<_, ErrorResponse>(response)
+        })
+        .await
+        .unwrap();
+
+        open(&mut ws, "s1", "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms>").await;
+        let auth = recv(&mut ws).await;
+        assert!(auth.is("auth", ns::SASL));
+        send(
+            &mut ws,
+            "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>",
+        )
+        .await;
+
+        // Restarted after authentication
+        open(
+            &mut ws,
+            "s2",
+            "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+        )
+        .await;
+        let iq = Iq::try_from(recv(&mut ws).await).unwrap();
+        send(&mut ws, &format!("<iq xmlns='jabber:client' type='result' id='{}'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>test@example.org/ws</jid></bind></iq>", iq.id)).await;
+
+        let close = recv(&mut ws).await;
+        assert!(close.is("close", ns::WEBSOCKET));
+        send(
+            &mut ws,
+            "<close xmlns='urn:ietf:params:xml:ns:xmpp-framing'/>",
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_incoming() {
+        let header = incoming(String::from(
+            "<open xmlns='urn:ietf:params:xml:ns:xmpp-framing' from='example.org' id='abc' version='1.0'/>",
+        ))
+        .unwrap();
+        assert_eq!(header, "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='abc' from='example.org' version='1.0'>");
+
+        let footer = incoming(String::from(
+            "<close xmlns='urn:ietf:params:xml:ns:xmpp-framing'/>",
+        ))
+        .unwrap();
+        assert_eq!(footer, "</stream:stream>");
+
+        let stanza = "<message xmlns='jabber:client'><body>Hi</body></message>";
+        assert_eq!(incoming(String::from(stanza)).unwrap(), stanza);
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/xmpp-websocket", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve(listener));
+
+        let mut config = AsyncConfig::new(Jid::from_str("test@example.org").unwrap(), "password");
+        config.server = AsyncServerConfig::WebSocket { url };
+        config.allow_plain_without_tls = true;
+        let mut client = AsyncClient::new_with_config(config);
+        match client.next().await {
+            Some(Event::Online { bound_jid, resumed }) => {
+                assert_eq!(bound_jid, Jid::from_str("test@example.org/ws").unwrap());
+                assert!(!resumed);
+            }
+            _ => panic!(),
+        }
+
+        client.send_end().await.unwrap();
+        server.await.unwrap();
+    }
+}
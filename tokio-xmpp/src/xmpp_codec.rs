@@ -1,15 +1,18 @@
 //! XML stream parser for XMPP
 
-use crate::Error;
+use crate::observer::{redact, to_xml, Direction, PacketObserver};
+use crate::{Error, PolicyError};
 use bytes::{BufMut, BytesMut};
-use log::debug;
+use log::{debug, log_enabled, Level};
 use minidom::tree_builder::TreeBuilder;
-use rxml::{Lexer, PushDriver, RawParser};
+use rxml::{Lexer, PushDriver, RawEvent, RawParser};
 use std;
 use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Write;
 use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio_util::codec::{Decoder, Encoder};
 use xmpp_parsers::Element;
 
@@ -26,6 +29,45 @@ pub enum Packet {
     StreamEnd,
 }
 
+/// Bounds on what an incoming stanza may contain, past which decoding
+/// fails with an `Error::Policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecLimits {
+    /// Bytes of a single stanza, including the text between stanzas
+    pub max_stanza_bytes: usize,
+    /// Nesting of elements, the stanza itself being at depth 1
+    pub max_depth: usize,
+    /// Attributes of a single element, namespace declarations included
+    pub max_attributes: usize,
+    /// Bytes of a single text node
+    pub max_text_length: usize,
+}
+
+impl Default for CodecLimits {
+    /// 256 KiB stanzas, with text nodes up to 128 KiB, 64 levels of
+    /// nesting and 64 attributes per element
+    fn default() -> Self {
+        CodecLimits {
+            max_stanza_bytes: 256 * 1024,
+            max_depth: 64,
+            max_attributes: 64,
+            max_text_length: 128 * 1024,
+        }
+    }
+}
+
+impl CodecLimits {
+    /// No limits, for trusted input
+    pub fn unlimited() -> Self {
+        CodecLimits {
+            max_stanza_bytes: usize::MAX,
+            max_depth: usize::MAX,
+            max_attributes: usize::MAX,
+            max_text_length: usize::MAX,
+        }
+    }
+}
+
 /// Stateful encoder/decoder for a bytestream from/to XMPP `Packet`
 pub struct XMPPCodec {
     /// Outgoing
@@ -33,19 +75,92 @@ pub struct XMPPCodec {
     /// Incoming
     driver: PushDriver<RawParser>,
     stanza_builder: TreeBuilder,
+    limits: CodecLimits,
+    /// Bytes read since the end of the last stanza
+    stanza_bytes: usize,
+    /// Attributes read for the current element
+    attributes: usize,
+    /// Bytes of the current text node
+    text_length: usize,
+    observer: Option<Arc<dyn PacketObserver>>,
 }
 
 impl XMPPCodec {
     /// Constructor
     pub fn new() -> Self {
+        Self::with_limits(CodecLimits::default())
+    }
+
+    /// Constructor decoding stanzas up to `limits`
+    pub fn with_limits(limits: CodecLimits) -> Self {
         let stanza_builder = TreeBuilder::new();
         let driver = PushDriver::wrap(Lexer::new(), RawParser::new());
         XMPPCodec {
             ns: None,
             driver,
             stanza_builder,
+            limits,
+            stanza_bytes: 0,
+            attributes: 0,
+            text_length: 0,
+            observer: None,
+        }
+    }
+
+    /// Sets what gets every decoded and encoded packet
+    pub fn with_observer(mut self, observer: Option<Arc<dyn PacketObserver>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Limits of the incoming stanzas
+    pub fn limits(&self) -> CodecLimits {
+        self.limits
+    }
+
+    /// What gets every decoded and encoded packet, if set
+    pub fn observer(&self) -> Option<Arc<dyn PacketObserver>> {
+        self.observer.clone()
+    }
+
+    /// Hands `packet` over to the observer
+    fn observe(&self, direction: Direction, packet: &Packet) {
+        if let Some(ref observer) = self.observer {
+            observer.observe(direction, SystemTime::now(), &redact(packet));
         }
     }
+
+    /// Checks that `token` doesn't take the stanza being read past the
+    /// limits
+    fn check_limits(&mut self, token: &RawEvent) -> Result<(), PolicyError> {
+        let limits = &self.limits;
+        match token {
+            RawEvent::ElementHeadOpen(..) => {
+                self.attributes = 0;
+                self.text_length = 0;
+                // The stream root is at depth 0 then, and stanzas at
+                // depth 1
+                if self.stanza_builder.depth() > limits.max_depth {
+                    return Err(PolicyError::Depth(limits.max_depth));
+                }
+            }
+            RawEvent::Attribute(..) => {
+                self.attributes += 1;
+                if self.attributes > limits.max_attributes {
+                    return Err(PolicyError::Attributes(limits.max_attributes));
+                }
+            }
+            RawEvent::Text(_, text) => {
+                // Long text comes in several tokens
+                self.text_length += text.len();
+                if self.text_length > limits.max_text_length {
+                    return Err(PolicyError::TextLength(limits.max_text_length));
+                }
+            }
+            _ => self.text_length = 0,
+        }
+        Ok(())
+    }
 }
 
 impl Default for XMPPCodec {
@@ -59,19 +174,48 @@ impl Decoder for XMPPCodec {
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let packet = self.decode_packet(buf)?;
+        if let Some(ref packet) = packet {
+            self.observe(Direction::Received, packet);
+        }
+        Ok(packet)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(buf)
+    }
+}
+
+impl XMPPCodec {
+    /// Decodes the next packet, if `buf` completes one
+    fn decode_packet(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, Error> {
         loop {
-            let token = match self.driver.parse(buf, false) {
+            let len = buf.len();
+            let parsed = self.driver.parse(buf, false);
+            self.stanza_bytes += len - buf.len();
+            if self.stanza_bytes > self.limits.max_stanza_bytes {
+                return Err(PolicyError::StanzaSize(self.limits.max_stanza_bytes).into());
+            }
+            let token = match parsed {
                 Ok(Some(token)) => token,
                 Ok(None) => break,
                 Err(rxml::Error::IO(e)) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                 Err(e) => return Err(minidom::Error::from(e).into()),
             };
+            self.check_limits(&token)?;
 
+            let between_stanzas =
+                self.stanza_builder.depth() == 1 && matches!(token, RawEvent::Text(..));
             let had_stream_root = self.stanza_builder.depth() > 0;
             self.stanza_builder.process_event(token)?;
             let has_stream_root = self.stanza_builder.depth() > 0;
+            if between_stanzas {
+                // Whitespace keepalives don't add up to a stanza
+                self.stanza_bytes = 0;
+            }
 
             if !had_stream_root && has_stream_root {
+                self.stanza_bytes = 0;
                 let root = self.stanza_builder.top().unwrap();
                 let attrs =
                     root.attrs()
@@ -93,6 +237,7 @@ impl Decoder for XMPPCodec {
                 self.driver.release_temporaries();
 
                 if let Some(stanza) = self.stanza_builder.unshift_child() {
+                    self.stanza_bytes = 0;
                     return Ok(Some(Packet::Stanza(stanza)));
                 }
             } else if let Some(_) = self.stanza_builder.root.take() {
@@ -104,10 +249,6 @@ impl Decoder for XMPPCodec {
 
         Ok(None)
     }
-
-    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.decode(buf)
-    }
 }
 
 impl Encoder<Packet> for XMPPCodec {
@@ -124,6 +265,11 @@ impl Encoder<Packet> for XMPPCodec {
             io::Error::new(io::ErrorKind::InvalidInput, e)
         }
 
+        if log_enabled!(Level::Debug) {
+            debug!(">> {}", to_xml(&redact(&item)));
+        }
+        self.observe(Direction::Sent, &item);
+
         match item {
             Packet::StreamStart(start_attrs) => {
                 let mut buf = String::new();
@@ -136,27 +282,50 @@ impl Encoder<Packet> for XMPPCodec {
                 }
                 write!(buf, ">\n").map_err(to_io_err)?;
 
-                debug!(">> {:?}", buf);
                 write!(dst, "{}", buf).map_err(to_io_err)
             }
             Packet::Stanza(stanza) => stanza
                 .write_to(&mut WriteBytes::new(dst))
-                .and_then(|_| {
-                    debug!(">> {:?}", dst);
-                    Ok(())
-                })
                 .map_err(|e| to_io_err(format!("{}", e))),
-            Packet::Text(text) => write_text(&text, dst)
-                .and_then(|_| {
-                    debug!(">> {:?}", dst);
-                    Ok(())
-                })
-                .map_err(to_io_err),
+            Packet::Text(text) => write_text(&text, dst).map_err(to_io_err),
             Packet::StreamEnd => write!(dst, "</stream:stream>\n").map_err(to_io_err),
         }
     }
 }
 
+/// Splits the bytes written by an `XMPPCodec` back into packets, for
+/// the transports which frame each of them on its own
+#[cfg(any(feature = "websocket", feature = "bosh"))]
+pub(crate) struct PacketSplitter {
+    codec: XMPPCodec,
+    buf: BytesMut,
+}
+
+#[cfg(any(feature = "websocket", feature = "bosh"))]
+impl PacketSplitter {
+    pub fn new() -> Self {
+        PacketSplitter {
+            // What we send is only limited by the server
+            codec: XMPPCodec::with_limits(CodecLimits::unlimited()),
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Packets completed by the newly written `data`
+    pub fn split(&mut self, data: &[u8]) -> Result<Vec<Packet>, Error> {
+        if data.starts_with(b"<stream:stream") {
+            // Stream restart, the previous header won't ever be closed
+            *self = PacketSplitter::new();
+        }
+        self.buf.extend_from_slice(data);
+        let mut packets = Vec::new();
+        while let Some(packet) = self.codec.decode(&mut self.buf)? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+}
+
 /// Write XML-escaped text string
 pub fn write_text<W: Write>(text: &str, writer: &mut W) -> Result<(), std::fmt::Error> {
     write!(writer, "{}", escape(text))
@@ -362,4 +531,160 @@ mod tests {
             _ => false,
         });
     }
+
+    fn limited_codec(limits: CodecLimits) -> (XMPPCodec, BytesMut) {
+        let mut c = XMPPCodec::with_limits(limits);
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Ok(Some(Packet::StreamStart(_))) => true,
+            _ => false,
+        });
+        (c, b)
+    }
+
+    #[test]
+    fn test_stanza_size_limit() {
+        let limits = CodecLimits {
+            max_stanza_bytes: 1024,
+            ..CodecLimits::default()
+        };
+        let (mut c, mut b) = limited_codec(limits);
+
+        // Whitespace between stanzas doesn't count towards them
+        for _ in 0..100 {
+            b.put_slice(b"<message><body>Foo</body></message>\n");
+        }
+        for _ in 0..100 {
+            let r = c.decode(&mut b);
+            assert!(match r {
+                Ok(Some(Packet::Stanza(_))) => true,
+                _ => false,
+            });
+        }
+
+        // Rejected before the end of the stanza arrives
+        b.put_slice(b"<message><body>");
+        b.put_slice(&[b'A'; 2048][..]);
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::StanzaSize(1024))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let limits = CodecLimits {
+            max_depth: 3,
+            ..CodecLimits::default()
+        };
+        let (mut c, mut b) = limited_codec(limits);
+
+        b.put_slice(b"<message><a><b/></a></message>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Ok(Some(Packet::Stanza(_))) => true,
+            _ => false,
+        });
+
+        b.put_slice(b"<message><a><b><c/></b></a></message>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::Depth(3))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let (mut c, mut b) = limited_codec(CodecLimits::default());
+        for _ in 0..10_000 {
+            b.put_slice(b"<a>");
+        }
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::Depth(64))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_attributes_limit() {
+        let limits = CodecLimits {
+            max_attributes: 2,
+            ..CodecLimits::default()
+        };
+        let mut c = XMPPCodec::with_limits(limits);
+        let mut b = BytesMut::with_capacity(1024);
+        // The stream header has more attributes than that
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::Attributes(2))) => true,
+            _ => false,
+        });
+
+        let limits = CodecLimits {
+            max_attributes: 4,
+            ..CodecLimits::default()
+        };
+        let (mut c, mut b) = limited_codec(limits);
+        b.put_slice(b"<message a='1' b='2' c='3' d='4'/>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Ok(Some(Packet::Stanza(_))) => true,
+            _ => false,
+        });
+
+        b.put_slice(b"<message a='1' b='2' c='3' d='4' e='5'/>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::Attributes(4))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_text_length_limit() {
+        let limits = CodecLimits {
+            max_text_length: 20_000,
+            ..CodecLimits::default()
+        };
+        let (mut c, mut b) = limited_codec(limits);
+
+        // Separate text nodes are limited separately
+        b.put_slice(b"<message><body>");
+        b.put_slice(&[b'A'; 15_000][..]);
+        b.put_slice(b"</body><body>");
+        b.put_slice(&[b'A'; 15_000][..]);
+        b.put_slice(b"</body></message>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Ok(Some(Packet::Stanza(_))) => true,
+            _ => false,
+        });
+
+        b.put_slice(b"<message><body>");
+        b.put_slice(&[b'A'; 25_000][..]);
+        b.put_slice(b"</body></message>");
+        let r = c.decode(&mut b);
+        assert!(match r {
+            Err(Error::Policy(PolicyError::TextLength(20_000))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_policy_stream_error() {
+        use std::convert::TryFrom;
+        use xmpp_parsers::stream_error::{DefinedCondition, StreamError};
+
+        let error = PolicyError::Depth(64).to_stream_error();
+        let elem = Element::from(error);
+        let error = StreamError::try_from(elem).unwrap();
+        assert_eq!(error.defined_condition, DefinedCondition::PolicyViolation);
+        assert_eq!(error.texts["en"], "elements nested deeper than 64");
+    }
 }
@@ -10,9 +10,15 @@ use xmpp_parsers::{Element, Jid};
 
 use crate::stream_features::StreamFeatures;
 use crate::stream_start;
-use crate::xmpp_codec::{Packet, XMPPCodec};
+use crate::xmpp_codec::{CodecLimits, Packet, XMPPCodec};
 use crate::Error;
 
+/// Binary stream over which XMPP can be spoken, to be boxed when the
+/// transport is only known at runtime
+pub trait AsyncReadAndWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadAndWrite for T {}
+
 /// Wraps a binary stream (tokio's `AsyncRead + AsyncWrite`) to decode
 /// and encode XMPP packets.
 ///
@@ -22,7 +28,8 @@ pub struct XMPPStream<S: AsyncRead + AsyncWrite + Unpin> {
     pub jid: Jid,
     /// Codec instance
     pub stream: Framed<S, XMPPCodec>,
-    /// `<stream:features/>` for XMPP version 1.0
+    /// `<stream:features/>` for XMPP version 1.0, empty for other
+    /// streams
     pub stream_features: StreamFeatures,
     /// Root namespace
     ///
@@ -40,12 +47,12 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
         stream: Framed<S, XMPPCodec>,
         ns: String,
         id: String,
-        stream_features: Element,
+        stream_features: StreamFeatures,
     ) -> Self {
         XMPPStream {
             jid,
             stream,
-            stream_features: StreamFeatures::new(stream_features),
+            stream_features,
             ns,
             id,
         }
@@ -53,7 +60,29 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
 
     /// Send a `<stream:stream>` start tag
     pub async fn start(stream: S, jid: Jid, ns: String) -> Result<Self, Error> {
-        let xmpp_stream = Framed::new(stream, XMPPCodec::new());
+        Self::start_with_limits(stream, jid, ns, CodecLimits::default()).await
+    }
+
+    /// Send a `<stream:stream>` start tag, decoding the incoming
+    /// stanzas up to `limits`
+    pub async fn start_with_limits(
+        stream: S,
+        jid: Jid,
+        ns: String,
+        limits: CodecLimits,
+    ) -> Result<Self, Error> {
+        Self::start_with_codec(stream, jid, ns, XMPPCodec::with_limits(limits)).await
+    }
+
+    /// Send a `<stream:stream>` start tag, encoding and decoding
+    /// packets with `codec`, such as one with an observer
+    pub async fn start_with_codec(
+        stream: S,
+        jid: Jid,
+        ns: String,
+        codec: XMPPCodec,
+    ) -> Result<Self, Error> {
+        let xmpp_stream = Framed::new(stream, codec);
         stream_start::start(xmpp_stream, jid, ns).await
     }
 
@@ -62,10 +91,12 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
         self.stream.into_inner()
     }
 
-    /// Re-run `start()`
+    /// Re-run `start()`, keeping the limits and observer
     pub async fn restart(self) -> Result<Self, Error> {
+        let codec = self.stream.codec();
+        let codec = XMPPCodec::with_limits(codec.limits()).with_observer(codec.observer());
         let stream = self.stream.into_inner();
-        Self::start(stream, self.jid, self.ns).await
+        Self::start_with_codec(stream, self.jid, self.ns, codec).await
     }
 }
 
@@ -6,7 +6,8 @@
 
 #![deny(bare_trait_objects)]
 
-use futures::stream::StreamExt;
+use futures::future::{select, Either, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
 use reqwest::{
     header::HeaderMap as ReqwestHeaderMap, Body as ReqwestBody, Client as ReqwestClient,
 };
@@ -16,14 +17,14 @@ use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use tokio::fs::File;
 use tokio_util::codec::{BytesCodec, FramedRead};
-use tokio_xmpp::{AsyncClient as TokioXmppClient, Event as TokioXmppEvent};
+use tokio_xmpp::{AsyncClient as TokioXmppClient, Event as TokioXmppEvent, IqError};
 use xmpp_parsers::{
     bookmarks2::Conference,
     caps::{compute_disco, hash_caps, Caps},
     disco::{DiscoInfoQuery, DiscoInfoResult, Feature, Identity},
     hashes::Algo,
     http_upload::{Header as HttpUploadHeader, SlotRequest, SlotResult},
-    iq::{Iq, IqType},
+    iq::{Iq, IqGetPayload, IqType},
     message::{Body, Message, MessageType},
     muc::{
         user::{MucUser, Status},
@@ -34,7 +35,7 @@ use xmpp_parsers::{
     pubsub::pubsub::{Items, PubSub},
     roster::{Item as RosterItem, Roster},
     stanza_error::{DefinedCondition, ErrorType, StanzaError},
-    BareJid, Element, FullJid, Jid,
+    BareJid, Element, Error as ParsersError, FullJid, Jid,
 };
 #[macro_use]
 extern crate log;
@@ -43,6 +44,16 @@ mod pubsub;
 
 pub type Error = tokio_xmpp::Error;
 
+/// Parsed response to one of our requests, telling what to do with it
+enum Response {
+    Roster(Roster),
+    PubSub(PubSub),
+    Upload(SlotResult, PathBuf),
+}
+
+/// Response to one of our requests, along with its recipient
+type PendingIq = LocalBoxFuture<'static, (Option<Jid>, Result<Option<Response>, IqError>)>;
+
 #[derive(Debug)]
 pub enum ClientType {
     Bot,
@@ -183,7 +194,7 @@ impl ClientBuilder<'_> {
             lang: Rc::new(self.lang),
             disco,
             node,
-            uploads: Vec::new(),
+            pending_iqs: FuturesUnordered::new(),
         };
 
         Ok(agent)
@@ -196,7 +207,7 @@ pub struct Agent {
     lang: Rc<Vec<String>>,
     disco: DiscoInfoResult,
     node: String,
-    uploads: Vec<(String, Jid, PathBuf)>,
+    pending_iqs: FuturesUnordered<PendingIq>,
 }
 
 impl Agent {
@@ -250,12 +261,62 @@ impl Agent {
         presence
     }
 
-    async fn handle_iq(&mut self, iq: Iq) -> Vec<Event> {
+    /// Sends a get request, whose response gets parsed, wrapped with
+    /// `response` and handled by `wait_for_events()`
+    fn send_get<P, F>(&mut self, to: Option<Jid>, payload: P, response: F)
+    where
+        P: IqGetPayload + 'static,
+        P::Response: TryFrom<Element, Error = ParsersError> + 'static,
+        F: FnOnce(P::Response) -> Response + 'static,
+    {
+        let result = self.client.send_get(to.clone(), payload);
+        self.pending_iqs.push(Box::pin(async move {
+            (to, result.await.map(|payload| payload.map(response)))
+        }));
+    }
+
+    async fn handle_iq_response(
+        &mut self,
+        to: Option<Jid>,
+        response: Result<Option<Response>, IqError>,
+    ) -> Vec<Event> {
         let mut events = vec![];
-        let from = iq
-            .from
-            .clone()
-            .unwrap_or_else(|| self.client.bound_jid().unwrap().clone());
+        let payload = match response {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return events,
+            Err(err) => {
+                warn!("Request to {:?} failed: {}", to, err);
+                return events;
+            }
+        };
+        // Requests without a recipient are answered by our own account
+        let from = match to {
+            Some(to) => to,
+            None => match self.client.bound_jid() {
+                Some(jid) => Jid::Bare(BareJid::from(jid.clone())),
+                None => return events,
+            },
+        };
+        match payload {
+            Response::Roster(roster) => {
+                for item in roster.items.into_iter() {
+                    events.push(Event::ContactAdded(item));
+                }
+            }
+            Response::PubSub(pubsub) => {
+                let new_events = pubsub::handle_iq_result(&from, pubsub);
+                events.extend(new_events);
+            }
+            Response::Upload(slot, path) => {
+                let new_events = handle_upload_result(slot, path).await;
+                events.extend(new_events);
+            }
+        }
+        events
+    }
+
+    async fn handle_iq(&mut self, iq: Iq) -> Vec<Event> {
+        let events = vec![];
         if let IqType::Get(payload) = iq.payload {
             if payload.is("query", ns::DISCO_INFO) {
                 let query = DiscoInfoQuery::try_from(payload);
@@ -294,21 +355,6 @@ impl Agent {
                     .into();
                 let _ = self.client.send_stanza(iq).await;
             }
-        } else if let IqType::Result(Some(payload)) = iq.payload {
-            // TODO: move private iqs like this one somewhere else, for
-            // security reasons.
-            if payload.is("query", ns::ROSTER) && iq.from.is_none() {
-                let roster = Roster::try_from(payload).unwrap();
-                for item in roster.items.into_iter() {
-                    events.push(Event::ContactAdded(item));
-                }
-            } else if payload.is("pubsub", ns::PUBSUB) {
-                let new_events = pubsub::handle_iq_result(&from, payload);
-                events.extend(new_events);
-            } else if payload.is("slot", ns::HTTP_UPLOAD) {
-                let new_events = handle_upload_result(&from, iq.id, payload, self).await;
-                events.extend(new_events);
-            }
         } else if let IqType::Set(_) = iq.payload {
             // We MUST answer unhandled set iqs with a service-unavailable error.
             let error = StanzaError::new(
@@ -381,7 +427,24 @@ impl Agent {
     }
 
     pub async fn wait_for_events(&mut self) -> Option<Vec<Event>> {
-        if let Some(event) = self.client.next().await {
+        // Responses to our requests only come while the client is polled
+        let next = if self.pending_iqs.is_empty() {
+            Either::Left(self.client.next().await)
+        } else {
+            match select(self.client.next(), self.pending_iqs.next()).await {
+                Either::Left((event, _)) => Either::Left(event),
+                Either::Right((response, _)) => Either::Right(response),
+            }
+        };
+        let event = match next {
+            Either::Left(event) => event,
+            Either::Right(Some((to, response))) => {
+                return Some(self.handle_iq_response(to, response).await);
+            }
+            Either::Right(None) => unreachable!(),
+        };
+
+        if let Some(event) = event {
             let mut events = Vec::new();
 
             match event {
@@ -390,24 +453,21 @@ impl Agent {
                     let _ = self.client.send_stanza(presence).await;
                     events.push(Event::Online);
                     // TODO: only send this when the ContactList feature is enabled.
-                    let iq = Iq::from_get(
-                        "roster",
-                        Roster {
-                            ver: None,
-                            items: vec![],
-                        },
-                    )
-                    .into();
-                    let _ = self.client.send_stanza(iq).await;
+                    let roster = Roster {
+                        ver: None,
+                        items: vec![],
+                    };
+                    self.send_get(None, roster, Response::Roster);
                     // TODO: only send this when the JoinRooms feature is enabled.
-                    let iq =
-                        Iq::from_get("bookmarks", PubSub::Items(Items::new(ns::BOOKMARKS2))).into();
-                    let _ = self.client.send_stanza(iq).await;
+                    let bookmarks = PubSub::Items(Items::new(ns::BOOKMARKS2));
+                    self.send_get(None, bookmarks, Response::PubSub);
                 }
                 TokioXmppEvent::Online { resumed: true, .. } => {}
                 TokioXmppEvent::Disconnected(_) => {
                     events.push(Event::Disconnected);
                 }
+                TokioXmppEvent::Reconnecting { .. } => {}
+                TokioXmppEvent::Redirected { .. } => {}
                 TokioXmppEvent::Stanza(elem) => {
                     if elem.is("iq", "jabber:client") {
                         let iq = Iq::try_from(elem).unwrap();
@@ -421,8 +481,6 @@ impl Agent {
                         let presence = Presence::try_from(elem).unwrap();
                         let new_events = self.handle_presence(presence).await;
                         events.extend(new_events);
-                    } else if elem.is("error", "http://etherx.jabber.org/streams") {
-                        println!("Received a fatal stream error: {}", String::from(&elem));
                     } else {
                         panic!("Unknown stanza: {}", String::from(&elem));
                     }
@@ -445,55 +503,36 @@ impl Agent {
             content_type: None,
         };
         let to = service.parse::<Jid>().unwrap();
-        let request = Iq::from_get("upload1", slot_request).with_to(to.clone());
-        self.uploads
-            .push((String::from("upload1"), to, path.to_path_buf()));
-        self.client.send_stanza(request.into()).await.unwrap();
+        let path = path.to_path_buf();
+        self.send_get(Some(to), slot_request, move |slot| {
+            Response::Upload(slot, path)
+        });
     }
 }
 
-async fn handle_upload_result(
-    from: &Jid,
-    iqid: String,
-    elem: Element,
-    agent: &mut Agent,
-) -> impl IntoIterator<Item = Event> {
-    let mut res: Option<(usize, PathBuf)> = None;
-
-    for (i, (id, to, filepath)) in agent.uploads.iter().enumerate() {
-        if to == from && id == &iqid {
-            res = Some((i, filepath.to_path_buf()));
-            break;
-        }
+async fn handle_upload_result(slot: SlotResult, file: PathBuf) -> Vec<Event> {
+    let mut headers = ReqwestHeaderMap::new();
+    for header in slot.put.headers {
+        let (attr, val) = match header {
+            HttpUploadHeader::Authorization(val) => ("Authorization", val),
+            HttpUploadHeader::Cookie(val) => ("Cookie", val),
+            HttpUploadHeader::Expires(val) => ("Expires", val),
+        };
+        headers.insert(attr, val.parse().unwrap());
     }
 
-    if let Some((index, file)) = res {
-        agent.uploads.remove(index);
-        let slot = SlotResult::try_from(elem).unwrap();
-
-        let mut headers = ReqwestHeaderMap::new();
-        for header in slot.put.headers {
-            let (attr, val) = match header {
-                HttpUploadHeader::Authorization(val) => ("Authorization", val),
-                HttpUploadHeader::Cookie(val) => ("Cookie", val),
-                HttpUploadHeader::Expires(val) => ("Expires", val),
-            };
-            headers.insert(attr, val.parse().unwrap());
-        }
-
-        let web = ReqwestClient::new();
-        let stream = FramedRead::new(File::open(file).await.unwrap(), BytesCodec::new());
-        let body = ReqwestBody::wrap_stream(stream);
-        let res = web
-            .put(slot.put.url.as_str())
-            .headers(headers)
-            .body(body)
-            .send()
-            .await
-            .unwrap();
-        if res.status() == 201 {
-            return vec![Event::HttpUploadedFile(slot.get.url)];
-        }
+    let web = ReqwestClient::new();
+    let stream = FramedRead::new(File::open(file).await.unwrap(), BytesCodec::new());
+    let body = ReqwestBody::wrap_stream(stream);
+    let res = web
+        .put(slot.put.url.as_str())
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    if res.status() == 201 {
+        return vec![Event::HttpUploadedFile(slot.get.url)];
     }
 
     return vec![];
@@ -6,12 +6,12 @@
 
 use super::Agent;
 use crate::Event;
+use crate::Response;
 use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::{self, Write};
 use xmpp_parsers::{
     avatar::{Data, Metadata},
-    iq::Iq,
     ns,
     pubsub::{
         event::Item,
@@ -41,8 +41,7 @@ pub(crate) async fn handle_metadata_pubsub_event(
                 if info.bytes as u64 == file_length {
                     events.push(Event::AvatarRetrieved(from.clone(), filename));
                 } else {
-                    let iq = download_avatar(from);
-                    let _ = agent.client.send_stanza(iq.into()).await;
+                    agent.send_get(Some(from.clone()), download_avatar(), Response::PubSub);
                 }
             }
         }
@@ -50,17 +49,13 @@ pub(crate) async fn handle_metadata_pubsub_event(
     events
 }
 
-fn download_avatar(from: &Jid) -> Iq {
-    Iq::from_get(
-        "coucou",
-        PubSub::Items(Items {
-            max_items: None,
-            node: NodeName(String::from(ns::AVATAR_DATA)),
-            subid: None,
-            items: Vec::new(),
-        }),
-    )
-    .with_to(from.clone())
+fn download_avatar() -> PubSub {
+    PubSub::Items(Items {
+        max_items: None,
+        node: NodeName(String::from(ns::AVATAR_DATA)),
+        subid: None,
+        items: Vec::new(),
+    })
 }
 
 // The return value of this function will be simply pushed to a Vec in the caller function,
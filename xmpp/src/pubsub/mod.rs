@@ -78,9 +78,8 @@ pub(crate) async fn handle_event(from: &Jid, elem: Element, agent: &mut Agent) -
     events
 }
 
-pub(crate) fn handle_iq_result(from: &Jid, elem: Element) -> impl IntoIterator<Item = Event> {
+pub(crate) fn handle_iq_result(from: &Jid, pubsub: PubSub) -> impl IntoIterator<Item = Event> {
     let mut events = Vec::new();
-    let pubsub = PubSub::try_from(elem).unwrap();
     trace!("PubSub: {:#?}", pubsub);
     if let PubSub::Items(items) = pubsub {
         match items.node.0.clone() {